@@ -1,22 +1,32 @@
-use crate::get_project_dir;
+use crate::extract::{self, ArchiveFormat, ExtractError};
+use crate::http::Http;
+use crate::lock::InstallLock;
+use crate::metadata::{now, MetadataCache};
+use crate::options::Options;
+use crate::platform::{
+    version_parts, Os, Platform, Provider, UnsupportedPlatform, CHROME_FOR_TESTING_SINCE,
+};
+use crate::progress::{Phase, Progress};
+use crate::record::InstallRecord;
 
-use flate2::read::GzDecoder;
-use log::info;
-use reqwest;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
-use std::fs::{create_dir_all, set_permissions, File, Permissions};
-use std::io::{copy, Error, Write};
-use std::path::PathBuf;
-use tar::Archive;
-use zip::ZipArchive;
+use std::ffi::OsString;
+use std::fs::{self, create_dir_all, remove_dir_all, File};
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Where a browser and its driver are downloaded from.
 #[derive(Debug, Clone)]
-struct DownloadLinks {
-    browser_url: String,
-    driver_url: String,
-    version: String,
+pub struct DownloadLinks {
+    pub browser_url: String,
+    pub driver_url: String,
+    pub version: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -60,108 +70,475 @@ impl Browser {
         }
     }
 
+    /// Overrides the OS and architecture the browser will be set up for,
+    /// which default to the ones this is running on. The names are the ones
+    /// in `std::env::consts`, like `windows` and `x86_64`. Drivers for
+    /// another platform can't be run, so they aren't checked once unpacked.
+    ///
+    /// # Example
+    /// ```
+    /// use browser_manager::Browser;
+    /// let browser = Browser::new("chrome".into(), "".into(), "".into(), "latest".into())
+    ///     .with_platform("windows", "x86_64");
+    /// assert_eq!(browser.os(), "windows");
+    /// ```
+    pub fn with_platform(mut self, os: &str, bitness: &str) -> Self {
+        self.os = os.to_string();
+        self.bitness = bitness.to_string();
+        self
+    }
+
+    /// The version asked for, or the driver version once installed.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The OS the browser is set up for.
+    pub fn os(&self) -> &str {
+        &self.os
+    }
+
+    /// The architecture the browser is set up for.
+    pub fn arch(&self) -> &str {
+        &self.bitness
+    }
+
+    /// Downloads the browser, if it isn't already installed, and the driver
+    /// using the options from the environment and config file.
     pub fn download(&self) -> Result<Browser, Box<dyn std::error::Error>> {
-        let links = self.get_download_urls();
-        if !self.browser_path.to_lowercase().contains(&self.name) {
-            if let Ok(browser_response) = reqwest::blocking::get(&links.browser_url) {
-                if let Ok(data) = browser_response.bytes() {
-                    let mut browser_download_path = PathBuf::from(&self.browser_path);
-                    browser_download_path.push(format!("{name}_browser.zip", name = &self.name));
-
-                    File::create(browser_download_path)?.write_all(&data)?;
+        self.download_with(&Options::load()?)
+    }
+
+    /// Downloads the browser, if it isn't already installed, and the driver.
+    /// When the browser has no `driver_path` the driver is placed in the
+    /// [`Options::project_dir`](struct.Options.html#method.project_dir).
+    pub fn download_with(&self, options: &Options) -> Result<Browser, Box<dyn std::error::Error>> {
+        self.download_with_progress(options, &|_| {})
+    }
+
+    /// Like [`download_with`](#method.download_with) but calls `on_progress`
+    /// as each step starts and as bytes and files are done, so callers can
+    /// show that something is happening during large downloads.
+    pub fn download_with_progress(
+        &self,
+        options: &Options,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<Browser, Box<dyn std::error::Error>> {
+        self.install(options, on_progress)
+            .map_err(|e| e as Box<dyn std::error::Error>)
+    }
+
+    /// Does the work for [`download_with_progress`](#method.download_with_progress)
+    /// with an error that can be sent between threads.
+    pub(crate) fn install(
+        &self,
+        options: &Options,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<Browser, Box<dyn std::error::Error + Send + Sync>> {
+        let report = |item: &str, phase, done, total| {
+            on_progress(&Progress::new(&self.name, item, phase, done, total))
+        };
+        let http = Http::new(options)?;
+        let download =
+            |url: &str, path: &PathBuf| -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+                refuse_offline(options, url)?;
+                let item = path.file_name().unwrap_or_default().to_string_lossy();
+                http.download(url, path, &|done, total| {
+                    report(&item, Phase::Downloading, done, total)
+                })?;
+                let size = path.metadata().map(|meta| meta.len()).unwrap_or(0);
+                report(&item, Phase::Verifying, size, Some(size));
+                Ok(())
+            };
+
+        report(&self.name, Phase::Resolving, 0, None);
+        let links = self.get_download_urls(&http, options)?;
+        if let Some(browser_download_path) = self.browser_download_path() {
+            let _lock = InstallLock::acquire(&browser_download_path)?;
+            let downloaded = match self.downloaded_browser(&browser_download_path) {
+                Some(downloaded) => downloaded,
+                None => {
+                    download(&links.browser_url, &browser_download_path)?;
+                    self.name_browser_download(&browser_download_path)?
                 }
+            };
+            self.set_up_browser(&downloaded, options, on_progress)?;
+        }
+
+        let driver_dir = self.driver_dir(options)?;
+        let archive = self.driver_archive(&driver_dir, &links);
+        let _lock = InstallLock::acquire(&install_target(&driver_dir, &self.name, true))?;
+        if self.needs_download(&archive) {
+            download(&links.driver_url, &archive)?;
+        }
+        self.set_up_driver(&archive, &driver_dir, links, options, on_progress)
+    }
+
+    /// Unpacks the browser that was downloaded to `downloaded`, if it needs
+    /// to be, and notes that it was used. Callers should hold the
+    /// `InstallLock` for the download.
+    pub(crate) fn set_up_browser(
+        &self,
+        downloaded: &Path,
+        options: &Options,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.unpack_browser(downloaded, on_progress)?;
+        self.record_use(downloaded, &self.version, false, options.keep_archives)?;
+        Ok(())
+    }
+
+    /// Where the driver from `links` is downloaded to in `driver_dir`.
+    pub(crate) fn driver_archive(&self, driver_dir: &Path, links: &DownloadLinks) -> PathBuf {
+        driver_dir.join(links.driver_url.rsplit('/').next().unwrap())
+    }
+
+    /// Whether the driver `archive` has to be downloaded, because it wasn't
+    /// unpacked by an earlier install that can be reused.
+    pub(crate) fn needs_download(&self, archive: &Path) -> bool {
+        if self.is_extracted(archive) {
+            info!("Reusing {} from {}", self.name, archive.display());
+            return false;
+        }
+        true
+    }
+
+    /// Unpacks the driver from `archive` into `driver_dir`, unless an
+    /// earlier install already did, notes that it was used and returns the
+    /// browser as it now is. Callers should hold the `InstallLock` for the
+    /// driver's `install_target`.
+    pub(crate) fn set_up_driver(
+        &self,
+        archive: &Path,
+        driver_dir: &Path,
+        links: DownloadLinks,
+        options: &Options,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<Browser, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.is_extracted(archive) {
+            self.extract_driver(archive, on_progress)?;
+        }
+        self.record_use(archive, &links.version, true, options.keep_archives)?;
+        Ok(self.installed(driver_dir, links))
+    }
+
+    /// Looks up where the browser and its driver are downloaded from, which
+    /// includes finding the latest driver version. This fails with an
+    /// [`UnsupportedPlatform`](struct.UnsupportedPlatform.html) when there is
+    /// no download for the browser's platform.
+    pub fn download_urls(
+        &self,
+        options: &Options,
+    ) -> Result<DownloadLinks, Box<dyn std::error::Error + Send + Sync>> {
+        self.get_download_urls(&Http::new(options)?, options)
+    }
+
+    /// The latest driver version is kept in the metadata cache for the
+    /// `latest_ttl`, so it isn't looked up on every run.
+    fn get_download_urls(
+        &self,
+        http: &Http,
+        options: &Options,
+    ) -> Result<DownloadLinks, Box<dyn std::error::Error + Send + Sync>> {
+        let mirrors = options.mirrors_for(&self.name);
+        let url = latest_driver_url(&self.name, &mirrors);
+        let cache = MetadataCache::new(options)?;
+        let driver_version = cache.get_or_fetch(&latest_key(url), options.latest_ttl, || {
+            Ok(if self.name == "firefox" {
+                let response = http.get(url)?;
+                let release_url = response.url().to_string();
+                version_from_release(&release_url, &response.bytes()?)
+            } else {
+                parse_version_text(&http.get_bytes(url)?)
+            })
+        })?;
+        Ok(self.links_for(&driver_version, &mirrors)?)
+    }
+
+    /// Where the browser should be downloaded to, or `None` when it is
+    /// already installed. The download servers don't say what format they
+    /// send, so this has no extension until the download is looked at.
+    pub(crate) fn browser_download_path(&self) -> Option<PathBuf> {
+        if self.browser_path.to_lowercase().contains(&self.name) {
+            return None;
+        }
+        Some(install_target(
+            Path::new(&self.browser_path),
+            &self.name,
+            false,
+        ))
+    }
+
+    /// Gives a browser downloaded to `path` the extension of the format it
+    /// turned out to be in, like `.dmg`, and returns where it now is.
+    pub(crate) fn name_browser_download(&self, path: &Path) -> Result<PathBuf, Error> {
+        match ArchiveFormat::sniff(path)? {
+            Some(format) => {
+                let named = with_extension(path, format.extension());
+                fs::rename(path, &named)?;
+                info!("Downloaded {} as a {}", self.name, format);
+                Ok(named)
             }
+            None => Ok(path.to_path_buf()),
         }
+    }
 
-        let mut driver_download_path;
+    /// A browser that was already downloaded to `path`, whichever format it
+    /// was in. This is still found once the download has been unpacked and
+    /// deleted.
+    pub(crate) fn downloaded_browser(&self, path: &Path) -> Option<PathBuf> {
+        ArchiveFormat::ALL
+            .iter()
+            .map(|format| with_extension(path, format.extension()))
+            .chain(Some(path.to_path_buf()))
+            .find(|path| path.is_file() || self.is_extracted(path))
+    }
+
+    /// The directory the driver goes in, which is the project directory
+    /// unless the browser has its own `driver_path`.
+    pub(crate) fn driver_dir(&self, options: &Options) -> Result<PathBuf, Error> {
         if self.driver_path.ne(&"".to_string()) {
-            driver_download_path = PathBuf::from(&self.driver_path);
+            Ok(PathBuf::from(&self.driver_path))
         } else {
-            driver_download_path = PathBuf::from(get_project_dir()?);
+            options.project_dir()
         }
-        let display = driver_download_path.clone();
-        driver_download_path.push(
-            &links
-                .driver_url
-                .split("/")
-                .collect::<Vec<&str>>()
-                .last()
-                .unwrap(),
-        );
+    }
+
+    /// Unpacks a downloaded browser, so that it can be prepared on any
+    /// platform: an archive like Linux Firefox's `.tar.bz2`, the `.app` bundle
+    /// from a disk image, the files from an MSI package, or the payload of a
+    /// self-extracting installer. Stub installers, which download the browser
+    /// when they are run, and downloads in no known format are left as they
+    /// are. Returns whether anything was unpacked. Callers should hold the
+    /// `InstallLock` for the download.
+    pub(crate) fn unpack_browser(
+        &self,
+        downloaded: &Path,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        // The download may have been deleted once it was unpacked
+        if self.is_extracted(downloaded) {
+            return Ok(false);
+        }
+        let format = match ArchiveFormat::sniff(downloaded)? {
+            Some(format) => format,
+            None => return Ok(false),
+        };
+        if format == ArchiveFormat::Exe
+            && extract::find_7z_payload(&mut File::open(downloaded)?)?.is_none()
+        {
+            warn!(
+                "{} is a stub installer, so it was left as it is",
+                downloaded.display()
+            );
+            return Ok(false);
+        }
+        self.install_archive(downloaded, on_progress, false)
+    }
+
+    /// Installs the driver from `archive` into the directory it is in. See
+    /// [`install_archive`](#method.install_archive).
+    pub(crate) fn extract_driver(
+        &self,
+        archive: &Path,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        self.install_archive(archive, on_progress, true)
+    }
 
-        if let Ok(driver_response) = reqwest::blocking::get(&links.driver_url) {
-            if let Ok(data) = driver_response.bytes() {
-                File::create(&driver_download_path)?.write_all(&data)?;
-                self.unpack_zip(driver_download_path.display().to_string())?;
+    /// Installs what is in `archive` into the directory it is in, as one
+    /// transaction. The archive is unpacked into a staging directory, the
+    /// driver is checked by running it with `--version` if it is a `driver`,
+    /// and only then is everything renamed into place. If any step fails the
+    /// files that were there before are put back and the staging directory
+    /// is removed. Callers should hold the `InstallLock` for its
+    /// `install_target`.
+    fn install_archive(
+        &self,
+        archive: &Path,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+        driver: bool,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let item = archive.file_name().unwrap_or_default().to_string_lossy();
+        let dest = archive.parent().unwrap_or_else(|| Path::new("."));
+        let staging = dest.join(format!(".{}.staging-{}", item, process::id()));
+        let previous = dest.join(format!(".{}.previous-{}", item, process::id()));
+        for leftover in &[&staging, &previous] {
+            if leftover.exists() {
+                remove_dir_all(leftover)?;
             }
         }
+        create_dir_all(&staging)?;
 
-        Ok(Browser::new(
+        let install = || -> Result<Vec<OsString>, Box<dyn std::error::Error + Send + Sync>> {
+            self.unpack_zip(archive.display().to_string(), &staging, &|done, total| {
+                on_progress(&Progress::new(
+                    &self.name,
+                    &item,
+                    Phase::Extracting,
+                    done,
+                    total,
+                ))
+            })?;
+            if driver {
+                self.validate_driver(&staging)?;
+            }
+            Ok(commit_entries(&staging, &previous, dest)?)
+        };
+        let installed = install();
+        let _ = remove_dir_all(&staging);
+        let entries = installed?;
+
+        let mut record = InstallRecord::read(archive).unwrap_or_default();
+        record.entries = entries
+            .iter()
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        record.browser = self.name.to_owned();
+        record.driver = driver;
+        record.installed = now();
+        record.replace_entries(archive)?;
+        record.write(archive)?;
+        Ok(true)
+    }
+
+    /// Notes in the record for `archive` that this browser has just used it,
+    /// so that [`clean`](fn.clean.html) keeps it. `version` is what was
+    /// installed from it. Unless `keep_archive` is set, an archive that has
+    /// been unpacked is deleted and the record says it is gone.
+    pub(crate) fn record_use(
+        &self,
+        archive: &Path,
+        version: &str,
+        driver: bool,
+        keep_archive: bool,
+    ) -> Result<(), Error> {
+        let mut record = InstallRecord::read(archive).unwrap_or_else(|| InstallRecord {
+            installed: now(),
+            ..InstallRecord::default()
+        });
+        record.browser = self.name.to_owned();
+        record.channel = self.version.to_owned();
+        record.version = version.to_string();
+        record.driver = driver;
+        record.last_used = now();
+        if !keep_archive && archive.is_file() && self.is_extracted(archive) {
+            fs::remove_file(archive)?;
+            info!("Deleted {} now that it is unpacked", archive.display());
+        }
+        record.archive_kept = archive.is_file();
+        record.write(archive)
+    }
+
+    /// The file name of this browser's driver on the platform it is being
+    /// set up for.
+    pub(crate) fn driver_name(&self) -> String {
+        let driver = driver_stem(&self.name);
+        if self.os == "windows" {
+            format!("{}.exe", driver)
+        } else {
+            driver.to_string()
+        }
+    }
+
+    /// Checks that the driver unpacked into `staging` runs. Drivers for
+    /// another platform can't be run here, so they are trusted as they are.
+    fn validate_driver(&self, staging: &Path) -> Result<(), Error> {
+        if self.os != env::consts::OS || self.bitness != env::consts::ARCH {
+            return Ok(());
+        }
+        let name = self.driver_name();
+        let driver = find_file(staging, &name)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("The {} archive does not contain {}", self.name, name),
+            )
+        })?;
+        let version = run_version(&driver).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("{} failed to run with --version: {}", name, e),
+            )
+        })?;
+        info!("Installed {}", version);
+        Ok(())
+    }
+
+    /// Whether `archive` was already unpacked by an earlier install and
+    /// everything it contained is still there. Files that another archive
+    /// has since been unpacked over are no longer in its record, so an older
+    /// driver isn't mistaken for the one that replaced it.
+    pub(crate) fn is_extracted(&self, archive: &Path) -> bool {
+        let dest = archive.parent().unwrap_or_else(|| Path::new("."));
+        match InstallRecord::read(archive) {
+            Some(record) => {
+                !record.entries.is_empty()
+                    && record.entries.iter().all(|name| dest.join(name).exists())
+            }
+            None => false,
+        }
+    }
+
+    /// The browser as it is once its driver has been set up in `driver_dir`.
+    pub(crate) fn installed(&self, driver_dir: &Path, links: DownloadLinks) -> Browser {
+        Browser::new(
             self.name.to_owned(),
-            display.display().to_string(),
+            driver_dir.display().to_string(),
             self.browser_path.to_owned(),
             links.version,
-        ))
+        )
+        .with_platform(&self.os, &self.bitness)
+    }
+
+    /// The names of the top level files in `browser_path` that the browser
+    /// was downloaded or unpacked to. This is empty when the browser wasn't
+    /// downloaded.
+    pub(crate) fn browser_files(&self) -> Result<Vec<String>, Error> {
+        let downloaded = match self
+            .browser_download_path()
+            .and_then(|path| self.downloaded_browser(&path))
+        {
+            Some(downloaded) => downloaded,
+            None => return Ok(vec![]),
+        };
+        if self.is_extracted(&downloaded) {
+            Ok(InstallRecord::read(&downloaded)
+                .map(|record| record.entries)
+                .unwrap_or_default())
+        } else {
+            let name = downloaded.file_name().unwrap_or_default();
+            Ok(vec![name.to_string_lossy().into_owned()])
+        }
     }
 
-    fn get_download_urls(&self) -> DownloadLinks {
+    /// Where the driver executable is once it has been installed in
+    /// `driver_path`.
+    pub(crate) fn driver_file(&self) -> Result<Option<PathBuf>, Error> {
+        find_file(Path::new(&self.driver_path), &self.driver_name())
+    }
+
+    pub(crate) fn links_for(
+        &self,
+        driver_version: &str,
+        mirrors: &Mirrors,
+    ) -> Result<DownloadLinks, UnsupportedPlatform> {
+        let driver_version = driver_version.to_string();
         let mut browser_detail = HashMap::new();
         browser_detail.insert("application".to_string(), &self.name);
         browser_detail.insert("platform".to_string(), &self.os);
         browser_detail.insert("version".to_string(), &self.version);
         browser_detail.insert("bitness".to_string(), &self.bitness);
-        parse_for_urls(browser_detail)
+        browser_detail.insert("driver_version".to_string(), &driver_version);
+        parse_for_urls(browser_detail, mirrors)
     }
 
-    fn unpack_zip(&self, file: String) -> Result<bool, Error> {
-        let zip_file = File::open(&file)?;
-        let is_tarball = file.ends_with(".tar.gz");
-        let mut proj_dir = PathBuf::from(file);
-        proj_dir.pop();
-
-        if is_tarball {
-            let tar = GzDecoder::new(zip_file);
-            let mut archive = Archive::new(tar);
-            archive.unpack(proj_dir.to_owned())?;
-        } else {
-            let mut archive = ZipArchive::new(zip_file)?;
-
-            for i in 0..archive.len() {
-                let mut _file = archive.by_index(i).unwrap();
-                let mut outpath = proj_dir.to_owned();
-                outpath.push(_file.enclosed_name().unwrap());
-
-                if (&*_file.name()).ends_with('/') {
-                    info!("File {} extracted to \"{}\"", i, outpath.display());
-                    create_dir_all(&outpath).unwrap();
-                } else {
-                    info!(
-                        "File {} extracted to \"{}\" ({} bytes)",
-                        i,
-                        outpath.display(),
-                        _file.size()
-                    );
-                    if let Some(p) = outpath.parent() {
-                        if !p.exists() {
-                            create_dir_all(&p).unwrap();
-                        }
-                    }
-                    let mut outfile = File::create(&outpath).unwrap();
-                    copy(&mut _file, &mut outfile).unwrap();
-                }
-
-                // Get and Set permissions
-                #[cfg(unix)]
-                {
-                    use std::os::unix::fs::PermissionsExt;
-
-                    if let Some(mode) = _file.unix_mode() {
-                        set_permissions(&outpath, Permissions::from_mode(mode)).unwrap();
-                    }
-                }
-            }
-        }
-
+    fn unpack_zip(
+        &self,
+        file: String,
+        proj_dir: &Path,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<bool, ExtractError> {
+        extract::unpack(Path::new(&file), proj_dir, on_file)?;
         Ok(true)
     }
 
@@ -177,156 +554,416 @@ impl Browser {
     }
 }
 
-const FIREFOX_BASE_URL: &str = "https://download.mozilla.org/?";
-const FIREFOX_DRIVER_BASE_URL: &str = "https://github.com/mozilla/geckodriver/releases/download/";
-const FIREFOX_DRIVER_LATEST: &str = "https://github.com/mozilla/geckodriver/releases/latest";
-const CHROMEDRIVER_BASE_URL: &str = "https://chromedriver.storage.googleapis.com/";
-const CHROMEDRIVER_LATEST_URL: &str = "https://chromedriver.storage.googleapis.com/LATEST_RELEASE";
-const EDGE_BASE_URL: &str = "https://officecdn-microsoft-com.akamaized.net/pr/C1297A47-86C4-4C1F-97FA-950631F94777/MacAutoupdate/";
-const EDGEDRIVER_BASE_URL: &str = "https://msedgedriver.azureedge.net/";
-
-fn parse_for_urls(data: HashMap<String, &String>) -> DownloadLinks {
-    let application;
-    match data.get("application") {
-        Some(app) => application = app,
-        None => panic!("Should have received an application name"),
+pub(crate) const FIREFOX_BASE_URL: &str = "https://download.mozilla.org/?";
+pub(crate) const FIREFOX_DRIVER_BASE_URL: &str =
+    "https://github.com/mozilla/geckodriver/releases/download/";
+pub(crate) const FIREFOX_DRIVER_LATEST: &str =
+    "https://api.github.com/repos/mozilla/geckodriver/releases/latest";
+pub(crate) const CHROME_BASE_URL: &str =
+    "https://chromeenterprise.google/browser/download/thank-you/?";
+pub(crate) const CHROMEDRIVER_BASE_URL: &str = "https://chromedriver.storage.googleapis.com/";
+pub(crate) const CHROMEDRIVER_LATEST_URL: &str =
+    "https://googlechromelabs.github.io/chrome-for-testing/LATEST_RELEASE_STABLE";
+pub(crate) const CHROME_FOR_TESTING_BASE_URL: &str =
+    "https://storage.googleapis.com/chrome-for-testing-public/";
+pub(crate) const EDGE_BASE_URL: &str =
+    "https://officecdn-microsoft-com.akamaized.net/pr/C1297A47-86C4-4C1F-97FA-950631F94777/";
+pub(crate) const EDGEDRIVER_BASE_URL: &str = "https://msedgedriver.azureedge.net/";
+pub(crate) const EDGEDRIVER_LATEST_URL: &str = "https://msedgedriver.azureedge.net/LATEST_STABLE";
+pub(crate) const FIREFOX_VERSIONS_URL: &str =
+    "https://product-details.mozilla.org/1.0/firefox.json";
+pub(crate) const GECKODRIVER_RELEASES_URL: &str =
+    "https://api.github.com/repos/mozilla/geckodriver/releases?per_page=100";
+pub(crate) const CHROME_VERSIONS_URL: &str =
+    "https://googlechromelabs.github.io/chrome-for-testing/known-good-versions.json";
+pub(crate) const CHROME_CHANNELS_URL: &str =
+    "https://googlechromelabs.github.io/chrome-for-testing/last-known-good-versions.json";
+pub(crate) const EDGEDRIVER_VERSIONS_URL: &str =
+    "https://msedgewebdriverstorage.blob.core.windows.net/edgewebdriver?restype=container&comp=list&delimiter=/";
+
+/// The URLs that browsers and drivers are downloaded from, and the endpoints
+/// that the latest versions are looked up from. Each one can be pointed at a
+/// mirror, like an Artifactory remote repository, using the `[mirrors]` table
+/// in the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirrors {
+    pub firefox: String,
+    pub geckodriver: String,
+    /// Describes the latest geckodriver release, either as GitHub's API does
+    /// or by redirecting to its tag.
+    pub geckodriver_latest: String,
+    pub chrome: String,
+    /// Where chromedriver 114 and older are downloaded from.
+    pub chromedriver: String,
+    /// Returns the latest chromedriver version as text.
+    pub chromedriver_latest: String,
+    /// Where chromedriver 115 and later are downloaded from.
+    pub chrome_for_testing: String,
+    pub edge: String,
+    pub edgedriver: String,
+    /// Returns the latest stable msedgedriver version as text.
+    pub edgedriver_latest: String,
+    /// Mozilla's product details, which list every Firefox release.
+    pub firefox_versions: String,
+    /// The GitHub API listing of geckodriver releases.
+    pub geckodriver_releases: String,
+    /// The Chrome for Testing versions that have downloads.
+    pub chrome_versions: String,
+    /// The newest Chrome for Testing version on each channel.
+    pub chrome_channels: String,
+    /// The storage listing with a directory for each msedgedriver version.
+    pub edgedriver_versions: String,
+}
+
+impl Default for Mirrors {
+    fn default() -> Self {
+        Mirrors {
+            firefox: FIREFOX_BASE_URL.to_string(),
+            geckodriver: FIREFOX_DRIVER_BASE_URL.to_string(),
+            geckodriver_latest: FIREFOX_DRIVER_LATEST.to_string(),
+            chrome: CHROME_BASE_URL.to_string(),
+            chromedriver: CHROMEDRIVER_BASE_URL.to_string(),
+            chromedriver_latest: CHROMEDRIVER_LATEST_URL.to_string(),
+            chrome_for_testing: CHROME_FOR_TESTING_BASE_URL.to_string(),
+            edge: EDGE_BASE_URL.to_string(),
+            edgedriver: EDGEDRIVER_BASE_URL.to_string(),
+            edgedriver_latest: EDGEDRIVER_LATEST_URL.to_string(),
+            firefox_versions: FIREFOX_VERSIONS_URL.to_string(),
+            geckodriver_releases: GECKODRIVER_RELEASES_URL.to_string(),
+            chrome_versions: CHROME_VERSIONS_URL.to_string(),
+            chrome_channels: CHROME_CHANNELS_URL.to_string(),
+            edgedriver_versions: EDGEDRIVER_VERSIONS_URL.to_string(),
+        }
     }
+}
 
-    let platform: String;
-    match data.get("platform") {
-        Some(plat) => platform = plat.to_string(),
-        None => panic!("Should have received an application platform"),
+/// Reads a version out of a `LATEST` style text file. Microsoft serve theirs
+/// as UTF-16 with a byte order mark so that is handled as well as UTF-8.
+pub(crate) fn parse_version_text(data: &[u8]) -> String {
+    let text = if data.starts_with(&[0xFF, 0xFE]) {
+        let units: Vec<u16> = data[2..]
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(data).to_string()
+    };
+    text.trim_start_matches('\u{feff}').trim().to_string()
+}
+
+/// Fails if `url` has to be downloaded while offline. Only what isn't
+/// installed yet is downloaded, so an install that is already there can
+/// still be used while offline.
+pub(crate) fn refuse_offline(
+    options: &Options,
+    url: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if options.offline {
+        return Err(format!("Can not download {} while offline", url).into());
     }
-    let os: String;
-    match data.get("bitness") {
-        Some(&bits) => {
-            match platform.as_str() {
-                "linux" => {
-                    match bits.as_str() {
-                        "x86_64" => os = format!("{}{}", platform, "64".to_string()),
-                        _=> os = platform.to_string()
-                    }
-                },
-                "windows" => {
-                    match bits.as_str() {
-                        "x86_64" => {
-                            match application.as_str() {
-                                "chrome" => os = format!("{}{}", "win".to_string(), "32".to_string()),
-                                _ => os = format!("{}{}", "win".to_string(), "64".to_string())
-                            }
-                        },
-                        _ => os = "win".to_string()
-                    }
-                },
-                _ => {
-                    match application.as_str() {
-                        "chrome" =>os = format!("{}{}", "mac".to_string(), "64".to_string()),
-                        _ => os = "macos".to_string()
-                    }
-                }
+    Ok(())
+}
+
+/// The driver of `browser`, without the `.exe` it has on Windows.
+fn driver_stem(browser: &str) -> &str {
+    match browser {
+        "firefox" => "geckodriver",
+        "chrome" => "chromedriver",
+        "edge" => "msedgedriver",
+        other => other,
+    }
+}
+
+/// What an install of `browser` into `dir`, or of its driver when `driver`
+/// is set, is for and so holds the `InstallLock` of. For a browser that is
+/// where it is downloaded to. Every version of a driver unpacks to the same
+/// file, so for a driver it is that file rather than the archive, and
+/// installs of different versions wait for each other.
+pub(crate) fn install_target(dir: &Path, browser: &str, driver: bool) -> PathBuf {
+    if driver {
+        dir.join(driver_stem(browser))
+    } else {
+        dir.join(format!("{}_browser", browser))
+    }
+}
+
+/// The URL that the latest driver version for `application` is looked up from.
+pub(crate) fn latest_driver_url<'a>(application: &str, mirrors: &'a Mirrors) -> &'a str {
+    match application {
+        "firefox" => &mirrors.geckodriver_latest,
+        "chrome" => &mirrors.chromedriver_latest,
+        _ => &mirrors.edgedriver_latest,
+    }
+}
+
+/// What the latest version found at `url` is kept as in the metadata cache.
+pub(crate) fn latest_key(url: &str) -> String {
+    format!("latest {}", url)
+}
+
+/// Reads the version of a GitHub release. The API describes it in JSON with
+/// a `tag_name`, while the web page and mirrors of it redirect to the tag so
+/// the version is the last part of the `url` we end up at.
+pub(crate) fn version_from_release(url: &str, body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+    match serde_json::from_slice::<Release>(body) {
+        Ok(release) => release.tag_name,
+        Err(_) => version_from_release_url(url),
+    }
+}
+
+fn version_from_release_url(url: &str) -> String {
+    url.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Renames everything in `staging` into `dest` and returns the names that
+/// were moved. Anything it replaces is first moved aside into `previous`, so
+/// that if a rename fails `dest` can be put back the way it was.
+fn commit_entries(staging: &Path, previous: &Path, dest: &Path) -> Result<Vec<OsString>, Error> {
+    create_dir_all(previous)?;
+    let mut replaced = vec![];
+    let mut added = vec![];
+    let mut commit = || -> Result<(), Error> {
+        for entry in fs::read_dir(staging)? {
+            let name = entry?.file_name();
+            let target = dest.join(&name);
+            if target.symlink_metadata().is_ok() {
+                fs::rename(&target, previous.join(&name))?;
+                replaced.push(name.clone());
             }
+            fs::rename(staging.join(&name), &target)?;
+            added.push(name);
         }
-        None => panic!("Should have received bitness for platform"),
+        Ok(())
     };
+    let committed = commit();
+
+    if committed.is_err() {
+        for name in &added {
+            let _ = remove_path(&dest.join(name));
+        }
+        for name in &replaced {
+            let _ = fs::rename(previous.join(name), dest.join(name));
+        }
+    }
+    let _ = remove_dir_all(previous);
+    committed.map(|_| added)
+}
 
-    let version;
-    match data.get("version") {
-        Some(ver) => version = ver,
+fn remove_path(path: &Path) -> Result<(), Error> {
+    if path.is_dir() && !path.is_symlink() {
+        remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Looks for a file called `name` anywhere under `dir`, as some archives keep
+/// the driver in a subdirectory.
+fn find_file(dir: &Path, name: &str) -> Result<Option<PathBuf>, Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if let Some(found) = find_file(&path, name)? {
+                return Ok(Some(found));
+            }
+        } else if path.file_name().is_some_and(|file| file == name) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// How long a driver gets to answer `--version`.
+const VALIDATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `driver --version` and returns the first line it prints. A driver
+/// that hangs is killed after `VALIDATE_TIMEOUT`.
+fn run_version(driver: &Path) -> Result<String, Error> {
+    let mut attempts = 0;
+    let mut child = loop {
+        let spawned = Command::new(driver)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        match spawned {
+            // The file can still be open for writing in a child that another
+            // thread is starting, which clears as soon as that child execs.
+            Err(e) if e.kind() == ErrorKind::ExecutableFileBusy && attempts < 10 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(50));
+            }
+            spawned => break spawned?,
+        }
+    };
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > VALIDATE_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(ErrorKind::TimedOut, "it did not exit"));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_string(&mut output)?;
+    }
+    if !status.success() {
+        return Err(Error::other(format!("it exited with {}", status)));
+    }
+    Ok(output.lines().next().unwrap_or_default().trim().to_string())
+}
+
+fn parse_for_urls(
+    data: HashMap<String, &String>,
+    mirrors: &Mirrors,
+) -> Result<DownloadLinks, UnsupportedPlatform> {
+    let application = match data.get("application") {
+        Some(app) => app.as_str(),
+        None => panic!("Should have received an application name"),
+    };
+
+    let platform = match data.get("platform") {
+        Some(plat) => plat.as_str(),
+        None => panic!("Should have received an application platform"),
+    };
+
+    let bitness = match data.get("bitness") {
+        Some(bits) => bits.as_str(),
+        None => panic!("Should have received bitness for platform"),
+    };
+    let version = match data.get("version") {
+        Some(ver) => ver,
         None => panic!("Could not find a valid file extension"),
     };
 
+    // The driver version is looked up ahead of time, see `get_download_urls`
+    let latest_version = match data.get("driver_version") {
+        Some(ver) => ver.to_string(),
+        None => String::new(),
+    };
+
+    // Browser downloads don't depend on the driver version, so they are
+    // looked up as the newest
+    let target = Platform::from_names(platform, bitness);
+    let asset = |provider: Provider, version: &str| match target {
+        Some(target) => target.asset_name(provider, version),
+        None => Err(UnsupportedPlatform {
+            provider,
+            platform: format!("{}-{}", platform, bitness),
+            version: version.to_string(),
+        }),
+    };
+
     let browser_path: String;
     let driver_path: String;
-    let mut latest_version = String::new();
-    match application.as_str() {
+    match application {
         "firefox" => {
-            let browser_os;
-            if os.eq(&"macos".to_string()) {
-                browser_os = "osx".to_string();
-            } else {
-                browser_os = os.clone();
-            }
             browser_path = format!(
                 "{base_url}product={application}-{version}&os={os}&lang=en-US",
-                base_url = FIREFOX_BASE_URL,
+                base_url = mirrors.firefox,
                 application = application,
                 version = version,
-                os = browser_os
+                os = asset(Provider::Firefox, "")?
             );
-            if let Ok(response) = reqwest::blocking::get(FIREFOX_DRIVER_LATEST) {
-                let url = response.url();
-                latest_version = url
-                    .as_str()
-                    .split("/")
-                    .collect::<Vec<&str>>()
-                    .last()
-                    .unwrap()
-                    .to_string();
-            }
 
-            let file_ending;
-            if platform.eq(&"windows".to_string()) {
-                file_ending = ".zip".to_string();
+            let file_ending = if target.is_some_and(|p| p.os == Os::Windows) {
+                ".zip"
             } else {
-                file_ending = ".tar.gz".to_string();
-            }
+                ".tar.gz"
+            };
 
             driver_path = format!(
                 "{base_url}{version}/geckodriver-{version}-{os}{file_ending}",
-                base_url = FIREFOX_DRIVER_BASE_URL,
+                base_url = mirrors.geckodriver,
                 version = latest_version,
-                os = os,
+                os = asset(Provider::Geckodriver, &latest_version)?,
                 file_ending = file_ending
             );
-        },
+        }
         "chrome" => {
-            if let Ok(response) = reqwest::blocking::get(CHROMEDRIVER_LATEST_URL) {
-                if let Ok(text) = response.text() {
-                    latest_version = text;
-                }
-            }
-            if os.eq(&"mac64") {
-                browser_path = format!("https://chromeenterprise.google/browser/download/thank-you/?platform={}&channel=stable&usagestats=0", os = "UNIVERSAL_MAC_DMG".to_string());
-            } else {
-                browser_path = format!("https://chromeenterprise.google/browser/download/thank-you/?platform={}_BUNDLE&channel=stable&usagestats=0", os = os,);
-            }
-
-            driver_path = format!(
-                "{base_url}{latest_version}/chromedriver_{os}.zip",
-                base_url = CHROMEDRIVER_BASE_URL,
-                latest_version = latest_version,
-                os = os,
+            browser_path = format!(
+                "{base_url}platform={platform}&channel=stable&usagestats=0",
+                base_url = mirrors.chrome,
+                platform = asset(Provider::Chrome, "")?
             );
-        },
-        _ => {
-            if os.eq(&"mac64") {
-                browser_path = format!("https://officecdn-microsoft-com.akamaized.net/pr/C1297A47-86C4-4C1F-97FA-950631F94777/");
+
+            let os = asset(Provider::Chromedriver, &latest_version)?;
+            driver_path = if is_chrome_for_testing(&latest_version) {
+                format!(
+                    "{base_url}{latest_version}/{os}/chromedriver-{os}.zip",
+                    base_url = mirrors.chrome_for_testing,
+                    latest_version = latest_version,
+                    os = os,
+                )
             } else {
-                browser_path = format!("https://officecdn-microsoft-com.akamaized.net/pr/C1297A47-86C4-4C1F-97FA-950631F94777/");
-            }
+                format!(
+                    "{base_url}{latest_version}/chromedriver_{os}.zip",
+                    base_url = mirrors.chromedriver,
+                    latest_version = latest_version,
+                    os = os,
+                )
+            };
+        }
+        _ => {
+            browser_path = mirrors.edge.to_owned();
             driver_path = format!(
                 "{base_url}{latest_version}/edgedriver_{os}.zip",
-                base_url = EDGEDRIVER_BASE_URL,
+                base_url = mirrors.edgedriver,
                 latest_version = latest_version,
-                os = os,
+                os = asset(Provider::Edgedriver, &latest_version)?,
             );
         }
-
     }
 
-
-    DownloadLinks {
+    Ok(DownloadLinks {
         browser_url: browser_path,
         driver_url: driver_path,
         version: latest_version,
+    })
+}
+
+/// Whether chromedriver `version` comes from Chrome for Testing rather than
+/// the old chromedriver storage. Versions that can't be read are taken to be
+/// the newest.
+fn is_chrome_for_testing(version: &str) -> bool {
+    match version_parts(version) {
+        Some(parts) => version_parts(CHROME_FOR_TESTING_SINCE).is_some_and(|since| parts >= since),
+        None => true,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dmg::tests::{hfs_volume, udif, Item};
+    use crate::test_server::{Reply, TestServer};
     use std::fs::File;
     use std::io::{ErrorKind, Write};
+    use std::sync::Mutex;
+    use tempfile::TempDir;
 
     #[test]
     fn create_new_strut_with_version_included() {
@@ -390,14 +1027,15 @@ mod tests {
     fn check_is_installer_fails_with_wrong_type() {
         let firefox = Browser::new(
             String::from("firefox"),
-            String::from("".to_string()), // "driver_path"
-            String::from("".to_string()), // "browser_path"
+            String::from(""), // "driver_path"
+            String::from(""), // "browser_path"
             "".to_string(),
         );
-        let filez = create_file("invalid_file_type.txt".to_string());
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("invalid_file_type.txt");
+        let filez = create_file(&file);
         match filez {
             Ok(_) => {
-                let file = PathBuf::from("invalid_file_type.txt");
                 let is_installer = firefox._is_installer(file);
                 match is_installer {
                     Ok(is_it) => {
@@ -427,11 +1065,11 @@ mod tests {
         } else {
             file_name.push_str("dmg");
         }
-        let fil_name = file_name.clone();
-        let filez = create_file(file_name);
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join(file_name);
+        let filez = create_file(&file);
         match filez {
             Ok(_) => {
-                let file = PathBuf::from(fil_name);
                 let is_installer = firefox._is_installer(file);
                 match is_installer {
                     Ok(is_it) => {
@@ -448,8 +1086,8 @@ mod tests {
     fn create_browser() {
         let firefox = Browser::new(
             String::from("firefox"),
-            String::from("driver_path".to_string()),
-            String::from("browser_path".to_string()),
+            String::from("driver_path"),
+            String::from("browser_path"),
             "".to_string(),
         );
         assert_eq!(firefox.name, String::from("firefox"));
@@ -457,21 +1095,560 @@ mod tests {
         assert_eq!(firefox.browser_path, String::from("browser_path"));
     }
 
+    /// A stand-in driver that answers `--version` like geckodriver does.
+    const DRIVER: &[u8] = b"#!/bin/sh\necho geckodriver 0.29.1\n";
+
+    fn driver_tarball() -> Vec<u8> {
+        tarball_of(DRIVER)
+    }
+
+    fn tarball_of(driver: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(driver.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "geckodriver", driver)
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn download_reports_each_phase() {
+        let archive = driver_tarball();
+        let size = archive.len() as u64;
+        let server = TestServer::new(move |request| match request.path.as_str() {
+            "/releases/latest" => Reply::redirect("/releases/tag/v0.29.1"),
+            _ => Reply::ok(&archive),
+        });
+        let dir = TempDir::new().unwrap();
+        let options = Options {
+            retries: 0,
+            mirrors: Mirrors {
+                geckodriver: format!("{}/download/", server.url),
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(dir.path().join("cache"))
+        };
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        let events = Mutex::new(vec![]);
+        firefox
+            .download_with_progress(&options, &|progress| {
+                events.lock().unwrap().push(progress.clone())
+            })
+            .unwrap();
+        let events = events.into_inner().unwrap();
+
+        let mut phases: Vec<Phase> = events.iter().map(|progress| progress.phase).collect();
+        phases.dedup();
+        assert_eq!(
+            phases,
+            vec![
+                Phase::Resolving,
+                Phase::Downloading,
+                Phase::Verifying,
+                Phase::Extracting
+            ]
+        );
+        let downloaded = events
+            .iter()
+            .rev()
+            .find(|progress| progress.phase == Phase::Downloading)
+            .unwrap();
+        assert_eq!(downloaded.item, "geckodriver-v0.29.1-linux64.tar.gz");
+        assert_eq!((downloaded.done, downloaded.total), (size, Some(size)));
+        assert!(dir.path().join("geckodriver").exists());
+    }
+
+    fn firefox_stub(archive: Vec<u8>, cache: &Path) -> (TestServer, Options) {
+        let server = TestServer::new(move |request| match request.path.as_str() {
+            "/releases/latest" => Reply::redirect("/releases/tag/v0.29.1"),
+            _ => Reply::ok(&archive),
+        });
+        let options = Options {
+            retries: 0,
+            mirrors: Mirrors {
+                geckodriver: format!("{}/download/", server.url),
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache)
+        };
+        (server, options)
+    }
+
+    fn archive_requests(server: &TestServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.path.starts_with("/download/"))
+            .count()
+    }
+
+    #[test]
+    fn second_install_reuses_the_first() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(driver_tarball(), cache.path());
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        firefox.download_with(&options).unwrap();
+        firefox.download_with(&options).unwrap();
+        assert_eq!(archive_requests(&server), 1);
+        assert_eq!(fs::read(dir.path().join("geckodriver")).unwrap(), DRIVER);
+        let record =
+            InstallRecord::read(&dir.path().join("geckodriver-v0.29.1-linux64.tar.gz")).unwrap();
+        assert_eq!(
+            (record.browser.as_str(), record.channel.as_str()),
+            ("firefox", "latest")
+        );
+        assert_eq!(record.version, "v0.29.1");
+        assert!(record.driver && record.last_used >= record.installed);
+        assert_eq!(record.entries, vec!["geckodriver"]);
+
+        // A driver that has gone missing is put back.
+        fs::remove_file(dir.path().join("geckodriver")).unwrap();
+        firefox.download_with(&options).unwrap();
+        assert_eq!(archive_requests(&server), 2);
+        assert!(dir.path().join("geckodriver").exists());
+    }
+
+    #[test]
+    fn installs_are_reused_while_offline() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(driver_tarball(), cache.path());
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+        let offline = Options {
+            offline: true,
+            ..options.clone()
+        };
+
+        let missing = firefox.download_with(&offline).unwrap_err();
+        assert!(missing.to_string().contains("offline"), "{}", missing);
+        assert!(server.requests().is_empty());
+
+        firefox.download_with(&options).unwrap();
+        let requests = server.requests().len();
+        let installed = firefox.download_with(&offline).unwrap();
+        assert_eq!(server.requests().len(), requests);
+        assert_eq!(installed.version(), "v0.29.1");
+        assert_eq!(fs::read(dir.path().join("geckodriver")).unwrap(), DRIVER);
+
+        // Nothing can be put back while offline
+        fs::remove_file(dir.path().join("geckodriver")).unwrap();
+        let missing = firefox.download_with(&offline).unwrap_err();
+        assert!(missing.to_string().contains("offline"), "{}", missing);
+    }
+
+    #[test]
+    fn drivers_unpacked_over_another_version_replace_it() {
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+        let older = dir.path().join("geckodriver-v0.33.0-linux64.tar.gz");
+        let newer = dir.path().join("geckodriver-v0.34.0-linux64.tar.gz");
+        let driver = |version: &str| format!("#!/bin/sh\necho geckodriver {}\n", version);
+        fs::write(&older, tarball_of(driver("0.33.0").as_bytes())).unwrap();
+        fs::write(&newer, tarball_of(driver("0.34.0").as_bytes())).unwrap();
+
+        // Another browser's driver is installed under another lock, so its
+        // record is left alone
+        let chromedriver = dir.path().join("chromedriver.zip");
+        let other = InstallRecord {
+            browser: "chrome".to_string(),
+            driver: true,
+            entries: vec!["geckodriver".to_string()],
+            ..InstallRecord::default()
+        };
+        other.write(&chromedriver).unwrap();
+
+        firefox.extract_driver(&older, &|_| {}).unwrap();
+        firefox.extract_driver(&newer, &|_| {}).unwrap();
+        assert!(firefox.is_extracted(&newer));
+        assert!(!firefox.is_extracted(&older));
+        assert!(InstallRecord::read(&older).unwrap().entries.is_empty());
+        assert_eq!(InstallRecord::read(&chromedriver), Some(other));
+
+        // Going back puts the older driver in place again
+        firefox.extract_driver(&older, &|_| {}).unwrap();
+        assert!(firefox.is_extracted(&older) && !firefox.is_extracted(&newer));
+        assert_eq!(
+            fs::read(dir.path().join("geckodriver")).unwrap(),
+            driver("0.33.0").as_bytes()
+        );
+    }
+
+    #[test]
+    fn archives_can_be_deleted_once_unpacked() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(driver_tarball(), cache.path());
+        let options = Options {
+            keep_archives: false,
+            ..options
+        };
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        firefox.download_with(&options).unwrap();
+        let archive = dir.path().join("geckodriver-v0.29.1-linux64.tar.gz");
+        assert!(!archive.exists());
+        assert!(!InstallRecord::read(&archive).unwrap().archive_kept);
+        assert_eq!(fs::read(dir.path().join("geckodriver")).unwrap(), DRIVER);
+
+        // What was unpacked is still used without the archive
+        firefox.download_with(&options).unwrap();
+        assert_eq!(archive_requests(&server), 1);
+
+        // And downloaded again if it has gone missing
+        fs::remove_file(dir.path().join("geckodriver")).unwrap();
+        let keep = Options {
+            keep_archives: true,
+            ..options
+        };
+        firefox.download_with(&keep).unwrap();
+        assert_eq!(archive_requests(&server), 2);
+        assert!(archive.exists());
+        assert!(InstallRecord::read(&archive).unwrap().archive_kept);
+    }
+
+    #[test]
+    fn latest_versions_are_kept_until_refreshed() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(driver_tarball(), cache.path());
+        let firefox = Browser::new("firefox".into(), "".into(), "".into(), "latest".into())
+            .with_platform("linux", "x86_64");
+        let lookups = || {
+            server
+                .requests()
+                .iter()
+                .filter(|request| request.path == "/releases/latest")
+                .count()
+        };
+
+        firefox.download_urls(&options).unwrap();
+        let links = firefox.download_urls(&options).unwrap();
+        assert_eq!(lookups(), 1);
+        assert!(links.driver_url.contains("v0.29.1"), "{:?}", links);
+
+        let refresh = Options {
+            refresh: true,
+            ..options
+        };
+        firefox.download_urls(&refresh).unwrap();
+        assert_eq!(lookups(), 2);
+    }
+
+    #[test]
+    fn concurrent_installs_share_one_download() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(driver_tarball(), cache.path());
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        std::thread::scope(|scope| {
+            let installs: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| firefox.install(&options, &|_| {}).map(|_| ())))
+                .collect();
+            for install in installs {
+                install.join().unwrap().unwrap();
+            }
+        });
+        assert_eq!(archive_requests(&server), 1);
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "geckodriver",
+                "geckodriver-v0.29.1-linux64.tar.gz",
+                "geckodriver-v0.29.1-linux64.tar.gz.installed",
+                "geckodriver.lock"
+            ]
+        );
+    }
+
+    #[test]
+    fn installs_of_any_driver_version_wait_for_each_other() {
+        let cache = TempDir::new().unwrap();
+        let (_server, options) = firefox_stub(driver_tarball(), cache.path());
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        // As held by an install of another version
+        let other = InstallLock::acquire(&install_target(dir.path(), "firefox", true)).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                sender
+                    .send(firefox.install(&options, &|_| {}).is_ok())
+                    .unwrap();
+            });
+            let waited = receiver.recv_timeout(std::time::Duration::from_millis(300));
+            assert!(waited.is_err(), "{:?}", waited);
+            drop(other);
+            assert!(receiver
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_driver_leaves_the_previous_install() {
+        let cache = TempDir::new().unwrap();
+        let (server, options) = firefox_stub(tarball_of(b"#!/bin/sh\nexit 1\n"), cache.path());
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("geckodriver"), "previous").unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform(env::consts::OS, env::consts::ARCH);
+
+        let error = firefox.download_with(&options).unwrap_err();
+        assert!(error.to_string().contains("--version"), "{}", error);
+        assert_eq!(archive_requests(&server), 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("geckodriver")).unwrap(),
+            "previous"
+        );
+        assert!(fs::read_dir(dir.path()).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with('.')));
+        assert!(!firefox.is_extracted(&dir.path().join("geckodriver-v0.29.1-linux64.tar.gz")));
+    }
+
+    #[test]
+    fn failed_commits_are_rolled_back() {
+        let dir = TempDir::new().unwrap();
+        let (staging, previous, dest) = (
+            dir.path().join("staging"),
+            dir.path().join("previous"),
+            dir.path().join("dest"),
+        );
+        for path in &[&staging, &dest] {
+            create_dir_all(path).unwrap();
+            fs::write(path.join("a"), path.display().to_string()).unwrap();
+            fs::write(path.join("b"), path.display().to_string()).unwrap();
+        }
+        // Nothing can be moved over a directory that isn't empty, so moving
+        // one of the old files aside will fail.
+        for name in &["a", "b"] {
+            create_dir_all(previous.join(name).join("in-the-way")).unwrap();
+        }
+
+        assert!(commit_entries(&staging, &previous, &dest).is_err());
+        for name in &["a", "b"] {
+            assert_eq!(
+                fs::read_to_string(dest.join(name)).unwrap(),
+                dest.display().to_string()
+            );
+        }
+        assert!(!previous.exists());
+    }
+
+    #[test]
+    fn commits_replace_what_was_there() {
+        let dir = TempDir::new().unwrap();
+        let (staging, previous, dest) = (
+            dir.path().join("staging"),
+            dir.path().join("previous"),
+            dir.path().join("dest"),
+        );
+        create_dir_all(staging.join("lib")).unwrap();
+        fs::write(staging.join("driver"), "new").unwrap();
+        create_dir_all(dest.join("lib").join("old")).unwrap();
+        fs::write(dest.join("driver"), "old").unwrap();
+
+        let mut names = commit_entries(&staging, &previous, &dest).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["driver", "lib"]);
+        assert_eq!(fs::read_to_string(dest.join("driver")).unwrap(), "new");
+        assert!(!dest.join("lib").join("old").exists());
+        assert!(!previous.exists());
+    }
+
+    #[test]
+    fn browser_downloads_are_named_by_their_format() {
+        let dir = TempDir::new().unwrap();
+        let chrome = Browser::new(
+            "chrome".to_string(),
+            "".to_string(),
+            dir.path().display().to_string(),
+            "latest".to_string(),
+        );
+        let path = chrome.browser_download_path().unwrap();
+        assert_eq!(path, dir.path().join("chrome_browser"));
+        assert!(chrome.downloaded_browser(&path).is_none());
+
+        fs::write(&path, driver_tarball()).unwrap();
+        let named = chrome.name_browser_download(&path).unwrap();
+        assert_eq!(named, dir.path().join("chrome_browser.tar.gz"));
+        assert!(!path.exists());
+        assert_eq!(chrome.downloaded_browser(&path), Some(named));
+    }
+
+    #[test]
+    fn archives_are_unpacked_from_browser_downloads() {
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            "".to_string(),
+            dir.path().display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(7);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "firefox/firefox", &b"browser"[..])
+            .unwrap();
+        let path = firefox.browser_download_path().unwrap();
+        fs::write(&path, builder.into_inner().unwrap().finish().unwrap()).unwrap();
+        let named = firefox.name_browser_download(&path).unwrap();
+
+        assert!(firefox.unpack_browser(&named, &|_| {}).unwrap());
+        assert_eq!(
+            fs::read(dir.path().join("firefox").join("firefox")).unwrap(),
+            b"browser"
+        );
+        assert_eq!(
+            InstallRecord::read(&named).unwrap().entries,
+            vec!["firefox"]
+        );
+        assert!(!firefox.unpack_browser(&named, &|_| {}).unwrap());
+    }
+
+    #[test]
+    fn apps_are_unpacked_from_disk_image_downloads() {
+        let dir = TempDir::new().unwrap();
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            "".to_string(),
+            dir.path().display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("macos", "x86_64");
+        let path = firefox.browser_download_path().unwrap();
+        fs::write(
+            &path,
+            udif(&hfs_volume(&[
+                Item::Folder("Firefox.app"),
+                Item::File("Firefox.app/firefox", b"browser", 0o755),
+                Item::Symlink("Applications", "/Applications"),
+            ])),
+        )
+        .unwrap();
+        let named = firefox.name_browser_download(&path).unwrap();
+        assert_eq!(named, dir.path().join("firefox_browser.dmg"));
+
+        assert!(firefox.unpack_browser(&named, &|_| {}).unwrap());
+        let app = dir.path().join("Firefox.app").join("firefox");
+        assert_eq!(fs::read(&app).unwrap(), b"browser");
+        assert!(!dir.path().join("Applications").exists());
+
+        // It isn't unpacked again while the bundle is there.
+        assert!(!firefox.unpack_browser(&named, &|_| {}).unwrap());
+        fs::remove_dir_all(dir.path().join("Firefox.app")).unwrap();
+        assert!(firefox.unpack_browser(&named, &|_| {}).unwrap());
+        assert!(app.exists());
+
+        let stub = dir.path().join("firefox_browser.exe");
+        fs::write(&stub, b"MZ\x90\x00 stub installer").unwrap();
+        assert!(!firefox.unpack_browser(&stub, &|_| {}).unwrap());
+        assert!(stub.exists());
+    }
+
     #[test]
     fn create_browser_get_download() {
         let firefox = Browser::new(
             String::from("firefox"),
-            String::from("".to_string()), // "driver_path"
-            String::from("".to_string()), // "browser_path"
+            String::from(""), // "driver_path"
+            String::from(""), // "browser_path"
             "".to_string(),
         );
-        let download_url = firefox.get_download_urls();
+        let server = TestServer::new(|request| match request.path.as_str() {
+            "/releases/latest" => Reply::redirect("/releases/tag/v0.29.1"),
+            _ => Reply::ok(b""),
+        });
+        let cache = TempDir::new().unwrap();
+        let options = Options {
+            mirrors: Mirrors {
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache.path())
+        };
+        let http = Http::new(&options).unwrap();
+        let download_url = firefox.get_download_urls(&http, &options).unwrap();
         assert!(download_url
             .browser_url
             .contains("https://download.mozilla.org/?product=firefox-latest"));
         assert!(
             download_url.driver_url.contains("geckodriver-v"),
-            "Result returned was {:?}", download_url
+            "Result returned was {:?}",
+            download_url
         )
     }
 
@@ -479,47 +1656,53 @@ mod tests {
     fn unpack_zip_file_wont_exist() {
         let firefox = Browser::new(
             String::from("firefox"),
-            String::from("".to_string()), // "driver_path"
-            String::from("".to_string()), // "browser_path"
+            String::from(""), // "driver_path"
+            String::from(""), // "browser_path"
             "".to_string(),
         );
-        let result = firefox.unpack_zip("file_wont_exist".to_string());
+        let result = firefox.unpack_zip("file_wont_exist".to_string(), Path::new("."), &|_, _| {});
         match result {
             Ok(_) => assert_ne!(
                 1, 2,
                 "Should not have got an Ok on a file that doesn't exist"
             ),
-            Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            Err(e) => assert!(matches!(
+                e,
+                ExtractError::Io { source, .. } if source.kind() == ErrorKind::NotFound
+            )),
         }
     }
 
     #[test]
     fn unpack_zip_file_not_zip() {
         // Setup
-        let filz = create_file("cheese.txt".to_string());
+        let dir = TempDir::new().unwrap();
+        let cheese = dir.path().join("cheese.txt");
+        let filz = create_file(&cheese);
         match filz {
             Ok(_) => {
                 //Test
                 let firefox = Browser::new(
                     String::from("firefox"),
-                    String::from("".to_string()), // "driver_path"
-                    String::from("".to_string()), // "browser_path"
+                    String::from(""), // "driver_path"
+                    String::from(""), // "browser_path"
                     "".to_string(),
                 );
-                let result = firefox.unpack_zip("cheese.txt".to_string());
+                let result =
+                    firefox.unpack_zip(cheese.display().to_string(), dir.path(), &|_, _| {});
                 match result {
                     Ok(_) => assert_ne!(
                         1, 2,
                         "Should not have got an Ok on a file that doesn't exist"
                     ),
-                    Err(e) => assert_eq!(e.kind(), ErrorKind::Other),
+                    Err(e) => assert!(matches!(e, ExtractError::UnknownFormat { .. })),
                 }
             }
             Err(_) => assert_ne!(1, 2, "Could no create file for test during setup"),
         }
     }
 
-    fn create_file(file: String) -> Result<File, Error> {
+    fn create_file(file: &Path) -> Result<File, Error> {
         let res_file = File::create(file);
         match res_file {
             Ok(mut file) => {
@@ -545,7 +1728,7 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let expected = "https://download.mozilla.org/?product=firefox-latest&os=linux64&lang=en-US"
             .to_string();
         assert_eq!(result.browser_url, expected)
@@ -563,7 +1746,7 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let expected =
             "https://download.mozilla.org/?product=firefox-latest&os=win64&lang=en-US".to_string();
         assert_eq!(result.browser_url, expected)
@@ -581,7 +1764,7 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let expected =
             "https://download.mozilla.org/?product=firefox-latest&os=win&lang=en-US".to_string();
         assert_eq!(result.browser_url, expected)
@@ -599,7 +1782,7 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let expected =
             "https://download.mozilla.org/?product=firefox-latest&os=osx&lang=en-US".to_string();
         assert_eq!(result.browser_url, expected)
@@ -617,11 +1800,12 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let browser_expected = "UNIVERSAL_MAC_DMG".to_string();
         assert!(
             result.browser_url.contains(&browser_expected),
-            "Result is {:?}", result
+            "Result is {:?}",
+            result
         )
     }
 
@@ -632,17 +1816,32 @@ mod tests {
         let windows = "windows".to_string();
         let bitness = "x86_64".to_string();
         let version = "latest".to_string();
+        let driver_version = "114.0.5735.90".to_string();
         data.insert("application".to_string(), &firefox);
         data.insert("platform".to_string(), &windows);
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
+        data.insert("driver_version".to_string(), &driver_version);
 
-        let result = parse_for_urls(data);
-        let expected = "chromedriver_win32.zip".to_string();
+        let result = parse_for_urls(data.clone(), &Mirrors::default()).unwrap();
+        assert_eq!(
+            result.driver_url,
+            "https://chromedriver.storage.googleapis.com/114.0.5735.90/chromedriver_win32.zip"
+        );
         assert!(
-            result.driver_url.contains(&expected),
-            "Result is {:?}", result
-        )
+            result.browser_url.contains("platform=win64_BUNDLE"),
+            "Result is {:?}",
+            result
+        );
+
+        // Chrome for Testing has a 64 bit chromedriver
+        let driver_version = "115.0.5790.170".to_string();
+        data.insert("driver_version".to_string(), &driver_version);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
+        assert_eq!(
+            result.driver_url,
+            "https://storage.googleapis.com/chrome-for-testing-public/115.0.5790.170/win64/chromedriver-win64.zip"
+        );
     }
 
     #[test]
@@ -657,11 +1856,12 @@ mod tests {
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
 
-        let result = parse_for_urls(data);
-        let expected = "chromedriver_linux64.zip".to_string();
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
+        let expected = "/linux64/chromedriver-linux64.zip".to_string();
         assert!(
             result.driver_url.contains(&expected),
-            "Result is {:?}", result
+            "Result is {:?}",
+            result
         )
     }
 
@@ -677,8 +1877,194 @@ mod tests {
         data.insert("platform".to_string(), &platform);
         data.insert("bitness".to_string(), &bitness);
         data.insert("version".to_string(), &version);
-        let result = parse_for_urls(data);
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
         let expected = "edgedriver_linux64.zip".to_string();
-        assert!(result.driver_url.contains(&expected), "Result is {:?}", result)
+        assert!(
+            result.driver_url.contains(&expected),
+            "Result is {:?}",
+            result
+        )
+    }
+
+    #[test]
+    fn mirrors_are_used_for_urls() {
+        let mut data = HashMap::new();
+        let browser = "edge".to_string();
+        let platform = "linux".to_string();
+        let bitness = "x86_64".to_string();
+        let version = "latest".to_string();
+        data.insert("application".to_string(), &browser);
+        data.insert("platform".to_string(), &platform);
+        data.insert("bitness".to_string(), &bitness);
+        data.insert("version".to_string(), &version);
+
+        let mirrors = Mirrors {
+            edge: "https://artifactory.example/edge/".to_string(),
+            edgedriver: "https://artifactory.example/edgedriver/".to_string(),
+            ..Mirrors::default()
+        };
+        let result = parse_for_urls(data, &mirrors).unwrap();
+        assert_eq!(result.browser_url, "https://artifactory.example/edge/");
+        assert!(
+            result
+                .driver_url
+                .starts_with("https://artifactory.example/edgedriver/"),
+            "Result is {:?}",
+            result
+        )
+    }
+
+    #[test]
+    fn mirrors_are_used_for_chrome_browser_url() {
+        let mut data = HashMap::new();
+        let browser = "chrome".to_string();
+        let platform = "linux".to_string();
+        let bitness = "x86_64".to_string();
+        let version = "latest".to_string();
+        data.insert("application".to_string(), &browser);
+        data.insert("platform".to_string(), &platform);
+        data.insert("bitness".to_string(), &bitness);
+        data.insert("version".to_string(), &version);
+
+        let mirrors = Mirrors {
+            chrome: "https://artifactory.example/chrome/?".to_string(),
+            ..Mirrors::default()
+        };
+        let result = parse_for_urls(data, &mirrors).unwrap();
+        assert_eq!(
+            result.browser_url,
+            "https://artifactory.example/chrome/?platform=linux64_BUNDLE&channel=stable&usagestats=0"
+        );
+    }
+
+    #[test]
+    fn driver_version_is_used_in_driver_url() {
+        let mut data = HashMap::new();
+        let browser = "firefox".to_string();
+        let platform = "linux".to_string();
+        let bitness = "x86_64".to_string();
+        let version = "latest".to_string();
+        let driver_version = "v0.29.1".to_string();
+        data.insert("application".to_string(), &browser);
+        data.insert("platform".to_string(), &platform);
+        data.insert("bitness".to_string(), &bitness);
+        data.insert("version".to_string(), &version);
+        data.insert("driver_version".to_string(), &driver_version);
+
+        let result = parse_for_urls(data, &Mirrors::default()).unwrap();
+        assert_eq!(
+            result.driver_url,
+            "https://github.com/mozilla/geckodriver/releases/download/v0.29.1/geckodriver-v0.29.1-linux64.tar.gz"
+        );
+    }
+
+    #[test]
+    fn arm64_gets_arm64_drivers() {
+        let mirrors = Mirrors::default();
+        let firefox = Browser::new("firefox".into(), "".into(), "".into(), "latest".into())
+            .with_platform("linux", "aarch64");
+        let links = firefox.links_for("v0.34.0", &mirrors).unwrap();
+        assert!(
+            links.browser_url.contains("&os=linux64-aarch64&"),
+            "{}",
+            links.browser_url
+        );
+        assert!(
+            links
+                .driver_url
+                .ends_with("/geckodriver-v0.34.0-linux-aarch64.tar.gz"),
+            "{}",
+            links.driver_url
+        );
+
+        let chrome = Browser::new("chrome".into(), "".into(), "".into(), "latest".into())
+            .with_platform("macos", "aarch64");
+        let links = chrome.links_for("105.0.5195.52", &mirrors).unwrap();
+        assert!(
+            links.driver_url.ends_with("/chromedriver_mac64_m1.zip"),
+            "{}",
+            links.driver_url
+        );
+
+        let edge = Browser::new("edge".into(), "".into(), "".into(), "latest".into())
+            .with_platform("windows", "aarch64");
+        let links = edge.links_for("105.0.1343.53", &mirrors).unwrap();
+        assert!(
+            links.driver_url.ends_with("/edgedriver_arm64.zip"),
+            "{}",
+            links.driver_url
+        );
+    }
+
+    #[test]
+    fn platforms_without_a_download_are_errors() {
+        let chrome = Browser::new("chrome".into(), "".into(), "".into(), "latest".into())
+            .with_platform("linux", "aarch64");
+        let error = chrome
+            .links_for("120.0.6099.109", &Mirrors::default())
+            .unwrap_err();
+        assert_eq!(error.provider, Provider::Chrome);
+        assert_eq!(error.platform, "linux-aarch64");
+
+        let firefox = Browser::new("firefox".into(), "".into(), "".into(), "latest".into())
+            .with_platform("freebsd", "x86_64");
+        let error = firefox.links_for("v0.34.0", &Mirrors::default());
+        assert_eq!(error.unwrap_err().platform, "freebsd-x86_64");
+    }
+
+    #[test]
+    fn failed_version_lookups_are_errors() {
+        let cache = TempDir::new().unwrap();
+        let options = Options {
+            retries: 0,
+            mirrors: Mirrors {
+                // Nothing listens on the discard port so the connection is refused
+                chromedriver_latest: "http://127.0.0.1:9/LATEST_RELEASE".to_string(),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache.path())
+        };
+        let http = Http::new(&options).unwrap();
+        let chrome = Browser::new(
+            "chrome".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "latest".to_string(),
+        );
+        let result = chrome.get_download_urls(&http, &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn releases_are_read_from_the_api_or_the_redirect() {
+        let api = "https://api.github.com/repos/mozilla/geckodriver/releases/latest";
+        let json = br#"{"tag_name": "v0.34.0", "name": "0.34.0", "prerelease": false}"#;
+        assert_eq!(version_from_release(api, json), "v0.34.0");
+
+        let redirected = "https://mirror.example/geckodriver/releases/tag/v0.33.0/";
+        assert_eq!(
+            version_from_release(redirected, b"<html></html>"),
+            "v0.33.0"
+        );
+    }
+
+    #[test]
+    fn can_parse_version_text() {
+        assert_eq!(parse_version_text(b"91.0.4472.101\n"), "91.0.4472.101");
+        assert_eq!(
+            parse_version_text(b"\xef\xbb\xbf91.0.864.59\r\n"),
+            "91.0.864.59"
+        );
+        // UTF-16 LE with a byte order mark, like msedgedriver's LATEST_STABLE
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .iter()
+            .cloned()
+            .chain(
+                "91.0.864.59\r\n"
+                    .encode_utf16()
+                    .flat_map(|u| u.to_le_bytes().to_vec()),
+            )
+            .collect();
+        assert_eq!(parse_version_text(&utf16), "91.0.864.59");
     }
 }
@@ -0,0 +1,259 @@
+use crate::browser::Browser;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use tar::{Builder, Header};
+
+/// The name of the manifest at the top of every bundle.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// What is in a bundle made by [`write_bundle`](fn.write_bundle.html). Paths
+/// are relative to the top of the bundle and always use `/`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// The operating system everything in the bundle is for.
+    pub os: String,
+    /// The architecture everything in the bundle is for.
+    pub arch: String,
+    pub browsers: Vec<BundledBrowser>,
+}
+
+/// One browser and its driver in a [`Manifest`](struct.Manifest.html).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundledBrowser {
+    pub name: String,
+    /// The version of the driver.
+    pub version: String,
+    /// Where the driver executable is.
+    pub driver: String,
+    /// The top level files and directories of the browser, like
+    /// `Firefox.app`. This is empty when the browser wasn't downloaded.
+    pub browser: Vec<String>,
+}
+
+/// Writes a gzipped tarball to `output` with each of the installed
+/// `browsers`, their drivers and a [`MANIFEST_FILE`](constant.MANIFEST_FILE.html)
+/// describing them, so that they can be copied onto a machine and used
+/// without downloading anything. The browsers all have to be for the same
+/// platform, which need not be the one this is running on.
+///
+/// # Example
+/// ```no_run
+/// use browser_manager::{install_all, write_bundle, Browser, Options};
+/// use std::path::Path;
+/// let options = Options::default();
+/// let dir = options.platform_dir().unwrap().display().to_string();
+/// let browsers = vec![Browser::new("firefox".into(), dir.clone(), dir, "latest".into())
+///     .with_platform("windows", "x86_64")];
+/// let installed: Vec<Browser> = install_all(&browsers, &options, &|_| {})
+///     .into_iter()
+///     .map(|installed| installed.result.unwrap())
+///     .collect();
+/// let manifest = write_bundle(&installed, Path::new("firefox-windows.tar.gz")).unwrap();
+/// ```
+pub fn write_bundle(browsers: &[Browser], output: &Path) -> Result<Manifest, Error> {
+    let first = browsers
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "There is nothing to bundle"))?;
+    let mut manifest = Manifest {
+        os: first.os().to_string(),
+        arch: first.arch().to_string(),
+        browsers: vec![],
+    };
+
+    // Browsers can share a directory, so each top level entry is only added once
+    let mut entries = BTreeMap::new();
+    for browser in browsers {
+        if browser.os() != manifest.os || browser.arch() != manifest.arch {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} is for {}-{} but the bundle is for {}-{}",
+                    browser.name,
+                    browser.os(),
+                    browser.arch(),
+                    manifest.os,
+                    manifest.arch
+                ),
+            ));
+        }
+
+        let driver_dir = Path::new(&browser.driver_path);
+        let driver = browser.driver_file()?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "The {} driver is not in {}",
+                    browser.name,
+                    driver_dir.display()
+                ),
+            )
+        })?;
+        let driver = driver.strip_prefix(driver_dir).unwrap_or(&driver);
+        let parts: Vec<String> = driver
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        entries.insert(parts[0].to_owned(), driver_dir.join(&parts[0]));
+
+        let browser_files = browser.browser_files()?;
+        for name in &browser_files {
+            entries.insert(name.to_owned(), Path::new(&browser.browser_path).join(name));
+        }
+
+        manifest.browsers.push(BundledBrowser {
+            name: browser.name.to_owned(),
+            version: browser.version().to_string(),
+            driver: parts.join("/"),
+            browser: browser_files,
+        });
+    }
+
+    // Write next to the output and rename it into place once it is complete,
+    // so a failed bundle doesn't look like a good one
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let written = write_tarball(&partial, &entries, &manifest);
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written?;
+    fs::rename(&partial, output)?;
+    Ok(manifest)
+}
+
+fn write_tarball(
+    path: &Path,
+    entries: &BTreeMap<String, PathBuf>,
+    manifest: &Manifest,
+) -> Result<(), Error> {
+    let mut tar = Builder::new(GzEncoder::new(File::create(path)?, Compression::default()));
+    // Browsers like Firefox.app are full of symlinks that should stay links
+    tar.follow_symlinks(false);
+
+    let json = serde_json::to_vec_pretty(manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, MANIFEST_FILE, json.as_slice())?;
+
+    for (name, source) in entries {
+        if source.symlink_metadata()?.is_dir() {
+            tar.append_dir_all(name, source)?;
+        } else {
+            tar.append_path_with_name(source, name)?;
+        }
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::InstallRecord;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tar::Archive;
+    use tempfile::TempDir;
+
+    fn read_bundle(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut archive = Archive::new(GzDecoder::new(File::open(path).unwrap()));
+        let mut files = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().display().to_string();
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            files.insert(name, data);
+        }
+        files
+    }
+
+    #[test]
+    fn bundles_have_the_browser_driver_and_manifest() {
+        let dir = TempDir::new().unwrap();
+        let install = dir.path().join("install");
+        let app = install.join("Firefox.app").join("Contents").join("MacOS");
+        fs::create_dir_all(&app).unwrap();
+        fs::write(app.join("firefox"), b"browser").unwrap();
+        fs::write(install.join("firefox_browser.dmg"), b"image").unwrap();
+        InstallRecord {
+            entries: vec!["Firefox.app".to_string()],
+            ..InstallRecord::default()
+        }
+        .write(&install.join("firefox_browser.dmg"))
+        .unwrap();
+        fs::write(install.join("geckodriver"), b"driver").unwrap();
+        fs::write(install.join("chromedriver"), b"someone else's").unwrap();
+
+        let path = install.display().to_string();
+        let browser = Browser::new("firefox".into(), path.clone(), path, "0.34.0".into())
+            .with_platform("macos", "aarch64");
+        let output = dir.path().join("bundle.tar.gz");
+        let manifest = write_bundle(&[browser], &output).unwrap();
+
+        assert_eq!(manifest.os, "macos");
+        assert_eq!(manifest.arch, "aarch64");
+        assert_eq!(
+            manifest.browsers,
+            vec![BundledBrowser {
+                name: "firefox".to_string(),
+                version: "0.34.0".to_string(),
+                driver: "geckodriver".to_string(),
+                browser: vec!["Firefox.app".to_string()],
+            }]
+        );
+
+        let files = read_bundle(&output);
+        let names: Vec<&str> = files
+            .keys()
+            .map(|name| name.trim_end_matches('/'))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Firefox.app",
+                "Firefox.app/Contents",
+                "Firefox.app/Contents/MacOS",
+                "Firefox.app/Contents/MacOS/firefox",
+                "geckodriver",
+                MANIFEST_FILE,
+            ]
+        );
+        let written: Manifest = serde_json::from_slice(&files[MANIFEST_FILE]).unwrap();
+        assert_eq!(written, manifest);
+        assert!(!dir.path().join("bundle.tar.gz.partial").exists());
+    }
+
+    #[test]
+    fn browsers_for_different_platforms_cant_be_bundled_together() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("geckodriver"), b"driver").unwrap();
+        fs::write(dir.path().join("chromedriver.exe"), b"driver").unwrap();
+
+        let path = dir.path().display().to_string();
+        let browsers = vec![
+            Browser::new(
+                "firefox".into(),
+                path.clone(),
+                path.clone(),
+                "latest".into(),
+            )
+            .with_platform("linux", "x86_64"),
+            Browser::new("chrome".into(), path.clone(), path, "latest".into())
+                .with_platform("windows", "x86_64"),
+        ];
+        let output = dir.path().join("bundle.tar.gz");
+        let error = write_bundle(&browsers, &output).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,439 @@
+//! Reading Microsoft cabinet files, which is how MSI packages store the files
+//! they install. Folders that are stored or MSZIP compressed are supported,
+//! which is what browser packages use.
+//!
+//! Problems are reported as `io::Error`s, with `InvalidData` for a broken
+//! cabinet and `Unsupported` for features that aren't read.
+
+use flate2::read::DeflateDecoder;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+/// How far back MSZIP blocks can refer to what earlier blocks unpacked to.
+const MSZIP_HISTORY: usize = 32 * 1024;
+
+const COMPRESS_NONE: u16 = 0;
+const COMPRESS_MSZIP: u16 = 1;
+
+const PREV_CABINET: u16 = 0x0001;
+const NEXT_CABINET: u16 = 0x0002;
+const RESERVE_PRESENT: u16 = 0x0004;
+
+/// Set in a file's attributes when its name is UTF-8 rather than in the
+/// code page of the system that made the cabinet.
+const NAME_IS_UTF: u16 = 0x0080;
+/// Folder numbers at and above this mean the file is split across cabinets.
+const CONTINUED: u16 = 0xfffd;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(feature: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, feature.into())
+}
+
+fn le16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn le32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// A file in a cabinet.
+#[derive(Debug, Clone)]
+pub(crate) struct CabFile {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    /// Where the file starts in what its folder unpacks to.
+    pub(crate) offset: u64,
+    folder: u16,
+}
+
+#[derive(Debug)]
+struct Folder {
+    start: u64,
+    blocks: u16,
+    compression: u16,
+}
+
+pub(crate) struct Cabinet<R> {
+    reader: R,
+    folders: Vec<Folder>,
+    files: Vec<CabFile>,
+    data_reserve: u8,
+}
+
+impl<R: Read + Seek> Cabinet<R> {
+    /// Reads the list of folders and files at the start of the cabinet.
+    pub(crate) fn new(mut reader: R) -> io::Result<Cabinet<R>> {
+        let mut header = [0; 36];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"MSCF" {
+            return Err(invalid("not a cabinet"));
+        }
+        let files_offset = le32(&header, 16);
+        let folder_count = le16(&header, 26);
+        let file_count = le16(&header, 28);
+        let flags = le16(&header, 30);
+        if flags & (PREV_CABINET | NEXT_CABINET) != 0 {
+            return Err(unsupported("cabinets split across files"));
+        }
+        let (folder_reserve, data_reserve) = if flags & RESERVE_PRESENT != 0 {
+            let mut sizes = [0; 4];
+            reader.read_exact(&mut sizes)?;
+            reader.seek(SeekFrom::Current(i64::from(le16(&sizes, 0))))?;
+            (sizes[2], sizes[3])
+        } else {
+            (0, 0)
+        };
+
+        let mut folders = vec![];
+        for _ in 0..folder_count {
+            let mut folder = [0; 8];
+            reader.read_exact(&mut folder)?;
+            reader.seek(SeekFrom::Current(i64::from(folder_reserve)))?;
+            let compression = le16(&folder, 6) & 0x000f;
+            match compression {
+                COMPRESS_NONE | COMPRESS_MSZIP => {}
+                2 => return Err(unsupported("Quantum compressed cabinets")),
+                3 => return Err(unsupported("LZX compressed cabinets")),
+                other => return Err(invalid(format!("unknown compression {}", other))),
+            }
+            folders.push(Folder {
+                start: u64::from(le32(&folder, 0)),
+                blocks: le16(&folder, 4),
+                compression,
+            });
+        }
+
+        reader.seek(SeekFrom::Start(u64::from(files_offset)))?;
+        let mut files = vec![];
+        for _ in 0..file_count {
+            let mut entry = [0; 16];
+            reader.read_exact(&mut entry)?;
+            let name = read_name(&mut reader)?;
+            let folder = le16(&entry, 8);
+            if folder >= CONTINUED {
+                return Err(unsupported("cabinets split across files"));
+            }
+            let name = if le16(&entry, 14) & NAME_IS_UTF != 0 {
+                String::from_utf8_lossy(&name).into_owned()
+            } else {
+                name.iter().map(|byte| char::from(*byte)).collect()
+            };
+            if usize::from(folder) >= folders.len() {
+                return Err(invalid(format!("{} is in a missing folder", name)));
+            }
+            files.push(CabFile {
+                name,
+                size: u64::from(le32(&entry, 0)),
+                folder,
+                offset: u64::from(le32(&entry, 4)),
+            });
+        }
+        Ok(Cabinet {
+            reader,
+            folders,
+            files,
+            data_reserve,
+        })
+    }
+
+    pub(crate) fn folder_count(&self) -> usize {
+        self.folders.len()
+    }
+
+    /// The files in folder `index`, in the order they are stored.
+    pub(crate) fn files_in(&self, index: usize) -> Vec<CabFile> {
+        let mut files: Vec<CabFile> = self
+            .files
+            .iter()
+            .filter(|file| usize::from(file.folder) == index)
+            .cloned()
+            .collect();
+        files.sort_by_key(|file| file.offset);
+        files
+    }
+
+    /// A reader for what folder `index` unpacks to, which is its files one
+    /// after another.
+    pub(crate) fn open_folder(&mut self, index: usize) -> io::Result<FolderReader<'_, R>> {
+        let folder = &self.folders[index];
+        self.reader.seek(SeekFrom::Start(folder.start))?;
+        Ok(FolderReader {
+            reader: &mut self.reader,
+            remaining: folder.blocks,
+            compression: folder.compression,
+            data_reserve: self.data_reserve,
+            history: vec![],
+            block: vec![],
+            at: 0,
+            position: 0,
+        })
+    }
+}
+
+fn read_name<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut name = vec![];
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            return Ok(name);
+        }
+        if name.len() == 256 {
+            return Err(invalid("file name is too long"));
+        }
+        name.push(byte[0]);
+    }
+}
+
+/// Unpacks a folder a block at a time.
+pub(crate) struct FolderReader<'a, R> {
+    reader: &'a mut R,
+    remaining: u16,
+    compression: u16,
+    data_reserve: u8,
+    /// The end of what has been unpacked, for MSZIP blocks to refer back to.
+    history: Vec<u8>,
+    block: Vec<u8>,
+    at: usize,
+    position: u64,
+}
+
+impl<R: Read> FolderReader<'_, R> {
+    /// How much of the folder has been read.
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn next_block(&mut self) -> io::Result<bool> {
+        if self.remaining == 0 {
+            return Ok(false);
+        }
+        self.remaining -= 1;
+        let mut header = [0; 8];
+        self.reader.read_exact(&mut header)?;
+        let mut reserve = vec![0; usize::from(self.data_reserve)];
+        self.reader.read_exact(&mut reserve)?;
+        let mut stored = vec![0; usize::from(le16(&header, 4))];
+        self.reader.read_exact(&mut stored)?;
+        let size = usize::from(le16(&header, 6));
+
+        self.block = match self.compression {
+            COMPRESS_MSZIP => mszip(&self.history, &stored, size)?,
+            _ => stored,
+        };
+        if self.block.len() != size {
+            return Err(invalid("block is the wrong size"));
+        }
+        if self.compression == COMPRESS_MSZIP {
+            self.history.extend_from_slice(&self.block);
+            let excess = self.history.len().saturating_sub(MSZIP_HISTORY);
+            self.history.drain(..excess);
+        }
+        self.at = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for FolderReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let read = buf.len().min(self.block.len() - self.at);
+        buf[..read].copy_from_slice(&self.block[self.at..self.at + read]);
+        self.at += read;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// Unpacks an MSZIP block, which is a deflate stream that can refer back to
+/// what the blocks before it unpacked to. The decoder is given that history
+/// first as a stored deflate block, which is then dropped from the output.
+fn mszip(history: &[u8], stored: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let deflated = stored
+        .strip_prefix(b"CK")
+        .ok_or_else(|| invalid("MSZIP block has no signature"))?;
+    let length = history.len() as u16;
+    let mut input = Vec::with_capacity(5 + history.len() + deflated.len());
+    input.push(0);
+    input.extend_from_slice(&length.to_le_bytes());
+    input.extend_from_slice(&(!length).to_le_bytes());
+    input.extend_from_slice(history);
+    input.extend_from_slice(deflated);
+
+    let mut output = Vec::with_capacity(history.len() + size);
+    DeflateDecoder::new(&input[..])
+        .take((history.len() + size + 1) as u64)
+        .read_to_end(&mut output)
+        .map_err(|e| invalid(format!("bad MSZIP block: {}", e)))?;
+    if output.len() != history.len() + size {
+        return Err(invalid("MSZIP block unpacks to the wrong size"));
+    }
+    Ok(output.split_off(history.len()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use std::io::{Cursor, Write};
+
+    /// Builds a cabinet with one folder holding `files`, which is MSZIP
+    /// compressed if `mszip` is set.
+    pub(crate) fn cabinet(files: &[(&str, &[u8])], mszip: bool) -> Vec<u8> {
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.to_vec()).collect();
+        let mut blocks = vec![];
+        for chunk in
+            data.chunks(MSZIP_HISTORY)
+                .chain(if data.is_empty() { Some(&[][..]) } else { None })
+        {
+            let stored = if mszip {
+                let mut encoder = DeflateEncoder::new(b"CK".to_vec(), Default::default());
+                encoder.write_all(chunk).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                chunk.to_vec()
+            };
+            blocks.push((stored, chunk.len()));
+        }
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        for (name, data) in files {
+            let mut entry = vec![];
+            entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&(offset as u32).to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&NAME_IS_UTF.to_le_bytes());
+            entry.extend_from_slice(name.as_bytes());
+            entry.push(0);
+            entries.extend(entry);
+            offset += data.len();
+        }
+
+        let files_offset = 36 + 8;
+        let data_offset = files_offset + entries.len();
+        let mut cab = b"MSCF".to_vec();
+        cab.extend_from_slice(&[0; 12]);
+        cab.extend_from_slice(&(files_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&[0; 4]);
+        cab.extend_from_slice(&[3, 1]);
+        cab.extend_from_slice(&1u16.to_le_bytes());
+        cab.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&[0; 6]);
+        cab.extend_from_slice(&(data_offset as u32).to_le_bytes());
+        cab.extend_from_slice(&(blocks.len() as u16).to_le_bytes());
+        let compression = if mszip { COMPRESS_MSZIP } else { COMPRESS_NONE };
+        cab.extend_from_slice(&compression.to_le_bytes());
+        cab.extend(entries);
+        for (stored, size) in blocks {
+            cab.extend_from_slice(&[0; 4]);
+            cab.extend_from_slice(&(stored.len() as u16).to_le_bytes());
+            cab.extend_from_slice(&(size as u16).to_le_bytes());
+            cab.extend(stored);
+        }
+        let length = cab.len() as u32;
+        cab[8..12].copy_from_slice(&length.to_le_bytes());
+        cab
+    }
+
+    fn unpack(cab: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let mut cabinet = Cabinet::new(Cursor::new(cab)).unwrap();
+        let mut unpacked = vec![];
+        for folder in 0..cabinet.folder_count() {
+            let files = cabinet.files_in(folder);
+            let mut data = cabinet.open_folder(folder).unwrap();
+            for file in files {
+                let mut contents = vec![];
+                (&mut data)
+                    .take(file.size)
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                unpacked.push((file.name, contents));
+            }
+        }
+        unpacked
+    }
+
+    #[test]
+    fn stored_and_mszip_cabinets_unpack() {
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        for mszip in &[false, true] {
+            let cab = cabinet(&[("chrome.exe", &big), ("VERSION", b"90.0")], *mszip);
+            let unpacked = unpack(cab);
+            assert_eq!(unpacked[0], ("chrome.exe".to_string(), big.clone()));
+            assert_eq!(unpacked[1], ("VERSION".to_string(), b"90.0".to_vec()));
+        }
+    }
+
+    /// Writes deflate bits, least significant first.
+    struct Bits {
+        bytes: Vec<u8>,
+        used: u32,
+    }
+
+    impl Bits {
+        fn push(&mut self, value: u32, count: u32) {
+            for i in 0..count {
+                if self.used.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let last = self.bytes.last_mut().unwrap();
+                *last |= (((value >> i) & 1) as u8) << (self.used % 8);
+                self.used += 1;
+            }
+        }
+
+        /// Huffman codes are written most significant bit first.
+        fn push_code(&mut self, code: u32, count: u32) {
+            for i in (0..count).rev() {
+                self.push((code >> i) & 1, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn mszip_blocks_refer_back_to_earlier_blocks() {
+        let mut first = b"CK".to_vec();
+        let mut encoder = DeflateEncoder::new(vec![], Default::default());
+        encoder.write_all(b"hello world").unwrap();
+        first.extend(encoder.finish().unwrap());
+
+        // A fixed Huffman block copying 5 bytes from 11 back, which is
+        // before the start of this block.
+        let mut bits = Bits {
+            bytes: b"CK".to_vec(),
+            used: 16,
+        };
+        bits.push(1, 1);
+        bits.push(1, 2);
+        bits.push_code(0b000_0011, 7);
+        bits.push_code(6, 5);
+        bits.push(2, 2);
+        bits.push_code(0, 7);
+
+        let history = mszip(&[], &first, 11).unwrap();
+        assert_eq!(history, b"hello world");
+        assert_eq!(mszip(&history, &bits.bytes, 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn unsupported_cabinets_are_refused() {
+        let mut cab = cabinet(&[("a", b"a")], false);
+        cab[42] = 3;
+        let error = Cabinet::new(Cursor::new(cab)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+        assert!(error.to_string().contains("LZX"));
+
+        let error = Cabinet::new(Cursor::new(b"MSCF".to_vec())).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,419 @@
+use crate::browser::install_target;
+use crate::lock::{lock_path, InstallLock};
+use crate::metadata::now;
+use crate::options::Options;
+use crate::record::InstallRecord;
+
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How [`clean`](fn.clean.html) picks the installs to remove. An install is
+/// removed when any of the policies picks it, so with none set nothing is.
+#[derive(Debug, Clone, Default)]
+pub struct CleanPolicy {
+    /// Keep this many of the most recently installed versions of each
+    /// browser and channel, and of each of their drivers.
+    pub keep: Option<usize>,
+    /// Remove installs that haven't been used for this long.
+    pub unused_for: Option<Duration>,
+    /// Remove the least recently used installs until the rest fit in this
+    /// many bytes.
+    pub max_size: Option<u64>,
+    /// Only work out what would be removed.
+    pub dry_run: bool,
+}
+
+/// An install that [`clean`](fn.clean.html) removed, or would remove.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemovedInstall {
+    /// The archive the install came from, which is already gone if it
+    /// wasn't kept. Everything unpacked from it goes too, unless an install
+    /// that is kept has the same files.
+    pub archive: PathBuf,
+    pub browser: String,
+    pub version: String,
+    /// How many bytes removing it frees.
+    pub size: u64,
+    /// Which policy picked it.
+    pub reason: String,
+}
+
+/// What [`clean`](fn.clean.html) removed, or would remove.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CleanReport {
+    pub removed: Vec<RemovedInstall>,
+    /// How many bytes were freed in all.
+    pub freed: u64,
+}
+
+/// Removes old and unused browsers and drivers from the project directory,
+/// and from the directories of the other platforms set up with `--os` and
+/// `--arch`, as picked by `policy`. Each install is known by the
+/// [`InstallRecord`](struct.InstallRecord.html) beside its archive, and is
+/// locked while it is removed so that it isn't pulled out from under an
+/// install that is running at the same time.
+///
+/// # Example
+/// ```no_run
+/// use browser_manager::{clean, CleanPolicy, Options};
+/// use std::time::Duration;
+/// let policy = CleanPolicy {
+///     keep: Some(2),
+///     unused_for: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+///     ..CleanPolicy::default()
+/// };
+/// let report = clean(&Options::default(), &policy).unwrap();
+/// println!("Freed {} bytes", report.freed);
+/// ```
+pub fn clean(options: &Options, policy: &CleanPolicy) -> io::Result<CleanReport> {
+    let project_dir = options.project_dir()?;
+    let mut dirs = vec![project_dir.to_owned()];
+    if let Ok(platforms) = fs::read_dir(project_dir.join("platforms")) {
+        for entry in platforms {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+    let mut installs = vec![];
+    for dir in &dirs {
+        installs.extend(find_installs(dir)?);
+    }
+
+    let picked = pick(&installs, policy, now());
+    let kept: BTreeSet<&PathBuf> = installs
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !picked.contains_key(index))
+        .flat_map(|(_, install)| &install.files)
+        .collect();
+
+    let kept_targets: BTreeSet<PathBuf> = installs
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !picked.contains_key(index))
+        .map(|(_, install)| install.target())
+        .collect();
+
+    let mut report = CleanReport::default();
+    let mut counted = BTreeSet::new();
+    for (index, reason) in picked {
+        let install = &installs[index];
+        // Files that an install being kept has too stay where they are
+        let files: Vec<&PathBuf> = install
+            .files
+            .iter()
+            .filter(|file| !kept.contains(file))
+            .collect();
+        let size = files
+            .iter()
+            .filter(|file| counted.insert(file.to_path_buf()))
+            .map(|file| disk_size(file))
+            .sum();
+
+        if !policy.dry_run {
+            let target = install.target();
+            let _lock = InstallLock::acquire(&target)?;
+            for file in &files {
+                remove(file)?;
+            }
+            // The lock goes with the last install that uses it. Windows won't
+            // remove a file that is open, so there it is left for next time.
+            if !kept_targets.contains(&target) {
+                let _ = fs::remove_file(lock_path(&target));
+            }
+            info!("Removed {}: {}", install.archive.display(), reason);
+        }
+        report.freed += size;
+        report.removed.push(RemovedInstall {
+            archive: install.archive.to_owned(),
+            browser: install.record.browser.to_owned(),
+            version: install.record.version.to_owned(),
+            size,
+            reason,
+        });
+    }
+    Ok(report)
+}
+
+/// An archive with a record, and every file that belongs to it.
+struct Install {
+    archive: PathBuf,
+    record: InstallRecord,
+    files: Vec<PathBuf>,
+}
+
+impl Install {
+    /// What installing it again would lock.
+    fn target(&self) -> PathBuf {
+        let dir = self.archive.parent().unwrap_or_else(|| Path::new("."));
+        install_target(dir, &self.record.browser, self.record.driver)
+    }
+}
+
+fn find_installs(dir: &Path) -> io::Result<Vec<Install>> {
+    let mut installs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let archive = match InstallRecord::archive(&entry?.path()) {
+            Some(archive) => archive,
+            None => continue,
+        };
+        let record = match InstallRecord::read(&archive) {
+            Some(record) => record,
+            None => continue,
+        };
+        let mut files = vec![InstallRecord::path(&archive)];
+        for suffix in &["", ".part", ".part.validator", ".lock"] {
+            let mut name = archive.as_os_str().to_owned();
+            name.push(suffix);
+            files.push(PathBuf::from(name));
+        }
+        files.extend(record.entries.iter().map(|name| dir.join(name)));
+        files.retain(|file| file.symlink_metadata().is_ok());
+        installs.push(Install {
+            archive,
+            record,
+            files,
+        });
+    }
+    Ok(installs)
+}
+
+/// The indexes of the installs that `policy` picks and why, at `now`.
+fn pick(installs: &[Install], policy: &CleanPolicy, now: u64) -> BTreeMap<usize, String> {
+    let mut picked = BTreeMap::new();
+
+    if let Some(unused_for) = policy.unused_for {
+        for (index, install) in installs.iter().enumerate() {
+            let unused = now.saturating_sub(install.record.last_used);
+            if unused >= unused_for.as_secs() {
+                picked.insert(
+                    index,
+                    format!("it has not been used for {} days", unused / (24 * 60 * 60)),
+                );
+            }
+        }
+    }
+
+    if let Some(keep) = policy.keep {
+        let mut groups: BTreeMap<_, Vec<usize>> = BTreeMap::new();
+        for (index, install) in installs.iter().enumerate() {
+            let record = &install.record;
+            let key = (
+                install.archive.parent(),
+                &record.browser,
+                &record.channel,
+                record.driver,
+            );
+            groups.entry(key).or_default().push(index);
+        }
+        for (_, mut group) in groups {
+            group.sort_by_key(|index| std::cmp::Reverse(installs[*index].record.installed));
+            for index in group.into_iter().skip(keep) {
+                picked
+                    .entry(index)
+                    .or_insert_with(|| format!("only the {} newest are kept", keep));
+            }
+        }
+    }
+
+    if let Some(max_size) = policy.max_size {
+        let mut left: Vec<usize> = (0..installs.len())
+            .filter(|index| !picked.contains_key(index))
+            .collect();
+        left.sort_by_key(|index| installs[*index].record.last_used);
+        let size = |left: &[usize]| -> u64 {
+            let files: BTreeSet<&PathBuf> = left
+                .iter()
+                .flat_map(|index| &installs[*index].files)
+                .collect();
+            files.into_iter().map(|file| disk_size(file)).sum()
+        };
+        while !left.is_empty() && size(&left) > max_size {
+            let index = left.remove(0);
+            picked.insert(index, format!("the installs are over {} bytes", max_size));
+        }
+    }
+
+    picked
+}
+
+/// How many bytes `path` takes up, including everything in it if it is a
+/// directory. Links aren't followed.
+fn disk_size(path: &Path) -> u64 {
+    let meta = match path.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(_) => return 0,
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| disk_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// Writes an archive of `size` bytes for `browser` that was installed
+    /// `age` days ago and last used `unused` days ago.
+    fn install(
+        dir: &Path,
+        archive: &str,
+        entries: &[&str],
+        version: &str,
+        age: u64,
+        unused: u64,
+    ) -> PathBuf {
+        let archive = dir.join(archive);
+        fs::write(&archive, vec![0; 100]).unwrap();
+        for entry in entries {
+            fs::write(dir.join(entry), vec![0; 10]).unwrap();
+        }
+        InstallRecord {
+            browser: "firefox".to_string(),
+            channel: "latest".to_string(),
+            version: version.to_string(),
+            driver: true,
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            installed: now() - age * DAY,
+            last_used: now() - unused * DAY,
+            archive_kept: true,
+        }
+        .write(&archive)
+        .unwrap();
+        archive
+    }
+
+    fn options(dir: &TempDir) -> Options {
+        Options::default().with_cache_path(dir.path())
+    }
+
+    fn removed(report: &CleanReport) -> Vec<&str> {
+        report
+            .removed
+            .iter()
+            .map(|removed| removed.version.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn only_the_newest_are_kept() {
+        let dir = TempDir::new().unwrap();
+        let oldest = install(
+            dir.path(),
+            "gd-0.32.tar.gz",
+            &["geckodriver"],
+            "v0.32.0",
+            30,
+            30,
+        );
+        install(
+            dir.path(),
+            "gd-0.33.tar.gz",
+            &["geckodriver"],
+            "v0.33.0",
+            20,
+            0,
+        );
+        install(
+            dir.path(),
+            "gd-0.34.tar.gz",
+            &["geckodriver"],
+            "v0.34.0",
+            10,
+            0,
+        );
+        let policy = CleanPolicy {
+            keep: Some(2),
+            ..CleanPolicy::default()
+        };
+
+        let report = clean(&options(&dir), &policy).unwrap();
+        assert_eq!(removed(&report), vec!["v0.32.0"]);
+        // The driver is shared with the installs that are kept
+        assert!(dir.path().join("geckodriver").exists());
+        assert!(!oldest.exists());
+        assert!(!InstallRecord::path(&oldest).exists());
+        assert!(report.freed > 100);
+    }
+
+    #[test]
+    fn dry_runs_remove_nothing() {
+        let dir = TempDir::new().unwrap();
+        let old = install(dir.path(), "old.zip", &["old-driver"], "1", 90, 90);
+        let new = install(dir.path(), "new.zip", &["new-driver"], "2", 1, 1);
+        // A driver of its own, so nothing else uses its lock
+        let mut record = InstallRecord::read(&old).unwrap();
+        record.browser = "chrome".to_string();
+        record.write(&old).unwrap();
+        let policy = CleanPolicy {
+            unused_for: Some(Duration::from_secs(30 * DAY)),
+            dry_run: true,
+            ..CleanPolicy::default()
+        };
+
+        let report = clean(&options(&dir), &policy).unwrap();
+        assert_eq!(removed(&report), vec!["1"]);
+        assert_eq!(report.removed[0].reason, "it has not been used for 90 days");
+        assert!(old.exists() && dir.path().join("old-driver").exists());
+
+        let policy = CleanPolicy {
+            dry_run: false,
+            ..policy
+        };
+        let cleaned = clean(&options(&dir), &policy).unwrap();
+        assert_eq!(cleaned, report);
+        assert!(!old.exists() && !dir.path().join("old-driver").exists());
+        assert!(new.exists() && dir.path().join("new-driver").exists());
+        let locks: Vec<PathBuf> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lock"))
+            .collect();
+        assert!(locks.is_empty(), "{:?}", locks);
+    }
+
+    #[test]
+    fn least_recently_used_go_first_to_fit_the_size() {
+        let dir = TempDir::new().unwrap();
+        let a = install(dir.path(), "a.zip", &["a"], "a", 3, 1);
+        install(dir.path(), "b.zip", &["b"], "b", 2, 3);
+        install(dir.path(), "c.zip", &["c"], "c", 1, 2);
+        // Room for two and a half installs
+        let each = disk_size(&a) + disk_size(&InstallRecord::path(&a)) + 10;
+        let policy = CleanPolicy {
+            max_size: Some(each * 5 / 2),
+            ..CleanPolicy::default()
+        };
+        let report = clean(&options(&dir), &policy).unwrap();
+        assert_eq!(removed(&report), vec!["b"]);
+
+        let report = clean(&options(&dir), &CleanPolicy::default()).unwrap();
+        assert!(report.removed.is_empty());
+    }
+}
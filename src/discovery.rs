@@ -0,0 +1,431 @@
+use crate::browser::Browser;
+
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Executable names we look for on the `PATH` for each browser, keyed by the
+/// name we give the browser and the driver it needs.
+const PATH_BROWSERS: &[(&str, &str, &[&str])] = &[
+    ("firefox", "geckodriver", &["firefox", "firefox-bin"]),
+    (
+        "chrome",
+        "chromedriver",
+        &[
+            "google-chrome",
+            "google-chrome-stable",
+            "chrome",
+            "Google Chrome",
+        ],
+    ),
+];
+
+/// Well known install locations, relative to the filesystem root, for when the
+/// browser isn't on the `PATH`. This is the normal case on macOS and Windows.
+const MAC_LOCATIONS: &[(&str, &str, &str)] = &[
+    (
+        "firefox",
+        "geckodriver",
+        "Applications/Firefox.app/Contents/MacOS/firefox",
+    ),
+    (
+        "firefox",
+        "geckodriver",
+        "Applications/Firefox.app/Contents/MacOS/firefox-bin",
+    ),
+    (
+        "chrome",
+        "chromedriver",
+        "Applications/Google Chrome.app/Contents/MacOS/Google Chrome",
+    ),
+];
+
+const WINDOWS_LOCATIONS: &[(&str, &str, &str)] = &[
+    (
+        "firefox",
+        "geckodriver",
+        "Program Files/Mozilla Firefox/firefox.exe",
+    ),
+    (
+        "firefox",
+        "geckodriver",
+        "Program Files (x86)/Mozilla Firefox/firefox.exe",
+    ),
+    (
+        "chrome",
+        "chromedriver",
+        "Program Files/Google/Chrome/Application/chrome.exe",
+    ),
+    (
+        "chrome",
+        "chromedriver",
+        "Program Files (x86)/Google/Chrome/Application/chrome.exe",
+    ),
+];
+
+const LINUX_LOCATIONS: &[(&str, &str, &str)] = &[
+    ("firefox", "geckodriver", "usr/lib/firefox/firefox"),
+    ("chrome", "chromedriver", "opt/google/chrome/chrome"),
+];
+
+/// The context that browser and driver discovery runs in. By default this is
+/// the current machine, but the `PATH`, filesystem root and platform can all be
+/// swapped out so that discovery can be pointed at a fixture tree.
+///
+/// # Example
+/// ```
+/// use browser_manager::Discovery;
+/// let discovery = Discovery::new()
+///     .with_path("/opt/drivers")
+///     .with_root("/")
+///     .with_platform("linux", "x86_64");
+/// let firefox = discovery.find_browser_for("firefox");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    path: OsString,
+    root: PathBuf,
+    os: String,
+    arch: String,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Discovery {
+    /// Creates a discovery context for the current machine using the `PATH`
+    /// environmental variable and the host OS and architecture.
+    pub fn new() -> Self {
+        let os = env::consts::OS.to_string();
+        let root = default_root(&os);
+        Self {
+            path: env::var_os("PATH").unwrap_or_default(),
+            root,
+            os,
+            arch: env::consts::ARCH.to_string(),
+        }
+    }
+
+    /// Replaces the `PATH` used to look up browsers and drivers. The value is
+    /// split using the separator of the target OS, not the host.
+    pub fn with_path<S: Into<OsString>>(mut self, path: S) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Replaces the filesystem root that well known install locations are
+    /// resolved against.
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Replaces the OS and architecture that discovery runs as. The values
+    /// use the same names as `std::env::consts::{OS, ARCH}`.
+    pub fn with_platform(mut self, os: &str, arch: &str) -> Self {
+        self.os = os.to_string();
+        self.arch = arch.to_string();
+        self
+    }
+
+    /// Finds the path of a driver executable if it's on the `PATH`.
+    pub fn find_driver(&self, driver: &str) -> Option<PathBuf> {
+        self.find_on_path(driver)
+    }
+
+    /// Finds the browser details for `browser_name` if it is installed.
+    pub fn find_browser_for(&self, browser_name: &str) -> Option<Browser> {
+        self.available_browsers()
+            .into_iter()
+            .find(|browser| browser.name.eq(browser_name))
+    }
+
+    /// Lists all of the browsers that could be found, first on the `PATH` and
+    /// then in the usual install locations for the platform.
+    pub fn available_browsers(&self) -> Vec<Browser> {
+        let mut available_browsers: Vec<Browser> = vec![];
+
+        for (name, driver, executables) in PATH_BROWSERS {
+            for exe in executables.iter() {
+                if let Some(path) = self.find_on_path(exe) {
+                    available_browsers.push(self.browser(name, driver, &path));
+                    break;
+                }
+            }
+        }
+
+        let locations = match self.os.as_str() {
+            "macos" => MAC_LOCATIONS,
+            "windows" => WINDOWS_LOCATIONS,
+            _ => LINUX_LOCATIONS,
+        };
+        for (name, driver, location) in locations {
+            if available_browsers.iter().any(|b| b.name.eq(name)) {
+                continue;
+            }
+            let path = self.rooted(location);
+            if path.is_file() {
+                available_browsers.push(self.browser(name, driver, &path));
+            }
+        }
+
+        if self.is_mac() {
+            let safari = self.rooted("Applications/Safari.app/Contents/MacOS/Safari");
+            if safari.is_file() {
+                available_browsers.push(
+                    Browser::new(
+                        "Safari".to_string(),
+                        self.rooted("usr/bin/safaridriver").display().to_string(),
+                        safari.display().to_string(),
+                        "".to_string(),
+                    )
+                    .with_platform(&self.os, &self.arch),
+                );
+            }
+        }
+
+        available_browsers
+    }
+
+    fn browser(&self, name: &str, driver: &str, path: &Path) -> Browser {
+        let driver_path = match self.find_driver(driver) {
+            Some(path) => path.display().to_string(),
+            None => "".to_string(),
+        };
+        Browser::new(
+            name.to_string(),
+            driver_path,
+            path.display().to_string(),
+            "".to_string(),
+        )
+        .with_platform(&self.os, &self.arch)
+    }
+
+    fn find_on_path(&self, exe: &str) -> Option<PathBuf> {
+        let separator = if self.is_windows() { ';' } else { ':' };
+        let mut name = exe.to_string();
+        if self.is_windows() && !name.to_lowercase().ends_with(".exe") {
+            name.push_str(".exe");
+        }
+
+        self.path
+            .to_string_lossy()
+            .split(separator)
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| PathBuf::from(dir).join(&name))
+            .find(|candidate| self.is_executable(candidate))
+    }
+
+    fn is_executable(&self, path: &Path) -> bool {
+        if !path.is_file() {
+            return false;
+        }
+        // Windows doesn't have an executable bit so we trust the extension,
+        // which also means fixtures for Windows work on every host.
+        if self.is_windows() {
+            return true;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            match path.metadata() {
+                Ok(meta) => meta.permissions().mode() & 0o111 != 0,
+                Err(_) => false,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            true
+        }
+    }
+
+    fn rooted(&self, location: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        for part in location.split('/') {
+            path.push(part);
+        }
+        path
+    }
+
+    fn is_mac(&self) -> bool {
+        self.os.eq("macos")
+    }
+
+    fn is_windows(&self) -> bool {
+        self.os.eq("windows")
+    }
+}
+
+fn default_root(os: &str) -> PathBuf {
+    if os.eq("windows") {
+        let drive = env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+        PathBuf::from(format!("{}\\", drive))
+    } else {
+        PathBuf::from("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, File};
+    use tempfile::TempDir;
+
+    fn touch(root: &Path, location: &str) -> PathBuf {
+        let mut path = root.to_path_buf();
+        for part in location.split('/') {
+            path.push(part);
+        }
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::fs::{set_permissions, Permissions};
+            use std::os::unix::fs::PermissionsExt;
+            set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        }
+        path
+    }
+
+    fn empty_discovery(root: &TempDir, os: &str) -> Discovery {
+        Discovery::new()
+            .with_path("")
+            .with_root(root.path())
+            .with_platform(os, "x86_64")
+    }
+
+    #[test]
+    fn cant_find_drivers() {
+        let root = TempDir::new().unwrap();
+        let bin = root.path().join("bin");
+        create_dir_all(&bin).unwrap();
+
+        let discovery = empty_discovery(&root, "linux").with_path(bin.as_os_str());
+        assert_eq!(discovery.find_driver("geckodriver"), None);
+    }
+
+    #[test]
+    fn can_find_drivers_on_path() {
+        let root = TempDir::new().unwrap();
+        let driver = touch(root.path(), "usr/local/bin/geckodriver");
+
+        let path = format!("/nowhere:{}", driver.parent().unwrap().display());
+        let discovery = empty_discovery(&root, "linux").with_path(path);
+        assert_eq!(discovery.find_driver("geckodriver"), Some(driver));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wont_find_drivers_that_are_not_executable() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = TempDir::new().unwrap();
+        let driver = touch(root.path(), "bin/geckodriver");
+        set_permissions(&driver, Permissions::from_mode(0o644)).unwrap();
+
+        let discovery =
+            empty_discovery(&root, "linux").with_path(root.path().join("bin").as_os_str());
+        assert_eq!(discovery.find_driver("geckodriver"), None);
+    }
+
+    #[test]
+    fn can_find_windows_drivers_on_path() {
+        let root = TempDir::new().unwrap();
+        let driver = touch(root.path(), "tools/chromedriver.exe");
+
+        let path = format!("C:\\Windows;{}", root.path().join("tools").display());
+        let discovery = empty_discovery(&root, "windows").with_path(path);
+        assert_eq!(discovery.find_driver("chromedriver"), Some(driver));
+    }
+
+    #[test]
+    fn browsers_available_on_linux() {
+        let root = TempDir::new().unwrap();
+        touch(root.path(), "usr/bin/firefox");
+        touch(root.path(), "usr/bin/geckodriver");
+        touch(root.path(), "opt/google/chrome/chrome");
+
+        let discovery =
+            empty_discovery(&root, "linux").with_path(root.path().join("usr/bin").as_os_str());
+        let browsers = discovery.available_browsers();
+        assert_eq!(browsers.len(), 2, "Result was {:?}", browsers);
+
+        let firefox = discovery.find_browser_for("firefox").unwrap();
+        assert!(firefox.driver_path.ends_with("geckodriver"));
+        let chrome = discovery.find_browser_for("chrome").unwrap();
+        assert!(chrome.browser_path.ends_with("opt/google/chrome/chrome"));
+        assert_eq!(chrome.driver_path, "".to_string());
+        assert!(discovery.find_browser_for("Safari").is_none());
+    }
+
+    #[test]
+    fn browsers_available_on_mac() {
+        let root = TempDir::new().unwrap();
+        touch(
+            root.path(),
+            "Applications/Firefox.app/Contents/MacOS/firefox",
+        );
+        touch(root.path(), "Applications/Safari.app/Contents/MacOS/Safari");
+
+        let discovery = empty_discovery(&root, "macos");
+        let browsers = discovery.available_browsers();
+        assert_eq!(browsers.len(), 2, "Result was {:?}", browsers);
+
+        let safari = discovery.find_browser_for("Safari").unwrap();
+        assert!(safari.driver_path.ends_with("usr/bin/safaridriver"));
+        assert!(discovery.find_browser_for("firefox").is_some());
+        assert!(discovery.find_browser_for("chrome").is_none());
+    }
+
+    #[test]
+    fn browsers_available_on_windows() {
+        let root = TempDir::new().unwrap();
+        touch(
+            root.path(),
+            "Program Files (x86)/Google/Chrome/Application/chrome.exe",
+        );
+        touch(root.path(), "drivers/chromedriver.exe");
+
+        let discovery =
+            empty_discovery(&root, "windows").with_path(root.path().join("drivers").as_os_str());
+        let browsers = discovery.available_browsers();
+        assert_eq!(browsers.len(), 1, "Result was {:?}", browsers);
+
+        let chrome = discovery.find_browser_for("chrome").unwrap();
+        assert!(chrome.driver_path.ends_with("chromedriver.exe"));
+        assert!(discovery.find_browser_for("Safari").is_none());
+    }
+
+    #[test]
+    fn browsers_on_path_win_over_install_locations() {
+        let root = TempDir::new().unwrap();
+        let on_path = touch(root.path(), "bin/firefox");
+        touch(root.path(), "usr/lib/firefox/firefox");
+
+        let discovery =
+            empty_discovery(&root, "linux").with_path(root.path().join("bin").as_os_str());
+        let browsers = discovery.available_browsers();
+        assert_eq!(browsers.len(), 1);
+        assert_eq!(browsers[0].browser_path, on_path.display().to_string());
+    }
+
+    #[test]
+    fn should_be_found_and_returned() {
+        let root = TempDir::new().unwrap();
+        touch(root.path(), "bin/google-chrome");
+
+        let discovery =
+            empty_discovery(&root, "linux").with_path(root.path().join("bin").as_os_str());
+        match discovery.find_browser_for("chrome") {
+            Some(browser) => assert_eq!(browser.name, "chrome".to_string()),
+            None => panic!("Was not able to find browsers in the fixture"),
+        }
+    }
+}
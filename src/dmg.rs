@@ -0,0 +1,1202 @@
+//! Reading macOS disk images without `hdiutil`, so that browsers shipped as
+//! `.dmg` files can be unpacked on any platform. Only what browser images
+//! use is supported: UDIF images holding an HFS+ volume.
+//!
+//! Problems with an image are reported as `io::Error`s, with `InvalidData`
+//! for a broken image and `Unsupported` for features that aren't read.
+
+use flate2::read::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+const SECTOR: u64 = 512;
+
+/// Chunk types in a UDIF block table.
+const CHUNK_ZERO: u32 = 0x0000_0000;
+const CHUNK_RAW: u32 = 0x0000_0001;
+const CHUNK_IGNORE: u32 = 0x0000_0002;
+const CHUNK_ADC: u32 = 0x8000_0004;
+const CHUNK_ZLIB: u32 = 0x8000_0005;
+const CHUNK_BZIP2: u32 = 0x8000_0006;
+const CHUNK_LZFSE: u32 = 0x8000_0007;
+const CHUNK_LZMA: u32 = 0x8000_0008;
+const CHUNK_COMMENT: u32 = 0x7fff_fffe;
+const CHUNK_END: u32 = 0xffff_ffff;
+
+/// Well known catalog node IDs.
+const ROOT_PARENT_ID: u32 = 1;
+const ROOT_FOLDER_ID: u32 = 2;
+const CATALOG_FILE_ID: u32 = 4;
+
+const FOLDER_RECORD: u16 = 1;
+const FILE_RECORD: u16 = 2;
+
+/// Set in a file's owner flags when its contents are compressed into an
+/// extended attribute rather than stored in the data fork.
+const UF_COMPRESSED: u8 = 0x20;
+/// Where hard linked files keep their contents.
+const PRIVATE_DATA: &str = "\0\0\0\0HFS+ Private Data";
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(feature: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, feature.into())
+}
+
+fn be16(buf: &[u8], at: usize) -> io::Result<u16> {
+    buf.get(at..at + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid("record is truncated"))
+}
+
+fn be32(buf: &[u8], at: usize) -> io::Result<u32> {
+    buf.get(at..at + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid("record is truncated"))
+}
+
+fn be64(buf: &[u8], at: usize) -> io::Result<u64> {
+    Ok(u64::from(be32(buf, at)?) << 32 | u64::from(be32(buf, at + 4)?))
+}
+
+/// How many bytes `sectors` sectors take up, as long as that fits.
+fn sector_bytes(sectors: u64) -> io::Result<u64> {
+    sectors
+        .checked_mul(SECTOR)
+        .ok_or_else(|| invalid("block table is too large"))
+}
+
+/// A run of sectors in a UDIF image and where its data is stored.
+#[derive(Debug)]
+struct Chunk {
+    kind: u32,
+    sector: u64,
+    sectors: u64,
+    offset: u64,
+    length: u64,
+}
+
+/// The HFS+ partition of a UDIF disk image.
+pub(crate) struct Udif {
+    image: File,
+    chunks: Vec<Chunk>,
+    sectors: u64,
+}
+
+impl Udif {
+    /// Reads the block table of the image, and picks out its HFS+ partition.
+    pub(crate) fn open(mut image: File) -> io::Result<Udif> {
+        let len = image.metadata()?.len();
+        if len < SECTOR {
+            return Err(invalid("too small to be a disk image"));
+        }
+        let mut trailer = [0; SECTOR as usize];
+        image.seek(SeekFrom::Start(len - SECTOR))?;
+        image.read_exact(&mut trailer)?;
+        if &trailer[..4] != b"koly" {
+            return Err(invalid("no UDIF trailer"));
+        }
+        let data_offset = be64(&trailer, 24)?;
+        let xml_offset = be64(&trailer, 216)?;
+        let xml_length = be64(&trailer, 224)?;
+        if xml_length == 0 || xml_offset.saturating_add(xml_length) > len {
+            return Err(invalid("no block table"));
+        }
+
+        let mut xml = vec![0; xml_length as usize];
+        image.seek(SeekFrom::Start(xml_offset))?;
+        image.read_exact(&mut xml)?;
+        let plist = plist::Value::from_reader_xml(Cursor::new(xml))
+            .map_err(|e| invalid(format!("unreadable block table: {}", e)))?;
+        let partitions = plist
+            .as_dictionary()
+            .and_then(|plist| plist.get("resource-fork"))
+            .and_then(|fork| fork.as_dictionary())
+            .and_then(|fork| fork.get("blkx"))
+            .and_then(|blkx| blkx.as_array())
+            .ok_or_else(|| invalid("no block table"))?;
+
+        let name_of = |partition: &plist::Value| {
+            partition
+                .as_dictionary()
+                .and_then(|partition| partition.get("Name").or_else(|| partition.get("CFName")))
+                .and_then(|name| name.as_string())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let hfs = partitions
+            .iter()
+            .find(|partition| name_of(partition).contains("Apple_HFS"));
+        let hfs = match hfs {
+            Some(hfs) => hfs,
+            None if partitions
+                .iter()
+                .any(|partition| name_of(partition).contains("APFS")) =>
+            {
+                return Err(unsupported("APFS volumes"))
+            }
+            None => return Err(invalid("no HFS+ partition")),
+        };
+        let table = hfs
+            .as_dictionary()
+            .and_then(|hfs| hfs.get("Data"))
+            .and_then(|data| data.as_data())
+            .ok_or_else(|| invalid("partition has no block table"))?;
+        let (sectors, chunks) = parse_block_table(table, data_offset)?;
+        Ok(Udif {
+            image,
+            chunks,
+            sectors,
+        })
+    }
+
+    /// How big the partition is once expanded. Callers should check this
+    /// against their limits before calling [`expand`](#method.expand).
+    pub(crate) fn size(&self) -> u64 {
+        self.sectors * SECTOR
+    }
+
+    /// Writes the partition out as a plain disk image. Runs of zeros are
+    /// left as holes.
+    pub(crate) fn expand(&mut self, out: &mut File) -> io::Result<()> {
+        out.set_len(self.size())?;
+        for chunk in &self.chunks {
+            let expanded = chunk.sectors * SECTOR;
+            self.image.seek(SeekFrom::Start(chunk.offset))?;
+            let mut stored = (&mut self.image).take(chunk.length);
+            let mut data = vec![];
+            match chunk.kind {
+                CHUNK_ZERO | CHUNK_IGNORE | CHUNK_COMMENT => continue,
+                CHUNK_RAW => {
+                    stored.take(expanded).read_to_end(&mut data)?;
+                }
+                CHUNK_ZLIB => {
+                    ZlibDecoder::new(stored)
+                        .take(expanded)
+                        .read_to_end(&mut data)?;
+                }
+                CHUNK_BZIP2 => {
+                    bzip2_rs::DecoderReader::new(stored)
+                        .take(expanded)
+                        .read_to_end(&mut data)?;
+                }
+                CHUNK_LZMA => {
+                    let mut compressed = vec![];
+                    stored.read_to_end(&mut compressed)?;
+                    let mut output = Capped {
+                        data: &mut data,
+                        cap: expanded,
+                    };
+                    let mut input = BufReader::new(&compressed[..]);
+                    let decompressed = if compressed.starts_with(&[0xfd, b'7', b'z', b'X', b'Z']) {
+                        lzma_rs::xz_decompress(&mut input, &mut output)
+                    } else {
+                        lzma_rs::lzma_decompress(&mut input, &mut output)
+                    };
+                    decompressed.map_err(|e| invalid(format!("bad LZMA chunk: {}", e)))?;
+                }
+                CHUNK_ADC => {
+                    let mut compressed = vec![];
+                    stored.read_to_end(&mut compressed)?;
+                    adc_decompress(&compressed, &mut data, expanded as usize)?;
+                }
+                CHUNK_LZFSE => return Err(unsupported("LZFSE compressed images")),
+                other => return Err(unsupported(format!("chunk type {:#x}", other))),
+            }
+            out.seek(SeekFrom::Start(chunk.sector * SECTOR))?;
+            out.write_all(&data)?;
+        }
+        out.flush()
+    }
+}
+
+/// Parses a `mish` block table. Chunk offsets are made absolute. The
+/// sector counts come straight from the image, so every chunk is checked to
+/// be inside a partition whose size in bytes fits in a `u64`.
+fn parse_block_table(table: &[u8], data_offset: u64) -> io::Result<(u64, Vec<Chunk>)> {
+    if table.get(..4) != Some(b"mish") {
+        return Err(invalid("partition has no block table"));
+    }
+    let sectors = be64(table, 16)?;
+    sector_bytes(sectors)?;
+    let base = data_offset.saturating_add(be64(table, 24)?);
+    let count = be32(table, 200)? as usize;
+    let mut chunks = vec![];
+    for i in 0..count {
+        let at = 204 + i * 40;
+        let kind = be32(table, at)?;
+        if kind == CHUNK_END {
+            break;
+        }
+        let chunk = Chunk {
+            kind,
+            sector: be64(table, at + 8)?,
+            sectors: be64(table, at + 16)?,
+            offset: base.saturating_add(be64(table, at + 24)?),
+            length: be64(table, at + 32)?,
+        };
+        match chunk.sector.checked_add(chunk.sectors) {
+            Some(end) if end <= sectors => chunks.push(chunk),
+            _ => return Err(invalid("block table runs past the partition")),
+        }
+    }
+    Ok((sectors, chunks))
+}
+
+/// A writer that refuses to grow `data` past `cap` bytes.
+struct Capped<'a> {
+    data: &'a mut Vec<u8>,
+    cap: u64,
+}
+
+impl Write for Capped<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.data.len() + buf.len()) as u64 > self.cap {
+            return Err(invalid("chunk expands past its size"));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Apple Data Compression, an LZ77 variant used by old disk images.
+fn adc_decompress(input: &[u8], out: &mut Vec<u8>, cap: usize) -> io::Result<()> {
+    let byte = |at: usize| {
+        input
+            .get(at)
+            .copied()
+            .ok_or_else(|| invalid("bad ADC chunk"))
+    };
+    let mut at = 0;
+    while at < input.len() {
+        let token = input[at];
+        let (length, distance) = if token & 0x80 != 0 {
+            let length = usize::from(token & 0x7f) + 1;
+            let literal = input
+                .get(at + 1..at + 1 + length)
+                .ok_or_else(|| invalid("bad ADC chunk"))?;
+            out.extend_from_slice(literal);
+            at += 1 + length;
+            if out.len() > cap {
+                return Err(invalid("chunk expands past its size"));
+            }
+            continue;
+        } else if token & 0x40 != 0 {
+            let length = usize::from(token & 0x3f) + 4;
+            let distance = usize::from(byte(at + 1)?) << 8 | usize::from(byte(at + 2)?);
+            at += 3;
+            (length, distance)
+        } else {
+            let length = usize::from((token & 0x3c) >> 2) + 3;
+            let distance = usize::from(token & 0x03) << 8 | usize::from(byte(at + 1)?);
+            at += 2;
+            (length, distance)
+        };
+        if distance >= out.len() || out.len() + length > cap {
+            return Err(invalid("bad ADC chunk"));
+        }
+        let start = out.len() - distance - 1;
+        for i in 0..length {
+            let copied = out[start + i];
+            out.push(copied);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    start: u32,
+    count: u32,
+}
+
+/// Where the contents of a file are on the volume.
+#[derive(Debug, Clone)]
+pub(crate) struct Fork {
+    size: u64,
+    blocks: u32,
+    extents: Vec<Extent>,
+}
+
+impl Fork {
+    fn parse(buf: &[u8], at: usize) -> io::Result<Fork> {
+        let mut extents = vec![];
+        for i in 0..8 {
+            let extent = Extent {
+                start: be32(buf, at + 16 + i * 8)?,
+                count: be32(buf, at + 20 + i * 8)?,
+            };
+            if extent.count > 0 {
+                extents.push(extent);
+            }
+        }
+        Ok(Fork {
+            size: be64(buf, at)?,
+            blocks: be32(buf, at + 12)?,
+            extents,
+        })
+    }
+
+    fn mapped_blocks(&self) -> u64 {
+        self.extents
+            .iter()
+            .map(|extent| u64::from(extent.count))
+            .sum()
+    }
+}
+
+/// What a catalog entry is.
+#[derive(Debug)]
+pub(crate) enum Kind {
+    Folder,
+    File(Fork),
+    /// The fork holds the path the link points to.
+    Symlink(Fork),
+}
+
+/// A file, folder or link on the volume.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    /// The path from the top of the volume, separated by `/`.
+    pub(crate) path: String,
+    pub(crate) kind: Kind,
+    /// The Unix permission bits, if any were set.
+    pub(crate) mode: Option<u32>,
+}
+
+/// A catalog record before paths have been worked out.
+struct Record {
+    parent: u32,
+    name: String,
+    id: u32,
+    kind: Kind,
+    mode: u16,
+    /// For hard links, the number of the `iNode` file holding the contents.
+    link: Option<u32>,
+}
+
+/// An HFS+ volume in a plain disk image.
+pub(crate) struct Volume {
+    disk: File,
+    block_size: u64,
+    catalog: Fork,
+    extents: Fork,
+}
+
+impl Volume {
+    pub(crate) fn open(mut disk: File) -> io::Result<Volume> {
+        let mut header = [0; 512];
+        disk.seek(SeekFrom::Start(1024))?;
+        disk.read_exact(&mut header)?;
+        match &header[..2] {
+            b"H+" | b"HX" => {}
+            b"BD" => return Err(unsupported("HFS volumes")),
+            _ => return Err(invalid("no HFS+ volume")),
+        }
+        let block_size = u64::from(be32(&header, 40)?);
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(invalid("bad block size"));
+        }
+        let mut volume = Volume {
+            disk,
+            block_size,
+            extents: Fork::parse(&header, 192)?,
+            catalog: Fork::parse(&header, 272)?,
+        };
+        if volume.extents.mapped_blocks() < u64::from(volume.extents.blocks) {
+            return Err(invalid("extents file is fragmented"));
+        }
+        let catalog = volume.catalog.clone();
+        volume.catalog = volume.with_overflow(catalog, CATALOG_FILE_ID)?;
+        Ok(volume)
+    }
+
+    /// Lists everything on the volume, with each folder before what is in
+    /// it.
+    pub(crate) fn entries(&mut self) -> io::Result<Vec<Entry>> {
+        let catalog = self.catalog.clone();
+        let mut records = vec![];
+        for (key, data) in self.leaf_records(&catalog)? {
+            if let Some(record) = parse_catalog_record(&key, &data)? {
+                records.push(record);
+            }
+        }
+
+        let folders: HashMap<u32, (u32, String)> = records
+            .iter()
+            .filter(|record| matches!(record.kind, Kind::Folder))
+            .map(|record| (record.id, (record.parent, record.name.clone())))
+            .collect();
+        let private = records
+            .iter()
+            .find(|record| record.parent == ROOT_FOLDER_ID && record.name == PRIVATE_DATA)
+            .map(|record| record.id);
+        let mut inodes: HashMap<String, Fork> = HashMap::new();
+        for record in &records {
+            if let (Some(private), Kind::File(fork)) = (private, &record.kind) {
+                if record.parent == private {
+                    inodes.insert(record.name.clone(), fork.clone());
+                }
+            }
+        }
+
+        let mut entries = vec![];
+        for record in records {
+            if record.id == ROOT_FOLDER_ID
+                || record.parent == ROOT_PARENT_ID
+                || Some(record.id) == private
+            {
+                continue;
+            }
+            let path = match path_of(&folders, record.parent, &record.name)? {
+                Some(path) => path,
+                // Something in the private data folder, which is only
+                // reached through the links to it.
+                None => continue,
+            };
+            let kind = match (record.kind, record.link) {
+                (Kind::File(_), Some(inode)) => {
+                    let fork = inodes.get(&format!("iNode{}", inode)).ok_or_else(|| {
+                        invalid(format!("{} is a hard link to a missing file", path))
+                    })?;
+                    Kind::File(fork.clone())
+                }
+                (kind, _) => kind,
+            };
+            let kind = match kind {
+                Kind::File(fork) => Kind::File(self.with_overflow(fork, record.id)?),
+                Kind::Symlink(fork) => Kind::Symlink(self.with_overflow(fork, record.id)?),
+                Kind::Folder => Kind::Folder,
+            };
+            let mode = u32::from(record.mode & 0o7777);
+            entries.push(Entry {
+                path,
+                kind,
+                mode: if mode == 0 { None } else { Some(mode) },
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// A reader for the contents of `fork`.
+    pub(crate) fn open_fork<'a>(&'a mut self, fork: &'a Fork) -> ForkReader<'a> {
+        ForkReader {
+            volume: self,
+            fork,
+            position: 0,
+        }
+    }
+
+    fn read_at(&mut self, fork: &Fork, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut reader = self.open_fork(fork);
+        reader.position = offset;
+        reader.read_exact(buf)
+    }
+
+    /// Adds the extents that didn't fit in the catalog record, which are
+    /// kept in the extents overflow file.
+    fn with_overflow(&mut self, mut fork: Fork, id: u32) -> io::Result<Fork> {
+        if fork.mapped_blocks() >= u64::from(fork.blocks) {
+            return Ok(fork);
+        }
+        let extents_file = self.extents.clone();
+        let mut overflow = vec![];
+        for (key, data) in self.leaf_records(&extents_file)? {
+            // The key is the fork type, the file ID and the first block
+            // the extents cover. Only data forks are read.
+            if key.first() == Some(&0) && be32(&key, 2)? == id {
+                let start = be32(&key, 6)?;
+                overflow.push((start, Fork::parse(&[&[0; 16][..], &data].concat(), 0)?));
+            }
+        }
+        overflow.sort_by_key(|(start, _)| *start);
+        for (_, extra) in overflow {
+            fork.extents.extend(extra.extents);
+        }
+        if fork.mapped_blocks() < u64::from(fork.blocks) {
+            return Err(invalid(format!("file {} is missing extents", id)));
+        }
+        Ok(fork)
+    }
+
+    /// Every record in the leaf nodes of the B-tree in `fork`, as a key
+    /// without its length and the data that follows it.
+    fn leaf_records(&mut self, fork: &Fork) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut header = [0; 46];
+        self.read_at(fork, 0, &mut header)?;
+        let first_leaf = be32(&header, 24)?;
+        let node_size = u64::from(be16(&header, 32)?);
+        let total_nodes = be32(&header, 36)?;
+        if node_size < 512 {
+            return Err(invalid("bad B-tree node size"));
+        }
+
+        let mut records = vec![];
+        let mut next = first_leaf;
+        let mut visited = 0;
+        while next != 0 {
+            visited += 1;
+            if visited > total_nodes || next >= total_nodes {
+                return Err(invalid("B-tree leaves loop"));
+            }
+            let mut node = vec![0; node_size as usize];
+            self.read_at(fork, u64::from(next) * node_size, &mut node)?;
+            if node[8] != 0xff {
+                return Err(invalid("B-tree leaf is not a leaf"));
+            }
+            // The record offsets are at the end of the node, with one more
+            // for where the free space starts
+            let count = usize::from(be16(&node, 10)?);
+            if 14 + 2 * (count + 1) > node.len() {
+                return Err(invalid("B-tree leaf has too many records"));
+            }
+            for i in 0..count {
+                let start = usize::from(be16(&node, node.len() - 2 * (i + 1))?);
+                let end = usize::from(be16(&node, node.len() - 2 * (i + 2))?);
+                let record = node
+                    .get(start..end)
+                    .ok_or_else(|| invalid("B-tree record is out of bounds"))?;
+                let key_length = usize::from(be16(record, 0)?);
+                let key = record
+                    .get(2..2 + key_length)
+                    .ok_or_else(|| invalid("B-tree key is out of bounds"))?;
+                records.push((key.to_vec(), record[2 + key_length..].to_vec()));
+            }
+            next = be32(&node, 0)?;
+        }
+        Ok(records)
+    }
+}
+
+/// Reads a fork by following its extents.
+pub(crate) struct ForkReader<'a> {
+    volume: &'a mut Volume,
+    fork: &'a Fork,
+    position: u64,
+}
+
+impl Read for ForkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.fork.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.volume.block_size;
+        let mut block = self.position / block_size;
+        for extent in &self.fork.extents {
+            let count = u64::from(extent.count);
+            if block < count {
+                let within = self.position % block_size + block * block_size;
+                let left_in_extent = count * block_size - within;
+                let left_in_fork = self.fork.size - self.position;
+                let wanted = (buf.len() as u64).min(left_in_extent).min(left_in_fork) as usize;
+                let at = u64::from(extent.start) * block_size + within;
+                self.volume.disk.seek(SeekFrom::Start(at))?;
+                let read = self.volume.disk.read(&mut buf[..wanted])?;
+                if read == 0 {
+                    return Err(invalid("file runs past the end of the volume"));
+                }
+                self.position += read as u64;
+                return Ok(read);
+            }
+            block -= count;
+        }
+        Err(invalid("file runs past its extents"))
+    }
+}
+
+/// Turns a catalog leaf record into a `Record`. Thread records, which
+/// only map IDs back to names, are skipped.
+fn parse_catalog_record(key: &[u8], data: &[u8]) -> io::Result<Option<Record>> {
+    let parent = be32(key, 0)?;
+    let length = usize::from(be16(key, 4)?);
+    let units = (0..length)
+        .map(|i| be16(key, 6 + i * 2))
+        .collect::<io::Result<Vec<u16>>>()?;
+    let name = String::from_utf16(&units).map_err(|_| invalid("name is not valid UTF-16"))?;
+
+    let mode = be16(data, 42).unwrap_or(0);
+    let record = match be16(data, 0)? {
+        FOLDER_RECORD => Record {
+            parent,
+            name,
+            id: be32(data, 8)?,
+            kind: Kind::Folder,
+            mode,
+            link: None,
+        },
+        FILE_RECORD => {
+            // Everything up to the data fork, which checks its own length
+            if data.len() < 88 {
+                return Err(invalid(format!("the record for {} is truncated", name)));
+            }
+            let id = be32(data, 8)?;
+            let owner_flags = data[41];
+            let file_type = &data[48..52];
+            let creator = &data[52..56];
+            let fork = Fork::parse(data, 88)?;
+            if owner_flags & UF_COMPRESSED != 0 {
+                return Err(unsupported(format!("HFS+ compressed files, like {}", name)));
+            }
+            let (kind, link) = match (file_type, creator) {
+                (b"slnk", b"rhap") => (Kind::Symlink(fork), None),
+                (b"hlnk", b"hfs+") => (Kind::File(fork), Some(be32(data, 44)?)),
+                (b"fdrp", b"MACS") => {
+                    return Err(unsupported(format!("folder hard links, like {}", name)))
+                }
+                _ => (Kind::File(fork), None),
+            };
+            Record {
+                parent,
+                name,
+                id,
+                kind,
+                mode,
+                link,
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(record))
+}
+
+/// The path of `name` in the folder `parent`, or `None` if it is inside
+/// the private data folder.
+fn path_of(
+    folders: &HashMap<u32, (u32, String)>,
+    parent: u32,
+    name: &str,
+) -> io::Result<Option<String>> {
+    let mut parts = vec![name.to_string()];
+    let mut folder = parent;
+    while folder != ROOT_FOLDER_ID {
+        let (grandparent, folder_name) = folders
+            .get(&folder)
+            .ok_or_else(|| invalid(format!("{} is in a missing folder", name)))?;
+        if *grandparent == ROOT_FOLDER_ID && folder_name == PRIVATE_DATA {
+            return Ok(None);
+        }
+        parts.push(folder_name.clone());
+        if parts.len() > 512 {
+            return Err(invalid("folders loop"));
+        }
+        folder = *grandparent;
+    }
+    parts.reverse();
+    Ok(Some(parts.join("/")))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// What goes in a fixture volume.
+    pub(crate) enum Item<'a> {
+        Folder(&'a str),
+        File(&'a str, &'a [u8], u16),
+        Symlink(&'a str, &'a str),
+        /// A file stored in as many one block extents as it needs, so that
+        /// some of them are in the extents overflow file.
+        Fragmented(&'a str, &'a [u8]),
+        /// A hard link to a file with these contents.
+        HardLink(&'a str, &'a [u8]),
+    }
+
+    const BLOCK: usize = 512;
+    const NODE: usize = 4096;
+
+    fn put16(buf: &mut [u8], at: usize, value: u16) {
+        buf[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put32(buf: &mut [u8], at: usize, value: u32) {
+        buf[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put64(buf: &mut [u8], at: usize, value: u64) {
+        buf[at..at + 8].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_fork(buf: &mut [u8], at: usize, size: u64, extents: &[(u32, u32)]) {
+        put64(buf, at, size);
+        put32(buf, at + 12, extents.iter().map(|(_, count)| count).sum());
+        for (i, (start, count)) in extents.iter().take(8).enumerate() {
+            put32(buf, at + 16 + i * 8, *start);
+            put32(buf, at + 20 + i * 8, *count);
+        }
+    }
+
+    fn catalog_key(parent: u32, name: &str) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let mut key = vec![0; 8 + units.len() * 2];
+        put16(&mut key, 0, (6 + units.len() * 2) as u16);
+        put32(&mut key, 2, parent);
+        put16(&mut key, 6, units.len() as u16);
+        for (i, unit) in units.iter().enumerate() {
+            put16(&mut key, 8 + i * 2, *unit);
+        }
+        key
+    }
+
+    fn folder_record(parent: u32, name: &str, id: u32) -> Vec<u8> {
+        let mut record = catalog_key(parent, name);
+        let mut data = vec![0; 88];
+        put16(&mut data, 0, FOLDER_RECORD);
+        put32(&mut data, 8, id);
+        put16(&mut data, 42, 0o040_755);
+        record.extend(data);
+        record
+    }
+
+    struct FileRecord<'a> {
+        parent: u32,
+        name: &'a str,
+        id: u32,
+        mode: u16,
+        kind: (&'a [u8; 4], &'a [u8; 4]),
+        special: u32,
+        size: u64,
+        extents: &'a [(u32, u32)],
+    }
+
+    fn file_record(file: FileRecord) -> Vec<u8> {
+        let mut record = catalog_key(file.parent, file.name);
+        let mut data = vec![0; 248];
+        put16(&mut data, 0, FILE_RECORD);
+        put32(&mut data, 8, file.id);
+        put16(&mut data, 42, file.mode);
+        put32(&mut data, 44, file.special);
+        data[48..52].copy_from_slice(file.kind.0);
+        data[52..56].copy_from_slice(file.kind.1);
+        put_fork(&mut data, 88, file.size, file.extents);
+        record.extend(data);
+        record
+    }
+
+    /// Lays out B-tree nodes: a header node followed by leaves holding
+    /// `records`, a few to a node so that the leaves are chained.
+    fn btree(records: &[Vec<u8>]) -> Vec<u8> {
+        let leaves: Vec<&[Vec<u8>]> = records.chunks(3).collect();
+        let total = 1 + leaves.len();
+        let mut tree = vec![0; total * NODE];
+        {
+            let header = &mut tree[..NODE];
+            header[8] = 1;
+            put16(header, 10, 3);
+            put32(header, 24, if leaves.is_empty() { 0 } else { 1 });
+            put32(header, 28, leaves.len() as u32);
+            put16(header, 32, NODE as u16);
+            put32(header, 36, total as u32);
+        }
+        for (i, leaf) in leaves.iter().enumerate() {
+            let node = &mut tree[(i + 1) * NODE..(i + 2) * NODE];
+            if i + 1 < leaves.len() {
+                put32(node, 0, i as u32 + 2);
+            }
+            node[8] = 0xff;
+            node[9] = 1;
+            put16(node, 10, leaf.len() as u16);
+            let mut at = 14;
+            for (j, record) in leaf.iter().enumerate() {
+                node[at..at + record.len()].copy_from_slice(record);
+                put16(node, NODE - 2 * (j + 1), at as u16);
+                at += record.len();
+            }
+            put16(node, NODE - 2 * (leaf.len() + 1), at as u16);
+        }
+        tree
+    }
+
+    /// Builds an HFS+ volume holding `items`.
+    pub(crate) fn hfs_volume(items: &[Item]) -> Vec<u8> {
+        let mut blocks: Vec<u8> = vec![0; 8 * BLOCK];
+        let mut store = |data: &[u8]| -> (u32, u32) {
+            let start = (blocks.len() / BLOCK) as u32;
+            let count = data.len().div_ceil(BLOCK).max(1) as u32;
+            blocks.extend_from_slice(data);
+            blocks.resize((start + count) as usize * BLOCK, 0);
+            (start, count)
+        };
+
+        let mut records = vec![folder_record(ROOT_PARENT_ID, "Fixture", ROOT_FOLDER_ID)];
+        let mut overflow = vec![];
+        let mut folders: HashMap<String, u32> = HashMap::new();
+        let mut next_id = 16;
+        let mut private = None;
+        let parent_of = |path: &str, folders: &HashMap<String, u32>| match path.rfind('/') {
+            Some(slash) => (folders[&path[..slash]], path[slash + 1..].to_string()),
+            None => (ROOT_FOLDER_ID, path.to_string()),
+        };
+
+        for item in items {
+            next_id += 1;
+            let id = next_id;
+            match item {
+                Item::Folder(path) => {
+                    let (parent, name) = parent_of(path, &folders);
+                    folders.insert(path.to_string(), id);
+                    records.push(folder_record(parent, &name, id));
+                }
+                Item::File(path, data, mode) => {
+                    let (parent, name) = parent_of(path, &folders);
+                    let extent = store(data);
+                    records.push(file_record(FileRecord {
+                        parent,
+                        name: &name,
+                        id,
+                        mode: 0o100_000 | mode,
+                        kind: (b"\0\0\0\0", b"\0\0\0\0"),
+                        special: 0,
+                        size: data.len() as u64,
+                        extents: &[extent],
+                    }));
+                }
+                Item::Symlink(path, target) => {
+                    let (parent, name) = parent_of(path, &folders);
+                    let extent = store(target.as_bytes());
+                    records.push(file_record(FileRecord {
+                        parent,
+                        name: &name,
+                        id,
+                        mode: 0o120_755,
+                        kind: (b"slnk", b"rhap"),
+                        special: 0,
+                        size: target.len() as u64,
+                        extents: &[extent],
+                    }));
+                }
+                Item::Fragmented(path, data) => {
+                    let (parent, name) = parent_of(path, &folders);
+                    // Every other block is left unused, so the extents
+                    // can't be merged.
+                    let extents: Vec<(u32, u32)> = data
+                        .chunks(BLOCK)
+                        .map(|chunk| {
+                            let extent = store(chunk);
+                            store(&[]);
+                            extent
+                        })
+                        .collect();
+                    records.push(file_record(FileRecord {
+                        parent,
+                        name: &name,
+                        id,
+                        mode: 0o100_644,
+                        kind: (b"\0\0\0\0", b"\0\0\0\0"),
+                        special: 0,
+                        size: data.len() as u64,
+                        extents: &extents,
+                    }));
+                    for (i, more) in extents[8..].chunks(8).enumerate() {
+                        let mut record = vec![0; 12];
+                        put16(&mut record, 0, 10);
+                        put32(&mut record, 4, id);
+                        put32(&mut record, 8, 8 + i as u32 * 8);
+                        let mut data = vec![0; 80];
+                        put_fork(&mut data, 0, 0, more);
+                        record.extend_from_slice(&data[16..]);
+                        overflow.push(record);
+                    }
+                }
+                Item::HardLink(path, data) => {
+                    let private = *private.get_or_insert_with(|| {
+                        next_id += 1;
+                        records.push(folder_record(ROOT_FOLDER_ID, PRIVATE_DATA, next_id));
+                        next_id
+                    });
+                    next_id += 1;
+                    let inode = next_id;
+                    let extent = store(data);
+                    records.push(file_record(FileRecord {
+                        parent: private,
+                        name: &format!("iNode{}", inode),
+                        id: inode,
+                        mode: 0o100_755,
+                        kind: (b"\0\0\0\0", b"\0\0\0\0"),
+                        special: 0,
+                        size: data.len() as u64,
+                        extents: &[extent],
+                    }));
+                    let (parent, name) = parent_of(path, &folders);
+                    records.push(file_record(FileRecord {
+                        parent,
+                        name: &name,
+                        id,
+                        mode: 0o100_444,
+                        kind: (b"hlnk", b"hfs+"),
+                        special: inode,
+                        size: 0,
+                        extents: &[],
+                    }));
+                }
+            }
+        }
+
+        let catalog = btree(&records);
+        let catalog_extent = store(&catalog);
+        let extents = btree(&overflow);
+        let extents_extent = store(&extents);
+
+        let mut volume = blocks;
+        let total_blocks = (volume.len() / BLOCK) as u32;
+        let header = &mut volume[1024..1536];
+        header[..2].copy_from_slice(b"H+");
+        put16(header, 2, 4);
+        put32(header, 40, BLOCK as u32);
+        put32(header, 44, total_blocks);
+        put_fork(header, 192, extents.len() as u64, &[extents_extent]);
+        put_fork(header, 272, catalog.len() as u64, &[catalog_extent]);
+        volume
+    }
+
+    fn block_table(sectors: u64, chunks: &[(u32, u64, u64, u64, u64)]) -> Vec<u8> {
+        let mut table = vec![0; 204 + (chunks.len() + 1) * 40];
+        table[..4].copy_from_slice(b"mish");
+        put32(&mut table, 4, 1);
+        put64(&mut table, 16, sectors);
+        put32(&mut table, 200, chunks.len() as u32 + 1);
+        for (i, (kind, sector, count, offset, length)) in chunks
+            .iter()
+            .chain(Some(&(CHUNK_END, sectors, 0, 0, 0)))
+            .enumerate()
+        {
+            let at = 204 + i * 40;
+            put32(&mut table, at, *kind);
+            put64(&mut table, at + 8, *sector);
+            put64(&mut table, at + 16, *count);
+            put64(&mut table, at + 24, *offset);
+            put64(&mut table, at + 32, *length);
+        }
+        table
+    }
+
+    /// Wraps `volume` in a UDIF image the way `hdiutil` does, with a
+    /// protective MBR entry before the HFS+ partition. Runs of zeros are
+    /// left out and the rest is stored in turn as raw, zlib, xz and ADC
+    /// chunks.
+    pub(crate) fn udif(volume: &[u8]) -> Vec<u8> {
+        udif_named(volume, "disk image (Apple_HFS : 1)")
+    }
+
+    /// Like `udif`, with `name` for the partition holding the volume.
+    pub(crate) fn udif_named(volume: &[u8], name: &str) -> Vec<u8> {
+        let mut data = vec![];
+        let mut chunks = vec![];
+        let per_chunk = 4 * SECTOR as usize;
+        for (i, piece) in volume.chunks(per_chunk).enumerate() {
+            let sector = (i * per_chunk) as u64 / SECTOR;
+            let sectors = (piece.len() as u64).div_ceil(SECTOR);
+            if piece.iter().all(|byte| *byte == 0) {
+                chunks.push((CHUNK_ZERO, sector, sectors, 0, 0));
+                continue;
+            }
+            let (kind, stored) = match i % 4 {
+                0 => (CHUNK_RAW, piece.to_vec()),
+                1 => {
+                    let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+                    encoder.write_all(piece).unwrap();
+                    (CHUNK_ZLIB, encoder.finish().unwrap())
+                }
+                2 => {
+                    let mut xz = vec![];
+                    lzma_rs::xz_compress(&mut &piece[..], &mut xz).unwrap();
+                    (CHUNK_LZMA, xz)
+                }
+                _ => {
+                    // Only literal runs, which are valid if not small.
+                    let mut adc = vec![];
+                    for run in piece.chunks(128) {
+                        adc.push(0x80 | (run.len() as u8 - 1));
+                        adc.extend_from_slice(run);
+                    }
+                    (CHUNK_ADC, adc)
+                }
+            };
+            chunks.push((
+                kind,
+                sector,
+                sectors,
+                data.len() as u64,
+                stored.len() as u64,
+            ));
+            data.extend(stored);
+        }
+        let sectors = (volume.len() as u64).div_ceil(SECTOR);
+        let hfs_table = block_table(sectors, &chunks);
+        let mbr_table = block_table(1, &[(CHUNK_ZERO, 0, 1, 0, 0)]);
+
+        let partition = |name: &str, table: Vec<u8>| {
+            let mut partition = plist::Dictionary::new();
+            partition.insert("Attributes".into(), "0x0050".into());
+            partition.insert("CFName".into(), name.into());
+            partition.insert("Data".into(), plist::Value::Data(table));
+            partition.insert("Name".into(), name.into());
+            plist::Value::Dictionary(partition)
+        };
+        let mut fork = plist::Dictionary::new();
+        fork.insert(
+            "blkx".into(),
+            plist::Value::Array(vec![
+                partition("Protective Master Boot Record (MBR : 0)", mbr_table),
+                partition(name, hfs_table),
+            ]),
+        );
+        let mut plist = plist::Dictionary::new();
+        plist.insert("resource-fork".into(), plist::Value::Dictionary(fork));
+        let mut xml = vec![];
+        plist::Value::Dictionary(plist)
+            .to_writer_xml(&mut xml)
+            .unwrap();
+
+        let mut trailer = vec![0; SECTOR as usize];
+        trailer[..4].copy_from_slice(b"koly");
+        put32(&mut trailer, 4, 4);
+        put32(&mut trailer, 8, 512);
+        put64(&mut trailer, 32, data.len() as u64);
+        put64(&mut trailer, 216, data.len() as u64);
+        put64(&mut trailer, 224, xml.len() as u64);
+        put64(&mut trailer, 492, sectors + 1);
+
+        let mut image = data;
+        image.extend(xml);
+        image.extend(trailer);
+        image
+    }
+
+    fn open_volume(image: &[u8], dir: &TempDir) -> Volume {
+        let path = dir.path().join("image.dmg");
+        fs::write(&path, image).unwrap();
+        let mut udif = Udif::open(File::open(&path).unwrap()).unwrap();
+        let mut disk = tempfile::tempfile_in(dir.path()).unwrap();
+        udif.expand(&mut disk).unwrap();
+        Volume::open(disk).unwrap()
+    }
+
+    fn read_entry(volume: &mut Volume, entry: &Entry) -> Vec<u8> {
+        let fork = match &entry.kind {
+            Kind::File(fork) | Kind::Symlink(fork) => fork.clone(),
+            Kind::Folder => panic!("{} is a folder", entry.path),
+        };
+        let mut contents = vec![];
+        volume.open_fork(&fork).read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn volumes_are_listed_from_the_image() {
+        let big: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let image = udif(&hfs_volume(&[
+            Item::Folder("Firefox.app"),
+            Item::Folder("Firefox.app/Contents"),
+            Item::File("Firefox.app/Contents/Info.plist", b"<plist/>", 0o644),
+            Item::Folder("Firefox.app/Contents/MacOS"),
+            Item::File("Firefox.app/Contents/MacOS/firefox", &big, 0o755),
+            Item::Symlink("Applications", "/Applications"),
+        ]));
+        let dir = TempDir::new().unwrap();
+        let mut volume = open_volume(&image, &dir);
+        let entries = volume.entries().unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "Applications",
+                "Firefox.app",
+                "Firefox.app/Contents",
+                "Firefox.app/Contents/Info.plist",
+                "Firefox.app/Contents/MacOS",
+                "Firefox.app/Contents/MacOS/firefox",
+            ]
+        );
+        assert_eq!(read_entry(&mut volume, &entries[5]), big);
+        assert_eq!(entries[5].mode, Some(0o755));
+        assert_eq!(read_entry(&mut volume, &entries[0]), b"/Applications");
+        assert!(matches!(entries[0].kind, Kind::Symlink(_)));
+    }
+
+    #[test]
+    fn overflow_extents_and_hard_links_are_followed() {
+        let fragmented: Vec<u8> = (0..20 * BLOCK as u32).map(|i| (i % 13) as u8).collect();
+        let image = udif(&hfs_volume(&[
+            Item::Fragmented("fragmented", &fragmented),
+            Item::HardLink("linked", b"shared contents"),
+        ]));
+        let dir = TempDir::new().unwrap();
+        let mut volume = open_volume(&image, &dir);
+        let entries = volume.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(read_entry(&mut volume, &entries[0]), fragmented);
+        assert_eq!(entries[1].path, "linked");
+        assert_eq!(read_entry(&mut volume, &entries[1]), b"shared contents");
+    }
+
+    #[test]
+    fn adc_copies_from_what_was_already_written() {
+        // "abc", then copy 4 bytes from 3 back, then 3 bytes from 2 back.
+        let compressed = [0x82, b'a', b'b', b'c', 0x40, 0x00, 0x02, 0x00, 0x01];
+        let mut out = vec![];
+        adc_decompress(&compressed, &mut out, 100).unwrap();
+        assert_eq!(out, b"abcabcacac");
+        assert!(adc_decompress(&[0x00, 0x05], &mut vec![], 100).is_err());
+    }
+
+    #[test]
+    fn images_without_hfs_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.dmg");
+        let image = udif_named(&hfs_volume(&[]), "disk image (Apple_APFS : 1)");
+        fs::write(&path, &image).unwrap();
+        let error = Udif::open(File::open(&path).unwrap()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+        fs::write(&path, vec![0; 2048]).unwrap();
+        let error = Udif::open(File::open(&path).unwrap()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_sizes_and_records_are_invalid() {
+        let chunk = (CHUNK_RAW, 0, 1, 0, SECTOR);
+        let tables = [
+            block_table(u64::MAX / 2, &[]),
+            block_table(1, &[(CHUNK_RAW, u64::MAX, 2, 0, 0)]),
+            block_table(1, &[chunk, (CHUNK_RAW, 1, 1, 0, 0)]),
+        ];
+        for table in &tables {
+            let error = parse_block_table(table, 0).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        assert_eq!(
+            parse_block_table(&block_table(1, &[chunk]), 0).unwrap().0,
+            1
+        );
+
+        let key = vec![0; 6];
+        let mut data = vec![0; 12];
+        put16(&mut data, 0, FILE_RECORD);
+        let error = parse_catalog_record(&key, &data).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // A leaf that says it has more records than fit in it
+        let mut volume = hfs_volume(&[Item::File("a", b"a", 0o644)]);
+        let catalog = 9 * BLOCK;
+        let first_leaf = be32(&volume[catalog..], 24).unwrap() as usize;
+        put16(&mut volume, catalog + first_leaf * NODE + 10, u16::MAX);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("volume");
+        fs::write(&path, &volume).unwrap();
+        let mut volume = Volume::open(File::open(&path).unwrap()).unwrap();
+        let error = volume.entries().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,1577 @@
+//! Unpacking browser and driver archives without trusting what is inside
+//! them. Every entry is checked before anything is written, so that a hostile or broken
+//! archive can't write outside the directory it is unpacked into.
+
+use crate::{cab, dmg, msi};
+use flate2::read::GzDecoder;
+use flate2::Crc;
+use log::debug;
+use ruzstd::StreamingDecoder;
+use sevenz_rust::{Password, SevenZArchiveEntry, SevenZReader};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use tar::{Archive, EntryType};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// The most an archive may unpack to. Browsers are hundreds of megabytes,
+/// so anything near this is a decompression bomb rather than a browser.
+const MAX_UNPACKED_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+/// The most entries an archive may have.
+const MAX_ENTRIES: usize = 10_000;
+/// The end of archive marker and block padding at the end of a tarball.
+const TAR_PADDING: u64 = 20 * 512;
+
+/// The signature and size of the header at the start of a 7z archive.
+const SEVEN_ZIP_MAGIC: [u8; 6] = [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c];
+const SEVEN_ZIP_HEADER: usize = 32;
+/// Set in a 7z entry's attributes when the high half holds a Unix mode.
+const UNIX_EXTENSION: u32 = 0x8000;
+
+/// The file type bits of a Unix mode, and the value they have for symlinks.
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Errors from unpacking an archive. Entries that could escape the directory
+/// being unpacked into are refused rather than skipped, as an archive that
+/// contains them can't be trusted.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// A file couldn't be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// The archive couldn't be read as the format it claims to be.
+    Corrupt { archive: PathBuf, reason: String },
+    /// An entry has an absolute path or one that uses `..`.
+    UnsafePath { entry: String },
+    /// A link points outside the directory being unpacked into.
+    UnsafeLink { entry: String, target: String },
+    /// An entry is something other than a file, directory or link, like a
+    /// device file.
+    Unsupported { entry: String, kind: String },
+    /// The archive isn't in any format we know.
+    UnknownFormat { archive: PathBuf },
+    /// The archive uses something we can't read, like a compression method
+    /// or file system.
+    UnsupportedFeature { archive: PathBuf, feature: String },
+    /// The archive unpacks to more than `limit` bytes.
+    TooLarge { limit: u64 },
+    /// The archive has more than `limit` entries.
+    TooManyEntries { limit: usize },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractError::Io { path, source } => {
+                write!(f, "Could not unpack {}: {}", path.display(), source)
+            }
+            ExtractError::Corrupt { archive, reason } => {
+                write!(
+                    f,
+                    "{} is not a valid archive: {}",
+                    archive.display(),
+                    reason
+                )
+            }
+            ExtractError::UnsafePath { entry } => {
+                write!(
+                    f,
+                    "Refusing to unpack {} as it is outside the target",
+                    entry
+                )
+            }
+            ExtractError::UnsafeLink { entry, target } => write!(
+                f,
+                "Refusing to unpack link {} as it points outside the target to {}",
+                entry, target
+            ),
+            ExtractError::Unsupported { entry, kind } => {
+                write!(f, "Refusing to unpack {} as it is a {}", entry, kind)
+            }
+            ExtractError::UnknownFormat { archive } => {
+                write!(f, "{} is not an archive format we know", archive.display())
+            }
+            ExtractError::UnsupportedFeature { archive, feature } => write!(
+                f,
+                "{} uses {}, which can't be unpacked",
+                archive.display(),
+                feature
+            ),
+            ExtractError::TooLarge { limit } => {
+                write!(f, "Archive unpacks to more than {} bytes", limit)
+            }
+            ExtractError::TooManyEntries { limit } => {
+                write!(f, "Archive has more than {} entries", limit)
+            }
+        }
+    }
+}
+
+impl Error for ExtractError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExtractError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// How much an archive is allowed to unpack to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub(crate) max_bytes: u64,
+    pub(crate) max_entries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bytes: MAX_UNPACKED_BYTES,
+            max_entries: MAX_ENTRIES,
+        }
+    }
+}
+
+/// Unpacks `archive` into `dest`, calling `on_file` with the number of
+/// entries done and, when it is known, the total.
+pub(crate) fn unpack(
+    archive: &Path,
+    dest: &Path,
+    on_file: &dyn Fn(u64, Option<u64>),
+) -> Result<(), ExtractError> {
+    unpack_with(archive, dest, Limits::default(), on_file)
+}
+
+pub(crate) fn unpack_with(
+    archive: &Path,
+    dest: &Path,
+    limits: Limits,
+    on_file: &dyn Fn(u64, Option<u64>),
+) -> Result<(), ExtractError> {
+    let format = ArchiveFormat::sniff(archive)
+        .map_err(io_error(archive))?
+        .ok_or_else(|| ExtractError::UnknownFormat {
+            archive: archive.to_path_buf(),
+        })?;
+    let file = File::open(archive).map_err(io_error(archive))?;
+    let mut unpacker = Unpacker {
+        archive,
+        dest,
+        limits,
+        written: 0,
+    };
+    debug!("Unpacking {} as {}", archive.display(), format);
+    match format {
+        ArchiveFormat::Zip => unpacker.zip(file, on_file),
+        ArchiveFormat::TarGz => unpacker.tarball(GzDecoder::new(file), on_file),
+        ArchiveFormat::TarBz2 => unpacker.tarball(bzip2_rs::DecoderReader::new(file), on_file),
+        ArchiveFormat::TarXz => unpacker.tar_xz(file, on_file),
+        ArchiveFormat::TarZst => {
+            let decoder = StreamingDecoder::new(file).map_err(|e| unpacker.invalid(e))?;
+            unpacker.tarball(decoder, on_file)
+        }
+        ArchiveFormat::Dmg => unpacker.dmg(file, on_file),
+        ArchiveFormat::Msi => unpacker.msi(file, on_file),
+        ArchiveFormat::Exe => unpacker.exe(file, on_file),
+    }
+}
+
+/// The formats that browsers and drivers are shipped in, as told apart by
+/// the bytes in the file rather than its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+    TarBz2,
+    TarXz,
+    TarZst,
+    /// A macOS disk image.
+    Dmg,
+    /// A Windows Installer package.
+    Msi,
+    /// A Windows executable, like a setup program.
+    Exe,
+}
+
+impl ArchiveFormat {
+    /// Every format, for when a file could be in any of them.
+    pub const ALL: [ArchiveFormat; 8] = [
+        ArchiveFormat::Zip,
+        ArchiveFormat::TarGz,
+        ArchiveFormat::TarBz2,
+        ArchiveFormat::TarXz,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::Dmg,
+        ArchiveFormat::Msi,
+        ArchiveFormat::Exe,
+    ];
+
+    /// Works out the format of the file at `path` from its magic bytes, or
+    /// `None` if it isn't one we know.
+    pub fn sniff(path: &Path) -> io::Result<Option<ArchiveFormat>> {
+        let mut file = File::open(path)?;
+        let mut head = [0; 8];
+        let read = read_up_to(&mut file, &mut head)?;
+        let head = &head[..read];
+
+        // Disk images are found by the trailer in their last 512 bytes, as
+        // they can start with anything, including a bzip2 or zlib stream.
+        let len = file.metadata()?.len();
+        if len >= 512 {
+            let mut trailer = [0; 4];
+            file.seek(SeekFrom::Start(len - 512))?;
+            if read_up_to(&mut file, &mut trailer)? == 4 && &trailer == b"koly" {
+                return Ok(Some(ArchiveFormat::Dmg));
+            }
+        }
+
+        let format = if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            ArchiveFormat::Zip
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            ArchiveFormat::TarGz
+        } else if head.starts_with(b"BZh") {
+            ArchiveFormat::TarBz2
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            ArchiveFormat::TarXz
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            ArchiveFormat::TarZst
+        } else if head.starts_with(&[0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1]) {
+            ArchiveFormat::Msi
+        } else if head.starts_with(b"MZ") {
+            ArchiveFormat::Exe
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(format))
+    }
+
+    /// The file extension files of this format usually have.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarBz2 => "tar.bz2",
+            ArchiveFormat::TarXz => "tar.xz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Dmg => "dmg",
+            ArchiveFormat::Msi => "msi",
+            ArchiveFormat::Exe => "exe",
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ArchiveFormat::Dmg => "DMG disk image",
+            ArchiveFormat::Msi => "MSI installer",
+            ArchiveFormat::Exe => "Windows executable",
+            other => other.extension(),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reads until `buf` is full or the file ends, returning how much was read.
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+struct Unpacker<'a> {
+    archive: &'a Path,
+    dest: &'a Path,
+    limits: Limits,
+    written: u64,
+}
+
+impl Unpacker<'_> {
+    fn tarball<R: Read>(
+        &mut self,
+        stream: R,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ExtractError> {
+        let mut tarball = Archive::new(stream);
+        let entries = tarball.entries().map_err(self.corrupt())?;
+        for (i, entry) in entries.enumerate() {
+            self.check_count(i)?;
+            let mut entry = entry.map_err(self.corrupt())?;
+            let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let kind = entry.header().entry_type();
+            match kind {
+                EntryType::XGlobalHeader | EntryType::XHeader => continue,
+                EntryType::Directory => {
+                    let path = self.dir_target(&name)?;
+                    self.create_dir(&path)?;
+                }
+                EntryType::Regular | EntryType::Continuous => {
+                    let path = self.target(&name)?;
+                    let mode = entry.header().mode().ok();
+                    self.write_file(&path, &mut entry, mode)?;
+                }
+                EntryType::Symlink => {
+                    let path = self.target(&name)?;
+                    let target = link_name(&entry, self)?;
+                    self.symlink(&name, &path, &target)?;
+                }
+                EntryType::Link => {
+                    // Hard link targets are relative to the top of the archive.
+                    let path = self.target(&name)?;
+                    let target = link_name(&entry, self)?;
+                    let original = self.target(&target).map_err(|_| ExtractError::UnsafeLink {
+                        entry: name.clone(),
+                        target: target.clone(),
+                    })?;
+                    self.create_parent(&path)?;
+                    fs::hard_link(&original, &path).map_err(io_error(&path))?;
+                }
+                other => {
+                    return Err(ExtractError::Unsupported {
+                        entry: name,
+                        kind: describe(other),
+                    })
+                }
+            }
+            on_file(i as u64 + 1, None);
+        }
+        Ok(())
+    }
+
+    /// The xz decoder can only write its output, so the tarball is
+    /// decompressed into a file beside the archive and unpacked from there.
+    fn tar_xz(
+        &mut self,
+        file: File,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ExtractError> {
+        let mut name = self.archive.as_os_str().to_owned();
+        name.push(format!(".tar-{}", process::id()));
+        let tar = PathBuf::from(name);
+        let result = self.decompress_xz(file, &tar).and_then(|_| {
+            let tarball = File::open(&tar).map_err(io_error(&tar))?;
+            self.tarball(tarball, on_file)
+        });
+        let _ = fs::remove_file(&tar);
+        result
+    }
+
+    fn decompress_xz(&self, file: File, tar: &Path) -> Result<(), ExtractError> {
+        // Tar adds a header to each entry and pads them, so the tarball is
+        // allowed to be a bit bigger than what it unpacks to.
+        let limit = self
+            .limits
+            .max_bytes
+            .saturating_add(self.limits.max_entries as u64 * 1024 + TAR_PADDING);
+        let mut output = Limited {
+            inner: File::create(tar).map_err(io_error(tar))?,
+            remaining: limit,
+        };
+        let decompressed = lzma_rs::xz_decompress(&mut BufReader::new(file), &mut output);
+        if output.remaining == 0 {
+            return Err(ExtractError::TooLarge {
+                limit: self.limits.max_bytes,
+            });
+        }
+        decompressed.map_err(|e| self.invalid(e))
+    }
+
+    /// A disk image holds a whole volume, usually with a link to
+    /// `/Applications` beside the browser, so only `.app` bundles are
+    /// unpacked. The volume is expanded into a file beside the image first.
+    fn dmg(&mut self, file: File, on_file: &dyn Fn(u64, Option<u64>)) -> Result<(), ExtractError> {
+        let mut udif = dmg::Udif::open(file).map_err(|e| self.read_error(e))?;
+        // Volumes have some free space, so are allowed to be a bit bigger
+        // than what they unpack to.
+        if udif.size() > self.limits.max_bytes.saturating_mul(2) {
+            return Err(ExtractError::TooLarge {
+                limit: self.limits.max_bytes,
+            });
+        }
+        let mut name = self.archive.as_os_str().to_owned();
+        name.push(format!(".volume-{}", process::id()));
+        let scratch = PathBuf::from(name);
+        let result = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&scratch)
+            .map_err(io_error(&scratch))
+            .and_then(|mut disk| {
+                udif.expand(&mut disk).map_err(|e| self.read_error(e))?;
+                let volume = dmg::Volume::open(disk).map_err(|e| self.read_error(e))?;
+                self.app_bundles(volume, on_file)
+            });
+        let _ = fs::remove_file(&scratch);
+        result
+    }
+
+    fn app_bundles(
+        &mut self,
+        mut volume: dmg::Volume,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ExtractError> {
+        let entries: Vec<dmg::Entry> = volume
+            .entries()
+            .map_err(|e| self.read_error(e))?
+            .into_iter()
+            .filter(|entry| {
+                entry
+                    .path
+                    .split('/')
+                    .next()
+                    .unwrap_or_default()
+                    .ends_with(".app")
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(self.invalid("there is no .app bundle in it"));
+        }
+        let total = entries.len();
+        if total > self.limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            let path = match entry.kind {
+                dmg::Kind::Folder => self.dir_target(&entry.path)?,
+                _ => self.target(&entry.path)?,
+            };
+            match &entry.kind {
+                dmg::Kind::Folder => self.create_dir(&path)?,
+                dmg::Kind::File(fork) => {
+                    self.write_file(&path, &mut volume.open_fork(fork), entry.mode)?
+                }
+                dmg::Kind::Symlink(fork) => {
+                    let mut target = String::new();
+                    volume
+                        .open_fork(fork)
+                        .take(4096)
+                        .read_to_string(&mut target)
+                        .map_err(self.corrupt())?;
+                    self.symlink(&entry.path, &path, &target)?;
+                }
+            }
+            on_file(i as u64 + 1, Some(total as u64));
+        }
+        Ok(())
+    }
+
+    /// An MSI package keeps its files in cabinets, where they are named by
+    /// their key in the package's File table. The tables say where each one
+    /// is installed to.
+    fn msi(&mut self, file: File, on_file: &dyn Fn(u64, Option<u64>)) -> Result<(), ExtractError> {
+        let mut package = msi::Package::open(file).map_err(|e| self.read_error(e))?;
+        let paths = package.file_paths().map_err(|e| self.read_error(e))?;
+        let total = paths.len();
+        if total > self.limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        let mut done = 0;
+        for name in package.cabinets().map_err(|e| self.read_error(e))? {
+            let stream = package.open_stream(&name).map_err(|e| self.read_error(e))?;
+            let mut cabinet = cab::Cabinet::new(stream).map_err(|e| self.read_error(e))?;
+            for folder in 0..cabinet.folder_count() {
+                let files = cabinet.files_in(folder);
+                let mut data = cabinet
+                    .open_folder(folder)
+                    .map_err(|e| self.read_error(e))?;
+                for file in files {
+                    let path = paths.get(&file.name).ok_or_else(|| {
+                        self.invalid(format!("{} is not in the File table", file.name))
+                    })?;
+                    let target = self.target(path)?;
+                    let skip = file
+                        .offset
+                        .checked_sub(data.position())
+                        .ok_or_else(|| self.invalid(format!("{} overlaps another file", path)))?;
+                    io::copy(&mut (&mut data).take(skip), &mut io::sink())
+                        .map_err(|e| self.read_error(e))?;
+                    self.write_file(&target, &mut (&mut data).take(file.size), None)?;
+                    if data.position() != file.offset + file.size {
+                        return Err(self.invalid(format!("{} is truncated", path)));
+                    }
+                    done += 1;
+                    on_file(done, Some(total as u64));
+                }
+            }
+        }
+        if done == 0 {
+            return Err(self.invalid("there are no files in it"));
+        }
+        Ok(())
+    }
+
+    /// Self-extracting installers, like Firefox's full installer, are a
+    /// program with a 7z archive after it.
+    fn exe(
+        &mut self,
+        mut file: File,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ExtractError> {
+        let start = find_7z_payload(&mut file)
+            .map_err(io_error(self.archive))?
+            .ok_or_else(|| ExtractError::UnsupportedFeature {
+                archive: self.archive.to_path_buf(),
+                feature: "an installer with no 7z archive in it, like a stub installer".to_string(),
+            })?;
+        self.seven_zip(Payload { file, start }, on_file)
+    }
+
+    fn seven_zip<R: Read + Seek>(
+        &mut self,
+        mut reader: R,
+        on_file: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ExtractError> {
+        let len = reader
+            .seek(SeekFrom::End(0))
+            .and_then(|len| reader.seek(SeekFrom::Start(0)).map(|_| len))
+            .map_err(io_error(self.archive))?;
+        let mut archive =
+            SevenZReader::new(reader, len, Password::empty()).map_err(|e| self.invalid(e))?;
+        let total = archive.archive().files.len();
+        if total > self.limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        let mut failure = None;
+        let mut done = 0;
+        let read = archive.for_each_entries(|entry, contents| {
+            match self.seven_zip_entry(entry, contents) {
+                Ok(()) => {
+                    done += 1;
+                    on_file(done, Some(total as u64));
+                    Ok(true)
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    Ok(false)
+                }
+            }
+        });
+        match failure {
+            Some(e) => Err(e),
+            None => read.map_err(|e| self.invalid(e)),
+        }
+    }
+
+    fn seven_zip_entry(
+        &mut self,
+        entry: &SevenZArchiveEntry,
+        contents: &mut dyn Read,
+    ) -> Result<(), ExtractError> {
+        let path = if entry.is_directory() {
+            self.dir_target(entry.name())?
+        } else {
+            self.target(entry.name())?
+        };
+        // Archives made on Unix keep the file's mode in the high half of its
+        // attributes.
+        let attributes = entry.windows_attributes();
+        let mode = if entry.has_windows_attributes && attributes & UNIX_EXTENSION != 0 {
+            Some(attributes >> 16)
+        } else {
+            None
+        };
+        if entry.is_directory() {
+            self.create_dir(&path)
+        } else if mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            let mut target = String::new();
+            contents
+                .take(4096)
+                .read_to_string(&mut target)
+                .map_err(self.corrupt())?;
+            self.symlink(entry.name(), &path, &target)
+        } else {
+            self.write_file(&path, contents, mode)
+        }
+    }
+
+    fn zip(&mut self, file: File, on_file: &dyn Fn(u64, Option<u64>)) -> Result<(), ExtractError> {
+        let mut zip = ZipArchive::new(file).map_err(|e| self.zip_error(e))?;
+        let total = zip.len();
+        if total > self.limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        for i in 0..total {
+            let mut entry = zip.by_index(i).map_err(|e| self.zip_error(e))?;
+            let name = entry.name().to_string();
+            let path = if entry.is_dir() {
+                self.dir_target(&name)?
+            } else {
+                self.target(&name)?
+            };
+            let mode = entry.unix_mode();
+            if entry.is_dir() {
+                self.create_dir(&path)?;
+            } else if mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+                let mut target = String::new();
+                entry
+                    .by_ref()
+                    .take(4096)
+                    .read_to_string(&mut target)
+                    .map_err(self.corrupt())?;
+                self.symlink(&name, &path, &target)?;
+            } else {
+                self.write_file(&path, &mut entry, mode)?;
+            }
+            on_file(i as u64 + 1, Some(total as u64));
+        }
+        Ok(())
+    }
+
+    /// Where the entry `name` goes, or an error if that is outside `dest` or
+    /// is `dest` itself.
+    fn target(&self, name: &str) -> Result<PathBuf, ExtractError> {
+        self.resolve(name, false)
+    }
+
+    /// Where the directory entry `name` goes. Unlike other entries this can
+    /// be `dest` itself, like the `./` that `tar -C dir .` starts with.
+    fn dir_target(&self, name: &str) -> Result<PathBuf, ExtractError> {
+        self.resolve(name, true)
+    }
+
+    fn resolve(&self, name: &str, is_dir: bool) -> Result<PathBuf, ExtractError> {
+        let relative = safe_relative_path(name)
+            .filter(|relative| is_dir || !relative.as_os_str().is_empty())
+            .ok_or_else(|| ExtractError::UnsafePath {
+                entry: name.to_string(),
+            })?;
+        // Links are checked as they are made, but a later entry could still
+        // be written through one, so nothing between `dest` and the entry,
+        // nor the entry itself, may be a link.
+        let mut path = self.dest.to_path_buf();
+        for component in relative.components() {
+            path.push(component);
+            if is_link(&path) {
+                return Err(ExtractError::UnsafePath {
+                    entry: name.to_string(),
+                });
+            }
+        }
+        Ok(path)
+    }
+
+    fn check_count(&self, index: usize) -> Result<(), ExtractError> {
+        if index >= self.limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), ExtractError> {
+        debug!("Creating {}", path.display());
+        fs::create_dir_all(path).map_err(io_error(path))
+    }
+
+    fn create_parent(&self, path: &Path) -> Result<(), ExtractError> {
+        match path.parent() {
+            Some(parent) => self.create_dir(parent),
+            None => Ok(()),
+        }
+    }
+
+    /// Copies an entry to `path`, counting what is actually written rather
+    /// than trusting the size the archive claims.
+    fn write_file(
+        &mut self,
+        path: &Path,
+        contents: &mut dyn Read,
+        mode: Option<u32>,
+    ) -> Result<(), ExtractError> {
+        self.create_parent(path)?;
+        let mut file = File::create(path).map_err(io_error(path))?;
+        let allowed = self.limits.max_bytes - self.written;
+        let copied =
+            io::copy(&mut contents.take(allowed + 1), &mut file).map_err(self.corrupt())?;
+        if copied > allowed {
+            drop(file);
+            let _ = fs::remove_file(path);
+            return Err(ExtractError::TooLarge {
+                limit: self.limits.max_bytes,
+            });
+        }
+        self.written += copied;
+        file.flush().map_err(io_error(path))?;
+        debug!("Unpacked {} ({} bytes)", path.display(), copied);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // Only the permission bits are kept, so setuid and setgid bits
+            // in an archive are ignored.
+            if let Some(mode) = mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+                    .map_err(io_error(path))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, path: &Path, target: &str) -> Result<(), ExtractError> {
+        if !link_stays_inside(name, target) || self.passes_through_link(name, target) {
+            return Err(ExtractError::UnsafeLink {
+                entry: name.to_string(),
+                target: target.to_string(),
+            });
+        }
+        self.create_parent(path)?;
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(target, path).map_err(io_error(path))
+        }
+        #[cfg(not(unix))]
+        {
+            Err(ExtractError::Unsupported {
+                entry: name.to_string(),
+                kind: "symlink".to_string(),
+            })
+        }
+    }
+
+    /// Whether the link `name` pointing to `target` goes through a link that
+    /// was already unpacked on the way. `link_stays_inside` only looks at the
+    /// names, so `x/..` looks like it stays put even when `x` is a link to
+    /// somewhere else.
+    fn passes_through_link(&self, name: &str, target: &str) -> bool {
+        let mut path = safe_relative_path(name)
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+        let target = target.replace('\\', "/");
+        let components: Vec<Component> = Path::new(&target).components().collect();
+        for (i, component) in components.iter().enumerate() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::ParentDir => {
+                    path.pop();
+                    continue;
+                }
+                _ => continue,
+            }
+            // The link may point at another link, just not through one
+            if i + 1 < components.len() && is_link(&self.dest.join(&path)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn corrupt(&self) -> impl Fn(io::Error) -> ExtractError + '_ {
+        move |e| self.invalid(e)
+    }
+
+    fn invalid(&self, reason: impl fmt::Display) -> ExtractError {
+        ExtractError::Corrupt {
+            archive: self.archive.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Maps errors from the disk image, MSI and cabinet readers, which use
+    /// `InvalidData` for broken files and `Unsupported` for features they
+    /// don't read.
+    fn read_error(&self, error: io::Error) -> ExtractError {
+        match error.kind() {
+            io::ErrorKind::InvalidData => self.invalid(error),
+            io::ErrorKind::Unsupported => ExtractError::UnsupportedFeature {
+                archive: self.archive.to_path_buf(),
+                feature: error.to_string(),
+            },
+            _ => ExtractError::Io {
+                path: self.archive.to_path_buf(),
+                source: error,
+            },
+        }
+    }
+
+    fn zip_error(&self, error: ZipError) -> ExtractError {
+        match error {
+            ZipError::Io(source) => ExtractError::Io {
+                path: self.archive.to_path_buf(),
+                source,
+            },
+            other => ExtractError::Corrupt {
+                archive: self.archive.to_path_buf(),
+                reason: other.to_string(),
+            },
+        }
+    }
+}
+
+/// The file seen from `start` on, which is how the 7z archive at the end of
+/// a self-extracting installer is read.
+struct Payload {
+    file: File,
+    start: u64,
+}
+
+impl Read for Payload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for Payload {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(self.start + offset),
+            other => other,
+        };
+        let at = self.file.seek(pos)?;
+        at.checked_sub(self.start)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the archive"))
+    }
+}
+
+/// Finds where the 7z archive in a self-extracting installer starts. The
+/// signature could turn up by chance in the program, so the checksum of
+/// the header after it has to match too.
+pub(crate) fn find_7z_payload(file: &mut File) -> io::Result<Option<u64>> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let read = read_up_to(file, &mut buf)?;
+        if read < SEVEN_ZIP_HEADER {
+            return Ok(None);
+        }
+        for at in 0..=read - SEVEN_ZIP_HEADER {
+            let header = &buf[at..at + SEVEN_ZIP_HEADER];
+            if header.starts_with(&SEVEN_ZIP_MAGIC) {
+                let mut crc = Crc::new();
+                crc.update(&header[12..]);
+                if crc.sum().to_le_bytes() == header[8..12] {
+                    return Ok(Some(offset + at as u64));
+                }
+            }
+        }
+        offset += (read - SEVEN_ZIP_HEADER + 1) as u64;
+    }
+}
+
+/// A writer that fails once `remaining` bytes have been written to it.
+struct Limited<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> Write for Limited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 >= self.remaining {
+            self.remaining = 0;
+            return Err(io::Error::other("archive is too large"));
+        }
+        let written = self.inner.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn io_error(path: &Path) -> impl Fn(io::Error) -> ExtractError + '_ {
+    move |source| ExtractError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn link_name<R: Read>(entry: &tar::Entry<R>, unpacker: &Unpacker) -> Result<String, ExtractError> {
+    match entry.link_name_bytes() {
+        Some(target) => Ok(String::from_utf8_lossy(&target).into_owned()),
+        None => Err(ExtractError::Corrupt {
+            archive: unpacker.archive.to_path_buf(),
+            reason: "a link has no target".to_string(),
+        }),
+    }
+}
+
+fn describe(kind: EntryType) -> String {
+    match kind {
+        EntryType::Char => "character device".to_string(),
+        EntryType::Block => "block device".to_string(),
+        EntryType::Fifo => "fifo".to_string(),
+        other => format!("{:?} entry", other).to_lowercase(),
+    }
+}
+
+/// Turns an entry name into a relative path, or `None` if it is absolute or
+/// climbs out with `..`. Backslashes are treated as separators, as archives
+/// made on Windows sometimes use them. Names like `./`, which archives made
+/// with `tar -C dir .` start with, are the top of the archive and so an empty
+/// path, which only a directory entry may have.
+fn safe_relative_path(name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for (i, component) in Path::new(&name).components().enumerate() {
+        match component {
+            Component::Normal(part) => {
+                // A drive letter, like `C:`, makes the path absolute on Windows.
+                if i == 0 && part.to_string_lossy().contains(':') {
+                    return None;
+                }
+                path.push(part);
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+fn is_link(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+/// Whether a symlink called `name` pointing to `target` resolves to
+/// somewhere inside the directory being unpacked into.
+fn link_stays_inside(name: &str, target: &str) -> bool {
+    let target = target.replace('\\', "/");
+    if target.is_empty() || target.starts_with('/') {
+        return false;
+    }
+    // Links are relative to the directory they are in.
+    let mut depth = safe_relative_path(name)
+        .map(|path| path.components().count() as i64 - 1)
+        .unwrap_or(-1);
+    for (i, component) in Path::new(&target).components().enumerate() {
+        match component {
+            Component::Normal(part) => {
+                if i == 0 && part.to_string_lossy().contains(':') {
+                    return false;
+                }
+                depth += 1;
+            }
+            Component::CurDir => {}
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    depth >= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cab::tests::cabinet as cab_fixture;
+    use crate::dmg::tests::{hfs_volume, udif, udif_named, Item};
+    use crate::msi::tests::package as msi_fixture;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+
+    /// Builds a tarball with raw headers, so that entries the `tar` crate
+    /// would refuse to write can still be made.
+    fn tarball(entries: &[(&str, EntryType, &[u8], Option<&str>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        ));
+        for (name, kind, data, link) in entries {
+            let mut header = tar::Header::new_old();
+            {
+                let raw = header.as_old_mut();
+                raw.name[..name.len()].copy_from_slice(name.as_bytes());
+                if let Some(link) = link {
+                    raw.linkname[..link.len()].copy_from_slice(link.as_bytes());
+                }
+            }
+            header.set_entry_type(*kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn zip_of(entries: &[(&str, &[u8], Option<u32>)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data, mode) in entries {
+            let mut options = FileOptions::default();
+            if let Some(mode) = mode {
+                options = options.unix_permissions(*mode);
+            }
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A zip holding one symlink. The `zip` crate can only write permission
+    /// bits, so the file type is patched into the central directory.
+    fn zip_symlink(name: &str, target: &str) -> Vec<u8> {
+        let mut archive = zip_of(&[(name, target.as_bytes(), Some(0o777))]);
+        let central = archive
+            .windows(4)
+            .position(|window| window == b"PK\x01\x02")
+            .unwrap();
+        let attributes = (S_IFLNK | 0o777) << 16;
+        archive[central + 38..central + 42].copy_from_slice(&attributes.to_le_bytes());
+        archive
+    }
+
+    /// Unpacks `archive` into a fresh directory, which sits inside another
+    /// one so that anything escaping it can be spotted.
+    fn unpack_bytes(
+        name: &str,
+        archive: &[u8],
+        limits: Limits,
+    ) -> (TempDir, Result<(), ExtractError>) {
+        let outer = TempDir::new().unwrap();
+        let path = outer.path().join(name);
+        fs::write(&path, archive).unwrap();
+        let dest = outer.path().join("dest");
+        fs::create_dir(&dest).unwrap();
+        let result = unpack_with(&path, &dest, limits, &|_, _| {});
+        (outer, result)
+    }
+
+    fn escaped(outer: &TempDir) -> bool {
+        outer.path().join("evil").exists() || outer.path().join("dest").join("evil").exists()
+    }
+
+    #[test]
+    fn ordinary_archives_unpack() {
+        let archive = tarball(&[
+            ("bin/", EntryType::Directory, b"", None),
+            ("bin/geckodriver", EntryType::Regular, b"driver", None),
+            ("current", EntryType::Symlink, b"", Some("bin")),
+        ]);
+        let (outer, result) = unpack_bytes("driver.tar.gz", &archive, Limits::default());
+        result.unwrap();
+        let dest = outer.path().join("dest");
+        assert_eq!(fs::read(dest.join("bin/geckodriver")).unwrap(), b"driver");
+
+        // Like `tar -C dir .` makes
+        let archive = tarball(&[
+            ("./", EntryType::Directory, b"", None),
+            ("./geckodriver", EntryType::Regular, b"driver", None),
+        ]);
+        let (outer, result) = unpack_bytes("driver.tar.gz", &archive, Limits::default());
+        result.unwrap();
+        assert_eq!(
+            fs::read(outer.path().join("dest/geckodriver")).unwrap(),
+            b"driver"
+        );
+
+        let archive = zip_of(&[("./", b"", None), ("./chromedriver", b"driver", None)]);
+        let (outer, result) = unpack_bytes("driver.zip", &archive, Limits::default());
+        result.unwrap();
+        assert!(outer.path().join("dest/chromedriver").exists());
+
+        let archive = zip_of(&[("chromedriver", b"driver", Some(0o755))]);
+        let (outer, result) = unpack_bytes("driver.zip", &archive, Limits::default());
+        result.unwrap();
+        assert_eq!(
+            fs::read(outer.path().join("dest/chromedriver")).unwrap(),
+            b"driver"
+        );
+    }
+
+    #[test]
+    fn paths_outside_the_target_are_refused() {
+        for name in &[
+            "../evil",
+            "bin/../../evil",
+            "/tmp/evil",
+            "..\\evil",
+            "C:/evil",
+        ] {
+            let archive = tarball(&[(name, EntryType::Regular, b"evil", None)]);
+            let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+            match result {
+                Err(ExtractError::UnsafePath { entry }) => assert_eq!(&entry, name),
+                other => panic!("{} was not refused: {:?}", name, other),
+            }
+            assert!(!escaped(&outer));
+
+            let archive = zip_of(&[(name, b"evil", None)]);
+            let (outer, result) = unpack_bytes("evil.zip", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::UnsafePath { .. })),
+                "{} was not refused: {:?}",
+                name,
+                result
+            );
+            assert!(!escaped(&outer));
+        }
+    }
+
+    #[test]
+    fn links_outside_the_target_are_refused() {
+        for target in &["..", "../evil", "bin/../../evil", "/etc/passwd"] {
+            let archive = tarball(&[("link", EntryType::Symlink, b"", Some(target))]);
+            let (_outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::UnsafeLink { .. })),
+                "{} was not refused: {:?}",
+                target,
+                result
+            );
+
+            let archive = tarball(&[("link", EntryType::Link, b"", Some(target))]);
+            let (_outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::UnsafeLink { .. })),
+                "{} was not refused: {:?}",
+                target,
+                result
+            );
+
+            let archive = zip_symlink("link", target);
+            let (_outer, result) = unpack_bytes("evil.zip", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::UnsafeLink { .. })),
+                "{} was not refused: {:?}",
+                target,
+                result
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn entries_are_not_written_through_links() {
+        // The link itself stays inside, but it could be swapped for one that
+        // doesn't, so writing through it is still refused.
+        let archive = tarball(&[
+            ("bin/", EntryType::Directory, b"", None),
+            ("link", EntryType::Symlink, b"", Some("bin")),
+            ("link/evil", EntryType::Regular, b"evil", None),
+        ]);
+        let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnsafePath { .. })));
+        assert!(!outer.path().join("dest/bin/evil").exists());
+    }
+
+    #[test]
+    fn only_directories_can_be_the_top_of_the_archive() {
+        for name in &["./", ".", ""] {
+            let archive = tarball(&[(name, EntryType::Regular, b"evil", None)]);
+            let (_outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::UnsafePath { .. })),
+                "{:?} was not refused: {:?}",
+                name,
+                result
+            );
+        }
+
+        let archive = tarball(&[("./", EntryType::Symlink, b"", Some("bin"))]);
+        let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnsafePath { .. })));
+        assert!(outer.path().join("dest").is_dir());
+
+        // A hard link to the top of the archive would be a link to `dest`
+        let archive = tarball(&[("link", EntryType::Link, b"", Some("./"))]);
+        let (_outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnsafeLink { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_are_not_chained_to_climb_out() {
+        // `y` looks like it stays inside from its name alone, but `x/..` is
+        // the directory above `dest`
+        let archive = tarball(&[
+            ("x", EntryType::Symlink, b"", Some(".")),
+            ("y", EntryType::Symlink, b"", Some("x/../evil")),
+            ("y", EntryType::Regular, b"evil", None),
+        ]);
+        let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+        assert!(
+            matches!(result, Err(ExtractError::UnsafeLink { .. })),
+            "{:?}",
+            result
+        );
+        assert!(!escaped(&outer));
+
+        // The same links the other way round, so `x` is only a link once
+        // `y` has been made
+        let archive = tarball(&[
+            ("y", EntryType::Symlink, b"", Some("x/../evil")),
+            ("x", EntryType::Symlink, b"", Some(".")),
+            ("y", EntryType::Regular, b"evil", None),
+        ]);
+        let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+        assert!(
+            matches!(result, Err(ExtractError::UnsafePath { .. })),
+            "{:?}",
+            result
+        );
+        assert!(!escaped(&outer));
+    }
+
+    #[test]
+    fn device_files_are_refused() {
+        for kind in &[EntryType::Char, EntryType::Block, EntryType::Fifo] {
+            let archive = tarball(&[("evil", *kind, b"", None)]);
+            let (outer, result) = unpack_bytes("evil.tar.gz", &archive, Limits::default());
+            assert!(
+                matches!(result, Err(ExtractError::Unsupported { .. })),
+                "{:?} was not refused: {:?}",
+                kind,
+                result
+            );
+            assert!(!escaped(&outer));
+        }
+    }
+
+    #[test]
+    fn decompression_bombs_are_refused() {
+        let limits = Limits {
+            max_bytes: 1024,
+            max_entries: 10,
+        };
+        let zeros = vec![0; 1025];
+        let archive = zip_of(&[("evil", &zeros, None)]);
+        let (outer, result) = unpack_bytes("evil.zip", &archive, limits);
+        assert!(matches!(
+            result,
+            Err(ExtractError::TooLarge { limit: 1024 })
+        ));
+        assert!(!escaped(&outer));
+
+        // The limit is for the whole archive, not each entry.
+        let half = vec![0; 600];
+        let archive = tarball(&[
+            ("one", EntryType::Regular, &half, None),
+            ("two", EntryType::Regular, &half, None),
+        ]);
+        let (_outer, result) = unpack_bytes("evil.tar.gz", &archive, limits);
+        assert!(matches!(result, Err(ExtractError::TooLarge { .. })));
+
+        let names: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+        let entries: Vec<(&str, &[u8], Option<u32>)> = names
+            .iter()
+            .map(|name| (name.as_str(), &b""[..], None))
+            .collect();
+        let (_outer, result) = unpack_bytes("evil.zip", &zip_of(&entries), limits);
+        assert!(matches!(
+            result,
+            Err(ExtractError::TooManyEntries { limit: 10 })
+        ));
+    }
+
+    #[test]
+    fn corrupt_archives_are_errors() {
+        let (_outer, result) =
+            unpack_bytes("driver.zip", b"PK\x03\x04 truncated", Limits::default());
+        assert!(matches!(result, Err(ExtractError::Corrupt { .. })));
+        let (_outer, result) =
+            unpack_bytes("driver.tar.gz", b"\x1f\x8b truncated", Limits::default());
+        assert!(matches!(result, Err(ExtractError::Corrupt { .. })));
+    }
+
+    /// An uncompressed tarball holding a `geckodriver`.
+    fn driver_tar() -> Vec<u8> {
+        let gzipped = tarball(&[("geckodriver", EntryType::Regular, b"driver", None)]);
+        let mut tar = vec![];
+        GzDecoder::new(&gzipped[..]).read_to_end(&mut tar).unwrap();
+        tar
+    }
+
+    /// A zstd frame that stores `data` in raw blocks, as there is no zstd
+    /// encoder to hand.
+    fn zstd_raw(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd];
+        // Single segment, with a four byte content size.
+        frame.push(0xa0);
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let chunks: Vec<&[u8]> = data.chunks(128 * 1024).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let last = (i == chunks.len() - 1) as u32;
+            let header = (chunk.len() as u32) << 3 | last;
+            frame.extend_from_slice(&header.to_le_bytes()[..3]);
+            frame.extend_from_slice(chunk);
+        }
+        frame
+    }
+
+    /// `driver_tar` compressed with `bzip2 -9`.
+    const DRIVER_TAR_BZ2: &[u8] = b"\
+\x42\x5a\x68\x39\x31\x41\x59\x26\x53\x59\x5a\x52\xc9\x87\x00\x00\x6f\xfb\x80\xc9\
+\x80\x00\x04\x40\x00\x6b\x80\x02\x00\x6e\xa8\x9f\x00\x04\x08\x20\x00\x54\x34\xa3\
+\x4d\x30\x81\x80\x11\xfa\xa0\x92\x8d\x41\xe8\x8d\x00\x00\x7d\xcb\xbb\x21\x03\x54\
+\x84\x22\x77\x5a\x5f\x39\x58\x81\x10\x08\x49\xe3\x16\x49\x60\xc8\xa3\x0f\xd5\xfd\
+\xf5\xba\x66\x98\x37\x22\x90\x17\xf5\xa3\x19\x04\xfc\x44\x11\x10\x0f\xc5\xdc\x91\
+\x4e\x14\x24\x16\x94\xb2\x61\xc0";
+
+    fn sniff_bytes(bytes: &[u8]) -> Option<ArchiveFormat> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("download");
+        fs::write(&path, bytes).unwrap();
+        ArchiveFormat::sniff(&path).unwrap()
+    }
+
+    fn dmg() -> Vec<u8> {
+        let mut image = b"BZh91AY&SY".to_vec();
+        image.resize(4096, 0);
+        image.extend_from_slice(b"koly");
+        image.resize(4096 + 512, 0);
+        image
+    }
+
+    #[test]
+    fn formats_are_sniffed_from_their_contents() {
+        let mut xz = vec![];
+        lzma_rs::xz_compress(&mut &driver_tar()[..], &mut xz).unwrap();
+        let cases: Vec<(Vec<u8>, ArchiveFormat)> = vec![
+            (zip_of(&[("a", b"a", None)]), ArchiveFormat::Zip),
+            (tarball(&[]), ArchiveFormat::TarGz),
+            (DRIVER_TAR_BZ2.to_vec(), ArchiveFormat::TarBz2),
+            (xz, ArchiveFormat::TarXz),
+            (zstd_raw(b"tar"), ArchiveFormat::TarZst),
+            (dmg(), ArchiveFormat::Dmg),
+            (
+                vec![0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1, 0, 0],
+                ArchiveFormat::Msi,
+            ),
+            (b"MZ\x90\x00".to_vec(), ArchiveFormat::Exe),
+        ];
+        for (bytes, format) in cases {
+            assert_eq!(sniff_bytes(&bytes), Some(format));
+        }
+        assert_eq!(sniff_bytes(b"<html>Not found</html>"), None);
+        assert_eq!(sniff_bytes(b""), None);
+    }
+
+    #[test]
+    fn compressed_tarballs_unpack_whatever_they_are_called() {
+        let mut xz = vec![];
+        lzma_rs::xz_compress(&mut &driver_tar()[..], &mut xz).unwrap();
+        let archives = vec![
+            tarball(&[("geckodriver", EntryType::Regular, b"driver", None)]),
+            DRIVER_TAR_BZ2.to_vec(),
+            xz,
+            zstd_raw(&driver_tar()),
+        ];
+        for archive in archives {
+            let (outer, result) = unpack_bytes("driver.zip", &archive, Limits::default());
+            result.unwrap();
+            assert_eq!(
+                fs::read(outer.path().join("dest/geckodriver")).unwrap(),
+                b"driver"
+            );
+            // Nothing but the unpacked driver is left behind.
+            assert_eq!(fs::read_dir(outer.path()).unwrap().count(), 2);
+        }
+    }
+
+    #[test]
+    fn xz_bombs_are_refused_before_they_fill_the_disk() {
+        let mut tar = driver_tar();
+        tar.resize(1024 * 1024, 0);
+        let mut xz = vec![];
+        lzma_rs::xz_compress(&mut &tar[..], &mut xz).unwrap();
+        let limits = Limits {
+            max_bytes: 1024,
+            max_entries: 10,
+        };
+        let (outer, result) = unpack_bytes("driver.tar.xz", &xz, limits);
+        assert!(matches!(result, Err(ExtractError::TooLarge { .. })));
+        assert_eq!(fs::read_dir(outer.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn installers_without_files_in_them_are_errors() {
+        let msi = b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1";
+        let (_outer, result) = unpack_bytes("chrome_browser.zip", msi, Limits::default());
+        assert!(result.is_err());
+        let (_outer, result) = unpack_bytes("firefox_browser", b"MZ\x90\x00", Limits::default());
+        match result {
+            Err(error @ ExtractError::UnsupportedFeature { .. }) => {
+                assert!(error.to_string().contains("stub installer"), "{}", error)
+            }
+            other => panic!("stub installer was unpacked: {:?}", other),
+        }
+        let (_outer, result) = unpack_bytes("driver.zip", b"<html>", Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnknownFormat { .. })));
+    }
+
+    #[test]
+    fn msi_packages_unpack_to_their_install_directory() {
+        let chrome: Vec<u8> = (0..70_000u32).map(|i| (i % 17) as u8).collect();
+        let cabinet = cab_fixture(&[("chrome.exe.1", &chrome), ("VERSION", b"90.0")], true);
+        let msi = msi_fixture(
+            &[
+                ("TARGETDIR", None, "SourceDir"),
+                ("ProgramFilesFolder", Some("TARGETDIR"), "."),
+                ("Vendor", Some("ProgramFilesFolder"), "Google"),
+                ("INSTALLDIR", Some("Vendor"), "CHROME~1|Chrome"),
+            ],
+            &[
+                ("chrome.exe.1", "INSTALLDIR", "chrome.exe"),
+                ("VERSION", "INSTALLDIR", "VERSION"),
+            ],
+            &cabinet,
+        );
+        let (outer, result) = unpack_bytes("chrome_browser", &msi, Limits::default());
+        result.unwrap();
+        let chrome_dir = outer.path().join("dest").join("Google").join("Chrome");
+        assert_eq!(fs::read(chrome_dir.join("chrome.exe")).unwrap(), chrome);
+        assert_eq!(fs::read(chrome_dir.join("VERSION")).unwrap(), b"90.0");
+
+        let escaping = msi_fixture(
+            &[
+                ("TARGETDIR", None, "SourceDir"),
+                ("Up", Some("TARGETDIR"), ".."),
+            ],
+            &[("evil", "Up", "evil")],
+            &cab_fixture(&[("evil", b"evil")], false),
+        );
+        let (outer, result) = unpack_bytes("chrome_browser", &escaping, Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnsafePath { .. })));
+        assert!(!escaped(&outer));
+    }
+
+    fn seven_zip(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+        let mut writer = sevenz_rust::SevenZWriter::new(Cursor::new(vec![])).unwrap();
+        for (name, contents) in entries {
+            let mut entry = SevenZArchiveEntry::new();
+            entry.name = name.to_string();
+            entry.is_directory = contents.is_none();
+            entry.has_stream = contents.is_some_and(|contents| !contents.is_empty());
+            writer.push_archive_entry(entry, *contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn self_extracting_installers_unpack_their_payload() {
+        let firefox: Vec<u8> = (0..50_000u32).map(|i| (i % 19) as u8).collect();
+        let mut installer = b"MZ\x90\x00".to_vec();
+        installer.resize(70_000, 0x90);
+        // The signature without a valid header after it, as could be in the
+        // program by chance.
+        installer.extend_from_slice(&SEVEN_ZIP_MAGIC);
+        installer.resize(140_000, 0);
+        installer.extend(seven_zip(&[
+            ("core", None),
+            ("core/firefox.exe", Some(&firefox)),
+            ("core/defaults", None),
+            ("setup.exe", Some(b"setup")),
+        ]));
+        let (outer, result) = unpack_bytes("firefox_browser", &installer, Limits::default());
+        result.unwrap();
+        let dest = outer.path().join("dest");
+        assert_eq!(
+            fs::read(dest.join("core").join("firefox.exe")).unwrap(),
+            firefox
+        );
+        assert!(dest.join("core").join("defaults").is_dir());
+        assert_eq!(fs::read(dest.join("setup.exe")).unwrap(), b"setup");
+
+        let mut escaping = b"MZ\x90\x00".to_vec();
+        escaping.extend(seven_zip(&[("../evil", Some(b"evil"))]));
+        let (outer, result) = unpack_bytes("firefox_browser", &escaping, Limits::default());
+        assert!(matches!(result, Err(ExtractError::UnsafePath { .. })));
+        assert!(!escaped(&outer));
+    }
+
+    #[test]
+    fn app_bundles_are_unpacked_from_disk_images() {
+        let firefox: Vec<u8> = (0..50_000u32).map(|i| (i % 7) as u8).collect();
+        let libxul: Vec<u8> = (0..12 * 512u32).map(|i| (i % 11) as u8).collect();
+        let image = udif(&hfs_volume(&[
+            Item::Folder("Firefox.app"),
+            Item::Folder("Firefox.app/Contents"),
+            Item::File("Firefox.app/Contents/Info.plist", b"<plist/>", 0o644),
+            Item::Folder("Firefox.app/Contents/MacOS"),
+            Item::File("Firefox.app/Contents/MacOS/firefox", &firefox, 0o755),
+            Item::Fragmented("Firefox.app/Contents/MacOS/XUL", &libxul),
+            Item::HardLink("Firefox.app/Contents/MacOS/plugin-container", b"helper"),
+            Item::Symlink("Firefox.app/Contents/Current", "MacOS"),
+            Item::Symlink("Applications", "/Applications"),
+            Item::File(".DS_Store", b"Bud1", 0o644),
+        ]));
+        let (outer, result) = unpack_bytes("firefox_browser", &image, Limits::default());
+        result.unwrap();
+        let dest = outer.path().join("dest");
+        let macos = dest.join("Firefox.app/Contents/MacOS");
+        assert_eq!(fs::read(macos.join("firefox")).unwrap(), firefox);
+        assert_eq!(fs::read(macos.join("XUL")).unwrap(), libxul);
+        assert_eq!(fs::read(macos.join("plugin-container")).unwrap(), b"helper");
+        assert_eq!(
+            fs::read(dest.join("Firefox.app/Contents/Info.plist")).unwrap(),
+            b"<plist/>"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(macos.join("firefox"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o755);
+            assert_eq!(
+                fs::read_link(dest.join("Firefox.app/Contents/Current")).unwrap(),
+                Path::new("MacOS")
+            );
+        }
+        assert!(!dest.join("Applications").exists());
+        assert!(!dest.join(".DS_Store").exists());
+        let leftovers: Vec<_> = fs::read_dir(outer.path()).unwrap().collect();
+        assert_eq!(leftovers.len(), 2, "scratch volume was left behind");
+    }
+
+    #[test]
+    fn disk_images_without_an_app_are_errors() {
+        let image = udif(&hfs_volume(&[Item::File("README", b"hello", 0o644)]));
+        let (_outer, result) = unpack_bytes("chrome_browser", &image, Limits::default());
+        assert!(matches!(result, Err(ExtractError::Corrupt { .. })));
+
+        let apfs = udif_named(&hfs_volume(&[]), "disk image (Apple_APFS : 1)");
+        let (_outer, result) = unpack_bytes("chrome_browser", &apfs, Limits::default());
+        match result {
+            Err(error @ ExtractError::UnsupportedFeature { .. }) => {
+                assert!(error.to_string().contains("APFS"), "{}", error)
+            }
+            other => panic!("APFS image was unpacked: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn safe_paths_are_relative() {
+        assert_eq!(
+            safe_relative_path("./bin/driver"),
+            Some(PathBuf::from("bin/driver"))
+        );
+        assert_eq!(safe_relative_path("./"), Some(PathBuf::new()));
+        assert_eq!(safe_relative_path("."), Some(PathBuf::new()));
+        assert!(link_stays_inside("a/b/link", "../c"));
+        assert!(link_stays_inside("a/link", "b/../c"));
+        assert!(!link_stays_inside("a/link", "../../c"));
+    }
+}
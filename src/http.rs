@@ -0,0 +1,1007 @@
+use crate::options::Options;
+
+use log::warn;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{
+    HeaderMap, AUTHORIZATION, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::{Certificate, Identity, NoProxy, Proxy, StatusCode, Url};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The longest we wait between attempts, even when a server asks for longer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Errors from talking to download servers. Each one carries enough detail,
+/// including the underlying cause, to tell a proxy or certificate problem
+/// apart from a missing file.
+#[derive(Debug)]
+pub enum HttpError {
+    /// The client couldn't be set up from the options, like an invalid proxy
+    /// URL or an unreadable CA bundle.
+    Config(String),
+    /// The request couldn't be sent or the response couldn't be read.
+    Request { url: String, source: reqwest::Error },
+    /// The server answered with an error status.
+    Status { url: String, status: u16 },
+    /// GitHub won't answer any more requests until `reset`. Requests with a
+    /// `github_token` are allowed many more than anonymous ones.
+    RateLimited {
+        url: String,
+        /// How many requests are left, which is usually none.
+        remaining: u64,
+        /// How many requests are allowed each hour.
+        limit: Option<u64>,
+        /// When the requests allowed are topped up again.
+        reset: Option<SystemTime>,
+    },
+    /// The connection dropped while a download was being streamed to disk.
+    Interrupted { url: String, source: io::Error },
+    /// A download couldn't be written to disk.
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Config(message) => write!(f, "{}", message),
+            HttpError::Request { url, source } => {
+                // reqwest keeps the useful part, like a certificate failure,
+                // further down the chain so print all of it. Some layers
+                // already repeat their cause so those are skipped.
+                write!(f, "Request to {} failed", url)?;
+                write_causes(f, source)
+            }
+            HttpError::Status { url, status } => {
+                write!(f, "Request to {} failed with status {}", url, status)
+            }
+            HttpError::RateLimited {
+                url,
+                remaining,
+                limit,
+                reset,
+            } => {
+                write!(f, "Request to {} was rate limited, {} ", url, remaining)?;
+                if let Some(limit) = limit {
+                    write!(f, "of {} ", limit)?;
+                }
+                write!(f, "requests are left")?;
+                if let Some(reset) = reset {
+                    let wait = reset.duration_since(SystemTime::now()).unwrap_or_default();
+                    write!(
+                        f,
+                        " until {} (in {} minutes)",
+                        httpdate::fmt_http_date(*reset),
+                        wait.as_secs().div_ceil(60)
+                    )?;
+                }
+                write!(f, ". Set GITHUB_TOKEN or --github-token for a higher limit")
+            }
+            HttpError::Interrupted { url, source } => {
+                write!(f, "Download from {} was interrupted", url)?;
+                write_causes(f, source)
+            }
+            HttpError::Io { path, source } => {
+                write!(f, "Could not write {}: {}", path.display(), source)
+            }
+        }
+    }
+}
+
+fn write_causes(f: &mut fmt::Formatter, error: &dyn Error) -> fmt::Result {
+    let mut previous = String::new();
+    let mut cause = Some(error);
+    while let Some(err) = cause {
+        let message = err.to_string();
+        if !previous.contains(&message) {
+            write!(f, ": {}", message)?;
+        }
+        previous = message;
+        cause = err.source();
+    }
+    Ok(())
+}
+
+impl HttpError {
+    /// Whether trying again might work, like after a dropped connection or
+    /// while a server is overloaded.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            // The limit is usually topped up hourly, which is too long to wait
+            HttpError::Config(_) | HttpError::Io { .. } | HttpError::RateLimited { .. } => false,
+            HttpError::Interrupted { .. } => true,
+            HttpError::Request { source, .. } => {
+                source.is_connect()
+                    || source.is_timeout()
+                    || source.is_request()
+                    || source.is_body()
+            }
+            HttpError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS.as_u16() || *status >= 500
+            }
+        }
+    }
+}
+
+impl Error for HttpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpError::Request { source, .. } => Some(source),
+            HttpError::Interrupted { source, .. } | HttpError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies us to servers. GitHub rejects API requests without one.
+pub(crate) const USER_AGENT: &str = concat!("browser-manager/", env!("CARGO_PKG_VERSION"));
+
+/// What every client is built with, whether blocking or async.
+pub(crate) struct ClientSettings {
+    pub(crate) proxy: Option<Proxy>,
+    pub(crate) certificates: Vec<Certificate>,
+    pub(crate) identity: Option<Identity>,
+}
+
+/// Reads the proxy and TLS settings from `options`. Unless a proxy is
+/// configured the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environmental
+/// variables are honoured.
+pub(crate) fn client_settings(options: &Options) -> Result<ClientSettings, HttpError> {
+    let mut settings = ClientSettings {
+        proxy: None,
+        certificates: vec![],
+        identity: None,
+    };
+
+    if let Some(proxy_url) = &options.proxy {
+        let mut proxy = Proxy::all(proxy_url.as_str())
+            .map_err(|e| HttpError::Config(format!("Invalid proxy `{}`: {}", proxy_url, e)))?;
+        if let Some(user) = &options.proxy_user {
+            let password = options.proxy_password.as_deref().unwrap_or("");
+            proxy = proxy.basic_auth(user, password);
+        }
+        let no_proxy = match &options.no_proxy {
+            Some(list) => NoProxy::from_string(list),
+            None => NoProxy::from_env(),
+        };
+        settings.proxy = Some(proxy.no_proxy(no_proxy));
+    }
+
+    if let Some(bundle) = &options.ca_bundle {
+        let pem = read(bundle, "CA bundle")?;
+        settings.certificates = Certificate::from_pem_bundle(&pem).map_err(|e| {
+            HttpError::Config(format!("Invalid CA bundle {}: {}", bundle.display(), e))
+        })?;
+    }
+
+    if let Some(cert) = &options.client_cert {
+        let cert_data = read(cert, "client certificate")?;
+        let identity = match &options.client_key {
+            Some(key) => Identity::from_pkcs8_pem(&cert_data, &read(key, "client key")?),
+            None => Identity::from_pkcs12_der(
+                &cert_data,
+                options.client_cert_password.as_deref().unwrap_or(""),
+            ),
+        }
+        .map_err(|e| {
+            HttpError::Config(format!(
+                "Invalid client certificate {}: {}",
+                cert.display(),
+                e
+            ))
+        })?;
+        settings.identity = Some(identity);
+    }
+
+    Ok(settings)
+}
+
+/// The `Authorization` header to send to `url`. The token is only ever sent
+/// to GitHub, so mirrors and the other download servers never see it.
+pub(crate) fn github_auth(token: &Option<String>, url: &str) -> Option<String> {
+    let token = token.as_ref()?;
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.to_lowercase();
+    if url.scheme() == "https" && (host == "github.com" || host.ends_with(".github.com")) {
+        Some(format!("Bearer {}", token))
+    } else {
+        None
+    }
+}
+
+/// Builds the client that every blocking request is sent with.
+fn client(options: &Options) -> Result<Client, HttpError> {
+    let settings = client_settings(options)?;
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(options.connect_timeout)
+        .timeout(options.read_timeout);
+    if let Some(proxy) = settings.proxy {
+        builder = builder.proxy(proxy);
+    }
+    for cert in settings.certificates {
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(identity) = settings.identity {
+        builder = builder.identity(identity);
+    }
+    builder
+        .build()
+        .map_err(|e| HttpError::Config(format!("Could not create the HTTP client: {}", e)))
+}
+
+/// A failed attempt and how long the server asked us to wait before the next.
+pub(crate) type Failure = (HttpError, Option<Duration>);
+
+/// Decides whether, and after how long, a failed request is tried again.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(options: &Options) -> Self {
+        RetryPolicy {
+            retries: options.retries,
+            backoff: options.retry_backoff,
+        }
+    }
+
+    /// How long to wait before retry number `attempt`, or `error` back if it
+    /// shouldn't be retried.
+    pub(crate) fn delay(&self, failure: Failure, attempt: u32) -> Result<Duration, HttpError> {
+        let (error, retry_after) = failure;
+        if attempt >= self.retries || !error.is_transient() {
+            return Err(error);
+        }
+        let delay = retry_after
+            .unwrap_or_else(|| backoff(self.backoff, attempt))
+            .min(MAX_RETRY_DELAY);
+        warn!("{}, retrying in {:.1}s", error, delay.as_secs_f32());
+        Ok(delay)
+    }
+}
+
+/// Every request to a download server goes through this, so they all share
+/// the same client settings and retry failures the same way.
+pub(crate) struct Http {
+    client: Client,
+    retry: RetryPolicy,
+    github_token: Option<String>,
+}
+
+impl Http {
+    pub(crate) fn new(options: &Options) -> Result<Http, HttpError> {
+        Ok(Http {
+            client: client(options)?,
+            retry: RetryPolicy::new(options),
+            github_token: options.github_token.to_owned(),
+        })
+    }
+
+    fn request(&self, url: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match github_auth(&self.github_token, url) {
+            Some(auth) => request.header(AUTHORIZATION, auth),
+            None => request,
+        }
+    }
+
+    /// Sends a GET request, retrying transient failures, and turns error
+    /// statuses into errors.
+    pub(crate) fn get(&self, url: &str) -> Result<Response, HttpError> {
+        self.with_retries(url, Ok)
+    }
+
+    /// Downloads the whole body of `url`. A connection dropped part way
+    /// through the body is retried like any other transient failure.
+    pub(crate) fn get_bytes(&self, url: &str) -> Result<Vec<u8>, HttpError> {
+        self.with_retries(url, |response| response.bytes().map(|data| data.to_vec()))
+    }
+
+    fn with_retries<T, F>(&self, url: &str, read: F) -> Result<T, HttpError>
+    where
+        F: Fn(Response) -> Result<T, reqwest::Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            let failure = match self.request(url).send() {
+                Ok(response) if response.status().is_success() => match read(response) {
+                    Ok(value) => return Ok(value),
+                    Err(source) => request_failure(url, source),
+                },
+                Ok(response) => status_failure(url, response.status(), response.headers()),
+                Err(source) => request_failure(url, source),
+            };
+            thread::sleep(self.retry.delay(failure, attempt)?);
+            attempt += 1;
+        }
+    }
+
+    /// Streams `url` into `path`. The body goes into `path` with `.part`
+    /// added and is only renamed into place once all of it has arrived.
+    ///
+    /// A partial file left by a failed attempt, or by an earlier run, is
+    /// resumed with a `Range` request. `If-Range` makes the server send the
+    /// whole file instead if it has changed since the partial file was
+    /// started. Attempts that add to the partial file don't count towards
+    /// the retries.
+    ///
+    /// `on_bytes` is called with the bytes there are so far, including any
+    /// resumed from a partial file, and the full size when it's known.
+    pub(crate) fn download(
+        &self,
+        url: &str,
+        path: &Path,
+        on_bytes: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), HttpError> {
+        let partial = PartialDownload::new(path);
+        let mut attempt = 0;
+        loop {
+            let before = partial.size();
+            let failure = match self.download_part(url, &partial, on_bytes) {
+                Ok(()) => break,
+                Err(failure) => failure,
+            };
+            if partial.size() > before {
+                attempt = 0;
+            }
+            thread::sleep(self.retry.delay(failure, attempt)?);
+            attempt += 1;
+        }
+        partial.finish(path)
+    }
+
+    fn download_part(
+        &self,
+        url: &str,
+        partial: &PartialDownload,
+        on_bytes: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), Failure> {
+        let resume = partial.resume_point();
+        let mut response = self.send_from(url, &resume)?;
+        let offset = match resume_offset(&resume, response.status(), response.headers()) {
+            Some(offset) => offset,
+            None => {
+                partial.restart();
+                response = self.send_from(url, &None)?;
+                0
+            }
+        };
+        check_status(url, response.status(), response.headers(), offset)?;
+
+        let mut file = partial.open(response.headers(), offset)?;
+        let expected = response.content_length();
+        let total = expected.map(|expected| expected + offset);
+        let mut received = 0;
+        on_bytes(offset, total);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = match response.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(interrupted(url, e)),
+            };
+            file.write_all(&buffer[..read])
+                .map_err(|e| partial.io_failure(e))?;
+            received += read as u64;
+            on_bytes(offset + received, total);
+        }
+        check_length(url, expected, received)?;
+        file.sync_all().map_err(|e| partial.io_failure(e))
+    }
+
+    fn send_from(&self, url: &str, resume: &Option<(u64, String)>) -> Result<Response, Failure> {
+        let mut request = self.request(url);
+        if let Some((length, tag)) = resume {
+            request = request
+                .header(RANGE, format!("bytes={}-", length))
+                .header(IF_RANGE, tag.as_str());
+        }
+        request
+            .send()
+            .map_err(|source| request_failure(url, source))
+    }
+}
+
+pub(crate) fn request_failure(url: &str, source: reqwest::Error) -> Failure {
+    (
+        HttpError::Request {
+            url: url.to_string(),
+            source,
+        },
+        None,
+    )
+}
+
+pub(crate) fn status_failure(url: &str, status: StatusCode, headers: &HeaderMap) -> Failure {
+    if let Some(error) = rate_limited(url, status, headers) {
+        return (error, None);
+    }
+    (
+        HttpError::Status {
+            url: url.to_string(),
+            status: status.as_u16(),
+        },
+        retry_after(headers),
+    )
+}
+
+pub(crate) fn interrupted(url: &str, source: io::Error) -> Failure {
+    (
+        HttpError::Interrupted {
+            url: url.to_string(),
+            source,
+        },
+        None,
+    )
+}
+
+/// The `.part` file a download is streamed into, and the file beside it
+/// that keeps the validator needed to resume it.
+pub(crate) struct PartialDownload {
+    part: PathBuf,
+    validator: PathBuf,
+}
+
+impl PartialDownload {
+    pub(crate) fn new(path: &Path) -> Self {
+        PartialDownload {
+            part: with_suffix(path, ".part"),
+            validator: with_suffix(path, ".part.validator"),
+        }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        fs::metadata(&self.part).map(|meta| meta.len()).unwrap_or(0)
+    }
+
+    /// The size of the partial file and its validator, when it can be resumed.
+    pub(crate) fn resume_point(&self) -> Option<(u64, String)> {
+        match (fs::read_to_string(&self.validator), self.size()) {
+            (Ok(tag), length) if length > 0 => Some((length, tag)),
+            _ => None,
+        }
+    }
+
+    /// Forgets the validator so the partial file is started again.
+    pub(crate) fn restart(&self) {
+        let _ = fs::remove_file(&self.validator);
+    }
+
+    /// Keeps the response's validator and opens the partial file, either to
+    /// add to it at `offset` or to start it again.
+    pub(crate) fn open(&self, headers: &HeaderMap, offset: u64) -> Result<File, Failure> {
+        // Only a strong ETag or a date can be used with If-Range. Without
+        // either a partial file can't be resumed safely.
+        let tag = headers
+            .get(ETAG)
+            .filter(|tag| !tag.as_bytes().starts_with(b"W/"))
+            .or_else(|| headers.get(LAST_MODIFIED))
+            .and_then(|tag| tag.to_str().ok());
+        match tag {
+            Some(tag) => fs::write(&self.validator, tag).map_err(|e| self.io_failure(e))?,
+            None => self.restart(),
+        }
+
+        if offset > 0 {
+            OpenOptions::new().append(true).open(&self.part)
+        } else {
+            File::create(&self.part)
+        }
+        .map_err(|e| self.io_failure(e))
+    }
+
+    pub(crate) fn io_failure(&self, source: io::Error) -> Failure {
+        (
+            HttpError::Io {
+                path: self.part.to_path_buf(),
+                source,
+            },
+            None,
+        )
+    }
+
+    /// Renames the finished partial file to `path`.
+    pub(crate) fn finish(&self, path: &Path) -> Result<(), HttpError> {
+        fs::rename(&self.part, path).map_err(|source| HttpError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let _ = fs::remove_file(&self.validator);
+        Ok(())
+    }
+}
+
+/// Where the body of a response to a possibly resumed request starts. `None`
+/// means the partial file doesn't line up with what the server has, so the
+/// download has to start again from the beginning.
+pub(crate) fn resume_offset(
+    resume: &Option<(u64, String)>,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Option<u64> {
+    match resume {
+        Some((length, _))
+            if status == StatusCode::PARTIAL_CONTENT
+                && content_range_start(headers) == Some(*length) =>
+        {
+            Some(*length)
+        }
+        Some(_)
+            if status == StatusCode::PARTIAL_CONTENT
+                || status == StatusCode::RANGE_NOT_SATISFIABLE =>
+        {
+            None
+        }
+        _ => Some(0),
+    }
+}
+
+/// Turns an error status, or a range we didn't ask for, into a failure.
+pub(crate) fn check_status(
+    url: &str,
+    status: StatusCode,
+    headers: &HeaderMap,
+    offset: u64,
+) -> Result<(), Failure> {
+    if !status.is_success() || (status == StatusCode::PARTIAL_CONTENT && offset == 0) {
+        return Err(status_failure(url, status, headers));
+    }
+    Ok(())
+}
+
+/// Catches a body that ended early without the connection reporting it.
+pub(crate) fn check_length(url: &str, expected: Option<u64>, received: u64) -> Result<(), Failure> {
+    if matches!(expected, Some(expected) if expected != received) {
+        return Err(interrupted(
+            url,
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the connection closed before the whole body was sent",
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Reads where a `Content-Range: bytes start-end/total` header starts.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.trim().strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+/// How long to wait before retry number `attempt`, starting from zero. The
+/// delay doubles each time and half of it is random so that clients which
+/// failed together don't all come back at once.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY);
+    let half = delay / 2;
+    let random = RandomState::new().build_hasher().finish();
+    half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+}
+
+/// Reads a `Retry-After` header, which is either a number of seconds or a
+/// date to wait until.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::from_secs(0)),
+    )
+}
+
+/// GitHub answers with a 403 or 429 and says how many requests are left in
+/// `X-RateLimit-*` headers once it has had too many.
+fn rate_limited(url: &str, status: StatusCode, headers: &HeaderMap) -> Option<HttpError> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let number =
+        |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+    let remaining = number("x-ratelimit-remaining").filter(|remaining| *remaining == 0)?;
+    Some(HttpError::RateLimited {
+        url: url.to_string(),
+        remaining,
+        limit: number("x-ratelimit-limit"),
+        reset: number("x-ratelimit-reset")
+            .map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)),
+    })
+}
+
+fn read(path: &Path, what: &str) -> Result<Vec<u8>, HttpError> {
+    fs::read(path).map_err(|e| {
+        HttpError::Config(format!("Could not read {} {}: {}", what, path.display(), e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, Request, TestServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    #[test]
+    fn default_options_build_a_client() {
+        assert!(client(&Options::default()).is_ok());
+    }
+
+    #[test]
+    fn proxy_with_auth_builds_a_client() {
+        let options = Options {
+            proxy: Some("http://proxy.example:3128".to_string()),
+            proxy_user: Some("user".to_string()),
+            proxy_password: Some("secret".to_string()),
+            no_proxy: Some("localhost, .internal.example".to_string()),
+            ..Options::default()
+        };
+        assert!(client(&options).is_ok());
+    }
+
+    #[test]
+    fn invalid_proxy_is_a_config_error() {
+        let options = Options {
+            proxy: Some("not a proxy".to_string()),
+            ..Options::default()
+        };
+        match client(&options) {
+            Err(HttpError::Config(message)) => assert!(message.contains("not a proxy")),
+            other => panic!("Expected a config error but got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_ca_bundle_is_a_config_error() {
+        let options = Options {
+            ca_bundle: Some("ca_bundle_wont_exist.pem".into()),
+            ..Options::default()
+        };
+        match client(&options) {
+            Err(HttpError::Config(message)) => {
+                assert!(message.contains("ca_bundle_wont_exist.pem"), "{}", message)
+            }
+            other => panic!("Expected a config error but got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_client_certificate_is_a_config_error() {
+        let dir = TempDir::new().unwrap();
+        let cert = dir.path().join("client.p12");
+        fs::write(&cert, b"not a certificate").unwrap();
+
+        let options = Options {
+            client_cert: Some(cert),
+            ..Options::default()
+        };
+        match client(&options) {
+            Err(HttpError::Config(message)) => {
+                assert!(message.contains("client certificate"), "{}", message)
+            }
+            other => panic!("Expected a config error but got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn http(retries: u32) -> Http {
+        Http::new(&Options {
+            retries,
+            retry_backoff: Duration::from_millis(1),
+            ..Options::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn request_errors_include_the_cause() {
+        // Nothing listens on the discard port so the connection is refused
+        let error = http(0).get("http://127.0.0.1:9/").unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("Request to http://127.0.0.1:9/ failed: "));
+        assert!(message.matches(": ").count() > 1, "{}", message);
+    }
+
+    #[test]
+    fn requests_identify_themselves() {
+        let server = TestServer::sequence(vec![Reply::ok(b"")]);
+        http(0).get(&server.url).unwrap();
+        let user_agent = server.requests()[0].header("user-agent").map(String::from);
+        assert_eq!(
+            user_agent.as_deref(),
+            Some(concat!("browser-manager/", env!("CARGO_PKG_VERSION")))
+        );
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let server = TestServer::sequence(vec![
+            Reply::status(503),
+            Reply::status(500),
+            Reply::ok(b"done"),
+        ]);
+        assert_eq!(http(3).get_bytes(&server.url).unwrap(), b"done");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_the_configured_retries() {
+        let server = TestServer::sequence(vec![Reply::status(502)]);
+        match http(2).get(&server.url) {
+            Err(HttpError::Status { status, .. }) => assert_eq!(status, 502),
+            other => panic!("Expected a status error but got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let server = TestServer::sequence(vec![Reply::status(404), Reply::ok(b"")]);
+        assert!(http(3).get(&server.url).is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn rate_limits_say_when_they_reset() {
+        let headers = vec![
+            ("X-RateLimit-Limit".to_string(), "60".to_string()),
+            ("X-RateLimit-Remaining".to_string(), "0".to_string()),
+            ("X-RateLimit-Reset".to_string(), "4102444800".to_string()),
+        ];
+        let server = TestServer::sequence(vec![
+            Reply::Response(403, headers, b"API rate limit exceeded".to_vec()),
+            Reply::ok(b""),
+        ]);
+        let error = http(3).get(&server.url).unwrap_err();
+        assert_eq!(server.requests().len(), 1);
+        match &error {
+            HttpError::RateLimited {
+                remaining,
+                limit,
+                reset,
+                ..
+            } => {
+                assert_eq!(*remaining, 0);
+                assert_eq!(*limit, Some(60));
+                assert_eq!(
+                    *reset,
+                    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(4102444800))
+                );
+            }
+            other => panic!("Expected a rate limit but got {:?}", other),
+        }
+        let message = error.to_string();
+        assert!(message.contains("0 of 60 requests"), "{}", message);
+        assert!(message.contains("01 Jan 2100"), "{}", message);
+
+        // A 403 that isn't about the rate limit is left alone
+        let server = TestServer::sequence(vec![Reply::status(403)]);
+        let error = http(0).get(&server.url).unwrap_err();
+        assert!(matches!(error, HttpError::Status { status: 403, .. }));
+    }
+
+    #[test]
+    fn github_tokens_are_only_sent_to_github() {
+        let token = Some("secret".to_string());
+        let auth = |url| github_auth(&token, url);
+        assert_eq!(
+            auth("https://api.github.com/repos/mozilla/geckodriver/releases/latest"),
+            Some("Bearer secret".to_string())
+        );
+        assert!(auth("https://github.com/mozilla/geckodriver/releases/latest").is_some());
+        assert_eq!(auth("http://api.github.com/"), None);
+        assert_eq!(auth("https://artifactory.example/github.com/"), None);
+        assert_eq!(auth("https://github.com.example/"), None);
+        assert_eq!(github_auth(&None, "https://api.github.com/"), None);
+
+        let server = TestServer::sequence(vec![Reply::ok(b"")]);
+        let http = Http::new(&Options {
+            github_token: token.clone(),
+            ..Options::default()
+        })
+        .unwrap();
+        http.get(&server.url).unwrap();
+        assert_eq!(server.requests()[0].header("authorization"), None);
+    }
+
+    #[test]
+    fn connection_resets_are_retried() {
+        let server = TestServer::sequence(vec![Reply::Reset, Reply::Reset, Reply::ok(b"done")]);
+        assert_eq!(http(3).get_bytes(&server.url).unwrap(), b"done");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn truncated_bodies_are_retried() {
+        let server = TestServer::sequence(vec![
+            Reply::Truncated(200, vec![], b"do".to_vec(), 4),
+            Reply::ok(b"done"),
+        ]);
+        assert_eq!(http(1).get_bytes(&server.url).unwrap(), b"done");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn retry_after_is_honoured() {
+        let server = TestServer::sequence(vec![
+            Reply::Response(
+                429,
+                vec![("Retry-After".to_string(), "1".to_string())],
+                vec![],
+            ),
+            Reply::ok(b"done"),
+        ]);
+        let start = Instant::now();
+        assert_eq!(http(1).get_bytes(&server.url).unwrap(), b"done");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn can_read_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        headers.insert(RETRY_AFTER, later.parse().unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..4 {
+            let full = base * (1 << attempt);
+            let delay = backoff(base, attempt);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+        let delay = backoff(base, 30);
+        assert!(delay >= MAX_RETRY_DELAY / 2 && delay <= MAX_RETRY_DELAY);
+    }
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// Serves `BODY` with `tag` as its ETag, and only the requested range
+    /// when `If-Range` matches like a real server.
+    fn ranged(request: &Request, tag: &str) -> Reply {
+        let headers = vec![("ETag".to_string(), tag.to_string())];
+        let start = match (request.header("range"), request.header("if-range")) {
+            (Some(range), Some(if_range)) if if_range == tag => range
+                .trim_start_matches("bytes=")
+                .trim_end_matches('-')
+                .parse::<usize>()
+                .unwrap(),
+            _ => return Reply::Response(200, headers, BODY.to_vec()),
+        };
+        let mut headers = headers;
+        headers.push((
+            "Content-Range".to_string(),
+            format!("bytes {}-{}/{}", start, BODY.len() - 1, BODY.len()),
+        ));
+        Reply::Response(206, headers, BODY[start..].to_vec())
+    }
+
+    #[test]
+    fn interrupted_downloads_are_resumed() {
+        let count = AtomicUsize::new(0);
+        let server = TestServer::new(move |request| {
+            if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                let headers = vec![("ETag".to_string(), "\"v1\"".to_string())];
+                Reply::Truncated(200, headers, BODY[..10].to_vec(), BODY.len())
+            } else {
+                ranged(request, "\"v1\"")
+            }
+        });
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+
+        http(1).download(&server.url, &path, &|_, _| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        let requests = server.requests();
+        assert_eq!(requests[0].header("range"), None);
+        assert_eq!(requests[1].header("range"), Some("bytes=10-"));
+        assert_eq!(requests[1].header("if-range"), Some("\"v1\""));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn partial_files_from_earlier_runs_are_resumed() {
+        let server = TestServer::new(|request| ranged(request, "\"v1\""));
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+        fs::write(dir.path().join("driver.zip.part"), &BODY[..5]).unwrap();
+        fs::write(dir.path().join("driver.zip.part.validator"), "\"v1\"").unwrap();
+
+        let progress = std::sync::Mutex::new(vec![]);
+        http(0)
+            .download(&server.url, &path, &|done, total| {
+                progress.lock().unwrap().push((done, total))
+            })
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        assert_eq!(server.requests()[0].header("range"), Some("bytes=5-"));
+        let progress = progress.into_inner().unwrap();
+        let total = Some(BODY.len() as u64);
+        assert_eq!(progress.first(), Some(&(5, total)));
+        assert_eq!(progress.last(), Some(&(BODY.len() as u64, total)));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn changed_files_are_downloaded_again() {
+        let server = TestServer::new(|request| ranged(request, "\"v2\""));
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+        fs::write(dir.path().join("driver.zip.part"), b"stale").unwrap();
+        fs::write(dir.path().join("driver.zip.part.validator"), "\"v1\"").unwrap();
+
+        http(0).download(&server.url, &path, &|_, _| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn downloads_without_a_validator_start_again() {
+        let server = TestServer::sequence(vec![
+            Reply::Truncated(200, vec![], BODY[..10].to_vec(), BODY.len()),
+            Reply::ok(BODY),
+        ]);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+
+        http(1).download(&server.url, &path, &|_, _| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), BODY);
+        assert_eq!(server.requests()[1].header("range"), None);
+    }
+
+    #[test]
+    fn failed_downloads_are_not_renamed_into_place() {
+        let server = TestServer::sequence(vec![Reply::Truncated(
+            200,
+            vec![],
+            BODY[..10].to_vec(),
+            BODY.len(),
+        )]);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+
+        match http(1).download(&server.url, &path, &|_, _| {}) {
+            Err(HttpError::Interrupted { .. }) => {}
+            other => panic!("Expected an interrupted download but got {:?}", other),
+        }
+        assert!(!path.exists());
+        assert!(dir.path().join("driver.zip.part").exists());
+    }
+}
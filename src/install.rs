@@ -0,0 +1,171 @@
+use crate::browser::Browser;
+use crate::options::Options;
+use crate::progress::Progress;
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// The outcome of setting up one browser from [`install_all`](fn.install_all.html).
+#[derive(Debug)]
+pub struct Installed {
+    /// The name of the browser that was asked for.
+    pub name: String,
+    /// The browser with its driver, or why it couldn't be set up.
+    pub result: Result<Browser, Box<dyn Error + Send + Sync>>,
+}
+
+/// Sets up each of `browsers`, running up to `options.parallelism` of them at
+/// once. Every browser is tried even if others fail, and the results are in
+/// the same order as `browsers`.
+///
+/// # Example
+/// ```no_run
+/// use browser_manager::{install_all, Browser, Options};
+/// let browsers = vec![
+///     Browser::new("firefox".into(), "".into(), "".into(), "latest".into()),
+///     Browser::new("chrome".into(), "".into(), "".into(), "latest".into()),
+/// ];
+/// for installed in install_all(&browsers, &Options::default(), &|_| {}) {
+///     if let Err(e) = installed.result {
+///         eprintln!("{}: {}", installed.name, e);
+///     }
+/// }
+/// ```
+pub fn install_all(
+    browsers: &[Browser],
+    options: &Options,
+    on_progress: &(dyn Fn(&Progress) + Sync),
+) -> Vec<Installed> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..browsers.len()).map(|_| None).collect::<Vec<_>>());
+    let workers = options.parallelism.max(1).min(browsers.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let browser = match browsers.get(index) {
+                    Some(browser) => browser,
+                    None => break,
+                };
+                let result = browser.install(options, on_progress);
+                results.lock().unwrap()[index] = Some(Installed {
+                    name: browser.name.to_owned(),
+                    result,
+                });
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|installed| installed.expect("Every browser should have been installed"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::Mirrors;
+    use crate::test_server::{Reply, TestServer};
+    use std::io::{Cursor, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn driver_zip() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("geckodriver", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"driver").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn firefox_in(dir: &TempDir) -> Browser {
+        Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("windows", "x86_64")
+    }
+
+    #[test]
+    fn installs_run_in_parallel_up_to_the_limit() {
+        let archive = driver_zip();
+        let running = AtomicUsize::new(0);
+        let most = Arc::new(AtomicUsize::new(0));
+        let seen = most.clone();
+        let server = TestServer::new(move |request| match request.path.as_str() {
+            "/releases/latest" => {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                seen.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(200));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Reply::redirect("/releases/tag/v0.29.1")
+            }
+            _ => Reply::ok(&archive),
+        });
+        let cache = TempDir::new().unwrap();
+        let options = Options {
+            parallelism: 2,
+            retries: 0,
+            mirrors: Mirrors {
+                geckodriver: format!("{}/download/", server.url),
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache.path())
+        };
+        let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+        let browsers: Vec<Browser> = dirs.iter().map(firefox_in).collect();
+
+        let results = install_all(&browsers, &options, &|_| {});
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|installed| installed.result.is_ok()));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert!(dirs
+            .iter()
+            .all(|dir| dir.path().join("geckodriver").exists()));
+    }
+
+    #[test]
+    fn one_failure_does_not_hide_the_others() {
+        let archive = driver_zip();
+        let server = TestServer::new(move |request| match request.path.as_str() {
+            "/releases/latest" => Reply::redirect("/releases/tag/v0.29.1"),
+            "/missing" => Reply::status(404),
+            _ => Reply::ok(&archive),
+        });
+        let cache = TempDir::new().unwrap();
+        let options = Options {
+            retries: 0,
+            mirrors: Mirrors {
+                geckodriver: format!("{}/download/", server.url),
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                chromedriver_latest: format!("{}/missing", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache.path())
+        };
+        let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+        let chrome = Browser::new(
+            "chrome".to_string(),
+            dirs[0].path().display().to_string(),
+            dirs[0].path().join("chrome").display().to_string(),
+            "latest".to_string(),
+        );
+        let browsers = vec![chrome, firefox_in(&dirs[1])];
+
+        let results = install_all(&browsers, &options, &|_| {});
+        assert_eq!(results[0].name, "chrome");
+        assert!(results[0].result.is_err());
+        assert_eq!(results[1].name, "firefox");
+        assert!(results[1].result.is_ok());
+    }
+}
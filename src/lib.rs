@@ -4,7 +4,9 @@
 //! up Selenium Drivers from the browser vendors like Chrome and Firefox.
 //!
 //! The downloads are placed in the result of the
-//! [`get_project_dir`](fn.get_project_dir.html).
+//! [`get_project_dir`](fn.get_project_dir.html), which can be moved with the
+//! `--cache-path` flag, the `BROWSER_MANAGER_HOME` environmental variable or
+//! `cache_path` in the `config.toml` in the [`get_config_dir`](fn.get_config_dir.html).
 //!
 //! ```bash
 //! USAGE:
@@ -16,17 +18,55 @@
 //!
 //! OPTIONS:
 //!    -b, --browser <browser_name>    Select the browser you wish to you with version. E.g. Firefox@69 or Chrome@latest
+//!        --cache-path <path>         Directory to download browsers and drivers into
 //! ```
+//!
+//! The library API is blocking. Enable the `async` feature for async versions
+//! that can be used inside a tokio runtime, like
+//! [`find_browser_for_async`](fn.find_browser_for_async.html) and
+//! `Browser::download_async`.
 
 use directories::ProjectDirs;
-use std::env;
-use std::fs;
 use std::io;
 use std::path::PathBuf;
-use which::which;
 
 mod browser;
-use crate::browser::Browser;
+mod bundle;
+mod cab;
+mod clean;
+mod config;
+mod discovery;
+mod dmg;
+mod extract;
+mod http;
+mod install;
+mod lock;
+mod metadata;
+mod msi;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod options;
+mod platform;
+mod progress;
+mod record;
+#[cfg(test)]
+mod test_server;
+mod versions;
+pub use crate::browser::{Browser, DownloadLinks, Mirrors};
+pub use crate::bundle::{write_bundle, BundledBrowser, Manifest, MANIFEST_FILE};
+pub use crate::clean::{clean, CleanPolicy, CleanReport, RemovedInstall};
+pub use crate::config::{env_var, Config, Source, ENV_PREFIX};
+pub use crate::discovery::Discovery;
+pub use crate::extract::{ArchiveFormat, ExtractError};
+pub use crate::http::HttpError;
+pub use crate::install::{install_all, Installed};
+pub use crate::options::{Options, CONFIG_FILE, HOME_ENV};
+pub use crate::platform::{Arch, Os, Platform, Provider, UnsupportedPlatform};
+pub use crate::progress::{Phase, Progress};
+pub use crate::record::InstallRecord;
+#[cfg(feature = "async")]
+pub use crate::versions::available_versions_async;
+pub use crate::versions::{available_versions, available_versions_with, Channel, Kind};
 
 /// Finds the path of a driver executable name passed in if it's on the
 /// `PATH` environmental variable.
@@ -42,44 +82,39 @@ use crate::browser::Browser;
 /// let can_we = can_find_driver("geckodriver");
 /// ```
 pub fn can_find_driver(driver: &str) -> PathBuf {
-    let result = which(driver);
-    match result {
-        Ok(path) => path,
-        Err(_) => PathBuf::new(),
-    }
+    Discovery::new().find_driver(driver).unwrap_or_default()
+}
+
+/// Finds the directory that browsers and drivers are downloaded to, creating it
+/// if needed. This respects the `BROWSER_MANAGER_HOME` environmental variable
+/// and the `cache_path` in the config file, otherwise it is the platform cache
+/// directory. See [`Options::project_dir`](struct.Options.html#method.project_dir)
+/// for examples.
+pub fn get_project_dir() -> io::Result<PathBuf> {
+    Options::load()?.project_dir()
 }
 
-/// Finds the base config directory, in a cross platform way, to create a new config directory
-/// for the org.webdriver.browser-manager project. Below are examples
+/// Finds the config directory, in a cross platform way, for the
+/// org.webdriver.browser-manager project. Below are examples
 ///
 /// |Platform | Value                                 | Example                                  |
 /// | ------- | ------------------------------------- | ---------------------------------------- |
-/// | Linux   | `$XDG_CONFIG_HOME` or `$HOME`/.config | /home/alice/.config/webdriverbrowsermanager|
+/// | Linux   | `$XDG_CONFIG_HOME` or `$HOME`/.config | /home/alice/.config/browser-manager      |
 /// | macOS   | `$HOME`/Library/Preferences  | /Users/Alice/Library/Preferences/org.webdriver.browser-manager |
-/// | Windows | `{FOLDERID_RoamingAppData}`           | C:\Users\Alice\AppData\Roaming\webdriver\browser-manager|
-pub fn get_project_dir() -> io::Result<PathBuf> {
-    let proj_dirs = ProjectDirs::from("org", "webdriver", "browser-manager");
-    match proj_dirs {
-        Some(proj_dir) => {
-            let selenium_dir = proj_dir.config_dir();
-            if selenium_dir.is_dir() {
-                Ok(PathBuf::from(selenium_dir))
-            } else {
-                let _created = fs::create_dir_all(selenium_dir);
-                match _created {
-                    Ok(_) => Ok(PathBuf::from(selenium_dir)),
-                    Err(_) => panic!("Could not create the project directory"),
-                }
-            }
-        }
-        None => {
-            panic!("Could not look up project directory")
-        }
+/// | Windows | `{FOLDERID_RoamingAppData}`           | C:\Users\Alice\AppData\Roaming\webdriver\browser-manager\config|
+pub fn get_config_dir() -> io::Result<PathBuf> {
+    match ProjectDirs::from("org", "webdriver", "browser-manager") {
+        Some(proj_dir) => Ok(proj_dir.config_dir().to_path_buf()),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Could not look up project directory",
+        )),
     }
 }
 
 /// Finds the browser details if they are available on the `PATH`
-/// environmental variable.
+/// environmental variable or in the usual install locations for the platform.
+/// Use [`Discovery`](struct.Discovery.html) to search somewhere else.
 ///
 /// # Arguments
 ///
@@ -91,165 +126,24 @@ pub fn get_project_dir() -> io::Result<PathBuf> {
 /// let browser = find_browser_for("firefox".to_string());
 /// ```
 pub fn find_browser_for(browser_name: String) -> Option<Browser> {
-    let available_browsers = get_available_browsers();
-    let mut found_browser = None;
-
-    for browser in &available_browsers {
-        if browser.name.eq(&browser_name) {
-            found_browser = Some(browser.to_owned());
-            break;
-        }
-    }
-    found_browser
-}
-
-fn get_available_browsers() -> Vec<Browser> {
-    let browsers: Vec<&str> = vec!["firefox", "firefox-bin", "Google Chrome", "chrome"];
-    let mut available_browsers: Vec<Browser> = vec![];
-
-    for exe in &browsers {
-        let result = which(exe);
-        match result {
-            Ok(path) => {
-                if path.display().to_string().contains("firefox") {
-                    let firefox = Browser::new(
-                        "firefox".to_string(),
-                        can_find_driver("geckodriver").display().to_string(),
-                        path.display().to_string(),
-                        "".to_string(),
-                    );
-                    available_browsers.push(firefox);
-                } else {
-                    let chrome = Browser::new(
-                        "chrome".to_string(),
-                        can_find_driver("chromedriver").display().to_string(),
-                        path.display().to_string(),
-                        "".to_string(),
-                    );
-                    available_browsers.push(chrome);
-                }
-            }
-            Err(_) => {}
-        }
-    }
-
-    if available_browsers.len() == 0 {
-        // Let's check if they might be available in the usual places on Mac.
-        // They should have been caught on other platforms
-        if let Some(browser) = check_path(
-            "firefox".to_string(),
-            "/Applications/Firefox.app/Contents/MacOS/firefox-bin",
-            "geckodriver",
-        ) {
-            available_browsers.push(browser);
-        }
-
-        if let Some(browser) = check_path(
-            "chrome".to_string(),
-            "/Applications/Google Chrome.app/Contents/MacOS/Google Chrome",
-            "chromedriver",
-        ) {
-            available_browsers.push(browser);
-        }
-    }
-
-    if is_mac() {
-        let safari = Browser::new(
-            "Safari".to_string(),
-            "/usr/bin/safaridriver".to_string(),
-            "/Applications/Safari.app/Contents/MacOS/Safari".to_string(),
-            "".to_string(),
-        );
-        available_browsers.push(safari);
-    }
-
-    available_browsers
-}
-
-fn check_path(name: String, path: &str, driver: &str) -> Option<Browser> {
-    let browser_path = PathBuf::from(path);
-
-    if browser_path.is_file() {
-        let browser = Browser::new(
-            name,
-            can_find_driver(driver).display().to_string(),
-            browser_path.display().to_string(),
-            "".to_string(),
-        );
-        Some(browser)
-    } else {
-        None
-    }
-}
-
-fn is_mac() -> bool {
-    env::consts::OS.to_string().eq(&"macos".to_string())
+    Discovery::new().find_browser_for(&browser_name)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-
-    #[test]
-    fn cant_find_drivers() {
-        let drivers = which("geckodriver");
-        match drivers {
-            Ok(path) => match env::var("PATH") {
-                Ok(value) => {
-                    let paths = env::split_paths(&value);
-                    let tmp__ = path.as_path().display().to_string();
-                    let mut tmp_path: Vec<&str> = tmp__.split("/").collect();
-                    tmp_path.pop();
-                    let driver_path = tmp_path.join("/");
-                    let mut new_paths: Vec<String> = vec![];
-                    for pat in paths {
-                        if driver_path.ne(&pat.display().to_string()) {
-                            new_paths.push(pat.display().to_string());
-                        }
-                    }
-
-                    env::set_var("PATH", &new_paths.join(":"));
-
-                    let need_path = can_find_driver("geckodriver");
-                    assert_eq!(need_path.display().to_string(), "".to_string());
-                }
-                Err(_) => {}
-            },
-            Err(_) => {}
-        }
-    }
-
-    #[test]
-    fn can_find_drivers_on_path() {
-        // This test assumes that drivers are already on the path.
-        let need_path = can_find_driver("geckodriver");
-        assert_ne!(need_path.display().to_string(), "");
-    }
-
-    #[test]
-    fn browsers_available_on_each_platform() {
-        // We need to mostly check that we don't get Safari on other platforms
-        let available_browsers = get_available_browsers();
-
-        if is_mac() {
-            // Safari is always available on Mac.
-            assert!(available_browsers.len() >= 2);
-        } else {
-            assert!(available_browsers.len() >= 1);
-        }
-    }
-
-    //#[test]
-    fn should_be_found_and_returned() {
-        // This test assumes that there is a browser available and found
-
-        let found_browser = find_browser_for("chrome".to_string());
-        match found_browser {
-            Some(browser) => {
-                assert_eq!(browser.name, "chrome".to_string())
-            }
-            None => assert!(false, "Was not able to find browsers on the machine"),
-        }
-    }
+/// Async version of [`find_browser_for`](fn.find_browser_for.html). The
+/// file system is searched on a blocking thread so the runtime isn't held up.
+///
+/// # Example
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use browser_manager::find_browser_for_async;
+/// let browser = find_browser_for_async("firefox".to_string()).await;
+/// # }
+/// ```
+#[cfg(feature = "async")]
+pub async fn find_browser_for_async(browser_name: String) -> Option<Browser> {
+    tokio::task::spawn_blocking(move || find_browser_for(browser_name))
+        .await
+        .ok()
+        .flatten()
 }
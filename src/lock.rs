@@ -0,0 +1,96 @@
+use fs2::FileExt;
+use log::info;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// An advisory lock that stops other browser-manager processes installing
+/// the same file at the same time. It is released when dropped, or by the
+/// OS if the process dies.
+#[derive(Debug)]
+pub(crate) struct InstallLock {
+    file: File,
+}
+
+impl InstallLock {
+    /// Waits until no other process is installing `target`, then locks it.
+    /// The lock is held on a `.lock` file beside `target`.
+    pub(crate) fn acquire(target: &Path) -> io::Result<InstallLock> {
+        let path = lock_path(target);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() != ErrorKind::WouldBlock && e.raw_os_error() != lock_contended() {
+                return Err(e);
+            }
+            info!(
+                "Waiting for another browser-manager to finish installing {}",
+                target.display()
+            );
+            file.lock_exclusive()?;
+        }
+        Ok(InstallLock { file })
+    }
+}
+
+impl Drop for InstallLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// The file the lock for `target` is held on.
+pub(crate) fn lock_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// The OS error fs2 gives when another process holds the lock, which isn't
+/// always reported as `WouldBlock`.
+fn lock_contended() -> Option<i32> {
+    fs2::lock_contended_error().raw_os_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn second_lock_waits_for_the_first() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("geckodriver.tar.gz");
+        let first = InstallLock::acquire(&target).unwrap();
+        assert!(dir.path().join("geckodriver.tar.gz.lock").exists());
+
+        let (sender, receiver) = mpsc::channel();
+        let waiting = target.clone();
+        let handle = thread::spawn(move || {
+            let _second = InstallLock::acquire(&waiting).unwrap();
+            sender.send(()).unwrap();
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn locks_for_different_targets_are_independent() {
+        let dir = TempDir::new().unwrap();
+        let _firefox = InstallLock::acquire(&dir.path().join("geckodriver.tar.gz")).unwrap();
+        assert!(InstallLock::acquire(&dir.path().join("chromedriver.zip")).is_ok());
+    }
+}
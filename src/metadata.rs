@@ -0,0 +1,221 @@
+use crate::lock::InstallLock;
+use crate::options::Options;
+
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The file in the project directory that looked up metadata is kept in.
+pub(crate) const METADATA_FILE: &str = "metadata.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    /// When the value was looked up, in seconds since the Unix epoch.
+    fetched: u64,
+    value: Value,
+}
+
+/// Metadata looked up from the download servers, like the latest version of
+/// a driver. It is kept in the project directory and only looked up again
+/// once it is older than its TTL, so that running often doesn't hit the
+/// servers every time and get rate limited.
+pub(crate) struct MetadataCache {
+    path: PathBuf,
+    offline: bool,
+    refresh: bool,
+}
+
+impl MetadataCache {
+    pub(crate) fn new(options: &Options) -> io::Result<Self> {
+        Ok(MetadataCache {
+            path: options.project_dir()?.join(METADATA_FILE),
+            offline: options.offline,
+            refresh: options.refresh,
+        })
+    }
+
+    /// Gets `key` from the cache, or from `fetch` when it isn't there or is
+    /// older than `ttl`.
+    pub(crate) fn get_or_fetch<T, F>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        fetch: F,
+    ) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<T, Box<dyn Error + Send + Sync>>,
+    {
+        if let Some(value) = self.cached(key, ttl)? {
+            return Ok(value);
+        }
+        let value = fetch()?;
+        self.store(key, &value)?;
+        Ok(value)
+    }
+
+    /// Gets `key` from the cache if it is younger than `ttl`, or `None` when
+    /// it should be looked up again. While offline an expired value is
+    /// better than nothing, so it is used instead, and having nothing is an
+    /// error.
+    pub(crate) fn cached<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+        if let Some(entry) = self.read().remove(key) {
+            let age = now().saturating_sub(entry.fetched);
+            if self.offline || (age < ttl.as_secs() && !self.refresh) {
+                match serde_json::from_value(entry.value) {
+                    Ok(value) => {
+                        debug!("Using {} from {}", key, self.path.display());
+                        return Ok(Some(value));
+                    }
+                    Err(e) => warn!("Ignoring {} in {}: {}", key, self.path.display(), e),
+                }
+            }
+        }
+        if self.offline {
+            return Err(format!(
+                "{} has not been looked up yet and can not be while offline",
+                key
+            )
+            .into());
+        }
+        Ok(None)
+    }
+
+    fn read(&self) -> BTreeMap<String, Entry> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(_) => return BTreeMap::new(),
+        };
+        serde_json::from_slice(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring {}, it is broken: {}", self.path.display(), e);
+            BTreeMap::new()
+        })
+    }
+
+    /// Adds `value` to the file. Other processes could be adding their own
+    /// values at the same time, so the file is locked while it is rewritten
+    /// and replaced in one rename.
+    pub(crate) fn store<T: Serialize>(&self, key: &str, value: &T) -> io::Result<()> {
+        let _lock = InstallLock::acquire(&self.path)?;
+        let mut entries = self.read();
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fetched: now(),
+                value: serde_json::to_value(value)?,
+            },
+        );
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(format!(".{}", process::id()));
+        let temporary = PathBuf::from(temporary);
+        fs::write(&temporary, serde_json::to_vec_pretty(&entries)?)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const EXPIRED: Duration = Duration::from_secs(0);
+
+    fn cache(dir: &TempDir, offline: bool, refresh: bool) -> MetadataCache {
+        let options = Options {
+            offline,
+            refresh,
+            ..Options::default().with_cache_path(dir.path())
+        };
+        MetadataCache::new(&options).unwrap()
+    }
+
+    #[test]
+    fn values_are_kept_until_they_expire() {
+        let dir = TempDir::new().unwrap();
+        let fetches = Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok(vec!["1.0".to_string()])
+        };
+
+        let cache = cache(&dir, false, false);
+        assert_eq!(
+            cache.get_or_fetch("versions", HOUR, fetch).unwrap(),
+            vec!["1.0"]
+        );
+        assert_eq!(
+            cache.get_or_fetch("versions", HOUR, fetch).unwrap(),
+            vec!["1.0"]
+        );
+        assert_eq!(fetches.get(), 1);
+        assert!(dir.path().join(METADATA_FILE).is_file());
+
+        cache.get_or_fetch("versions", EXPIRED, fetch).unwrap();
+        assert_eq!(fetches.get(), 2);
+    }
+
+    #[test]
+    fn refreshing_ignores_the_ttl() {
+        let dir = TempDir::new().unwrap();
+        let fetches = Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok(fetches.get())
+        };
+        cache(&dir, false, false)
+            .get_or_fetch("latest", HOUR, fetch)
+            .unwrap();
+        let refreshed = cache(&dir, false, true).get_or_fetch("latest", HOUR, fetch);
+        assert_eq!(refreshed.unwrap(), 2);
+        let cached = cache(&dir, false, false).get_or_fetch("latest", HOUR, fetch);
+        assert_eq!(cached.unwrap(), 2);
+    }
+
+    #[test]
+    fn expired_values_are_used_while_offline() {
+        let dir = TempDir::new().unwrap();
+        let fetch = || -> Result<String, Box<dyn Error + Send + Sync>> { Ok("1.0".to_string()) };
+        cache(&dir, false, false)
+            .get_or_fetch("latest", HOUR, fetch)
+            .unwrap();
+
+        let offline = cache(&dir, true, false);
+        assert_eq!(
+            offline.get_or_fetch("latest", EXPIRED, fetch).unwrap(),
+            "1.0"
+        );
+        let missing = offline.get_or_fetch("other", EXPIRED, fetch).unwrap_err();
+        assert!(missing.to_string().contains("offline"), "{}", missing);
+    }
+
+    #[test]
+    fn broken_files_are_replaced() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(METADATA_FILE), b"{ not json").unwrap();
+        let cache = cache(&dir, false, false);
+        let value = cache.get_or_fetch("latest", HOUR, || Ok(1)).unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(cache.read().len(), 1);
+    }
+}
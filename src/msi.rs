@@ -0,0 +1,642 @@
+//! Reading the tables of Windows Installer packages, so that the files in
+//! their cabinets can be given the paths they would be installed to.
+//!
+//! A package is a compound file. Each table is a stream holding its columns
+//! one after another, with strings kept once in a shared string pool.
+//! Problems are reported as `io::Error`s with `InvalidData`.
+
+use cfb::CompoundFile;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Seek};
+use std::path::PathBuf;
+
+/// Stream names are packed into characters from this range, and table
+/// streams start with `TABLE_PREFIX`.
+const PACKED_TWO: u32 = 0x3800;
+const PACKED_ONE: u32 = 0x4800;
+const TABLE_PREFIX: u32 = 0x4840;
+const PACKED_CHARS: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz._";
+
+/// Set in the string pool's code page when string references are three
+/// bytes rather than two.
+const LONG_STRING_REFS: u32 = 0x8000_0000;
+
+/// Column type bits.
+const TYPE_WIDTH: u16 = 0x00ff;
+const TYPE_VALID: u16 = 0x0100;
+const TYPE_STRING: u16 = 0x0800;
+const TYPE_NULLABLE: u16 = 0x1000;
+
+/// Directories that are put wherever the system keeps them, so files in
+/// them are unpacked relative to the folder instead.
+const STANDARD_FOLDERS: &[&str] = &[
+    "TARGETDIR",
+    "ProgramFilesFolder",
+    "ProgramFiles64Folder",
+    "LocalAppDataFolder",
+    "AppDataFolder",
+    "CommonFilesFolder",
+    "CommonFiles64Folder",
+];
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Unpacks the name of a stream. Table names come back starting with `!`.
+pub(crate) fn decode_name(name: &str) -> String {
+    let mut decoded = String::new();
+    for c in name.chars() {
+        let c = u32::from(c);
+        if (PACKED_TWO..PACKED_ONE).contains(&c) {
+            let c = c - PACKED_TWO;
+            decoded.push(char::from(PACKED_CHARS[(c & 0x3f) as usize]));
+            decoded.push(char::from(PACKED_CHARS[(c >> 6) as usize]));
+        } else if (PACKED_ONE..TABLE_PREFIX).contains(&c) {
+            decoded.push(char::from(PACKED_CHARS[(c - PACKED_ONE) as usize]));
+        } else if c == TABLE_PREFIX {
+            decoded.push('!');
+        } else {
+            decoded.extend(std::char::from_u32(c));
+        }
+    }
+    decoded
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Int(i32),
+    Str(String),
+}
+
+struct Column {
+    name: String,
+    kind: u16,
+}
+
+/// The rows of a table, by column name.
+struct Table {
+    rows: Vec<HashMap<String, Value>>,
+}
+
+impl Table {
+    fn strings(&self, key: &str, columns: &[&str]) -> HashMap<String, Vec<Option<String>>> {
+        self.rows
+            .iter()
+            .filter_map(|row| {
+                let key = match row.get(key) {
+                    Some(Value::Str(key)) => key.clone(),
+                    _ => return None,
+                };
+                let values = columns
+                    .iter()
+                    .map(|column| match row.get(*column) {
+                        Some(Value::Str(value)) => Some(value.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                Some((key, values))
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct Package<F> {
+    file: CompoundFile<F>,
+    /// Decoded stream names and where the streams are.
+    streams: HashMap<String, PathBuf>,
+    strings: Vec<String>,
+    long_refs: bool,
+}
+
+impl<F: Read + Seek> Package<F> {
+    pub(crate) fn open(inner: F) -> io::Result<Package<F>> {
+        let file = CompoundFile::open(inner)?;
+        let streams = file
+            .read_root_storage()
+            .filter(|entry| entry.is_stream())
+            .map(|entry| (decode_name(entry.name()), entry.path().to_path_buf()))
+            .collect();
+        let mut package = Package {
+            file,
+            streams,
+            strings: vec![],
+            long_refs: false,
+        };
+        package.read_strings()?;
+        Ok(package)
+    }
+
+    /// The decoded names of streams that hold cabinets, in order.
+    pub(crate) fn cabinets(&mut self) -> io::Result<Vec<String>> {
+        let mut cabinets = vec![];
+        let mut names: Vec<String> = self.streams.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let mut magic = vec![];
+            self.open_stream(&name)?.take(4).read_to_end(&mut magic)?;
+            if magic == b"MSCF" {
+                cabinets.push(name);
+            }
+        }
+        Ok(cabinets)
+    }
+
+    pub(crate) fn open_stream(&mut self, name: &str) -> io::Result<cfb::Stream<'_, F>> {
+        let path = self
+            .streams
+            .get(name)
+            .ok_or_else(|| invalid(format!("no {} stream", name)))?;
+        self.file.open_stream(path)
+    }
+
+    /// Where each file in the File table is installed, by its key. Paths
+    /// are relative to the install directory, and separated by `/`.
+    pub(crate) fn file_paths(&mut self) -> io::Result<HashMap<String, String>> {
+        let directories = self
+            .table("Directory")?
+            .strings("Directory", &["Directory_Parent", "DefaultDir"]);
+        let components = self
+            .table("Component")?
+            .strings("Component", &["Directory_"]);
+        let files = self
+            .table("File")?
+            .strings("File", &["Component_", "FileName"]);
+
+        let mut paths = HashMap::new();
+        for (key, values) in files {
+            let (component, name) = match (&values[0], &values[1]) {
+                (Some(component), Some(name)) => (component, long_name(name)),
+                _ => return Err(invalid(format!("file {} has no name", key))),
+            };
+            let directory = components
+                .get(component)
+                .and_then(|values| values[0].clone())
+                .ok_or_else(|| invalid(format!("file {} has no directory", key)))?;
+            let mut parts = vec![name.to_string()];
+            let mut current = directory;
+            for step in 0.. {
+                if step == 256 {
+                    return Err(invalid("directories loop"));
+                }
+                if STANDARD_FOLDERS.contains(&current.as_str()) {
+                    break;
+                }
+                let values = directories
+                    .get(&current)
+                    .ok_or_else(|| invalid(format!("directory {} is missing", current)))?;
+                let name = values[1].as_deref().map(target_dir).unwrap_or(".");
+                if name != "." {
+                    parts.push(name.to_string());
+                }
+                match &values[0] {
+                    Some(parent) if *parent != current => current = parent.clone(),
+                    _ => break,
+                }
+            }
+            parts.reverse();
+            paths.insert(key, parts.join("/"));
+        }
+        Ok(paths)
+    }
+
+    fn read_strings(&mut self) -> io::Result<()> {
+        let mut pool = vec![];
+        self.open_stream("!_StringPool")?.read_to_end(&mut pool)?;
+        let mut data = vec![];
+        self.open_stream("!_StringData")?.read_to_end(&mut data)?;
+        if pool.len() < 4 || pool.len() % 4 != 0 {
+            return Err(invalid("string pool is truncated"));
+        }
+        let code_page = u32::from_le_bytes([pool[0], pool[1], pool[2], pool[3]]);
+        self.long_refs = code_page & LONG_STRING_REFS != 0;
+
+        let entries: Vec<(u32, u16)> = pool[4..]
+            .chunks(4)
+            .map(|entry| {
+                (
+                    u32::from(u16::from_le_bytes([entry[0], entry[1]])),
+                    u16::from_le_bytes([entry[2], entry[3]]),
+                )
+            })
+            .collect();
+        // String references start at one, as zero means null.
+        let mut strings = vec![String::new()];
+        let mut offset = 0;
+        let mut i = 0;
+        while i < entries.len() {
+            let (mut length, refs) = entries[i];
+            // Strings of 64k or more keep the high half of their length in
+            // the reference count of an empty entry before them.
+            if length == 0 && refs != 0 {
+                i += 1;
+                let low = entries
+                    .get(i)
+                    .ok_or_else(|| invalid("string pool is truncated"))?
+                    .0;
+                length = u32::from(refs) << 16 | low;
+            }
+            let end = offset + length as usize;
+            let string = data
+                .get(offset..end)
+                .ok_or_else(|| invalid("string data is truncated"))?;
+            strings.push(String::from_utf8_lossy(string).into_owned());
+            offset = end;
+            i += 1;
+        }
+        self.strings = strings;
+        Ok(())
+    }
+
+    fn columns(&mut self, table: &str) -> io::Result<Vec<Column>> {
+        let schema = [
+            Column {
+                name: "Table".to_string(),
+                kind: TYPE_VALID | TYPE_STRING | 64,
+            },
+            Column {
+                name: "Number".to_string(),
+                kind: TYPE_VALID | 2,
+            },
+            Column {
+                name: "Name".to_string(),
+                kind: TYPE_VALID | TYPE_STRING | 64,
+            },
+            Column {
+                name: "Type".to_string(),
+                kind: TYPE_VALID | 2,
+            },
+        ];
+        let mut columns = vec![];
+        for row in self.rows("!_Columns", &schema)? {
+            if row["Table"] != Value::Str(table.to_string()) {
+                continue;
+            }
+            match (&row["Number"], &row["Name"], &row["Type"]) {
+                (Value::Int(number), Value::Str(name), Value::Int(kind)) => {
+                    columns.push((*number, name.clone(), *kind as u16))
+                }
+                _ => return Err(invalid(format!("bad column in {} table", table))),
+            }
+        }
+        columns.sort_by_key(|(number, _, _)| *number);
+        Ok(columns
+            .into_iter()
+            .map(|(_, name, kind)| Column { name, kind })
+            .collect())
+    }
+
+    fn table(&mut self, name: &str) -> io::Result<Table> {
+        let columns = self.columns(name)?;
+        if columns.is_empty() {
+            return Err(invalid(format!("no {} table", name)));
+        }
+        Ok(Table {
+            rows: self.rows(&format!("!{}", name), &columns)?,
+        })
+    }
+
+    /// Reads a table stream, which has each column for every row in turn.
+    fn rows(
+        &mut self,
+        stream: &str,
+        columns: &[Column],
+    ) -> io::Result<Vec<HashMap<String, Value>>> {
+        let mut data = vec![];
+        if self.streams.contains_key(stream) {
+            self.open_stream(stream)?.read_to_end(&mut data)?;
+        }
+        let widths: Vec<usize> = columns
+            .iter()
+            .map(|column| self.width(column.kind))
+            .collect();
+        let row_size: usize = widths.iter().sum();
+        if row_size == 0 || data.len() % row_size != 0 {
+            return Err(invalid(format!(
+                "{} has a partial row",
+                decode_name(stream)
+            )));
+        }
+        let count = data.len() / row_size;
+        let mut rows = vec![HashMap::new(); count];
+        let mut at = 0;
+        for (column, width) in columns.iter().zip(widths) {
+            for row in rows.iter_mut() {
+                let bytes = &data[at..at + width];
+                at += width;
+                let raw = bytes
+                    .iter()
+                    .rev()
+                    .fold(0u32, |value, byte| value << 8 | u32::from(*byte));
+                let value = if is_binary(column.kind) {
+                    Value::Null
+                } else if is_string(column.kind) {
+                    match raw {
+                        0 => Value::Null,
+                        index => Value::Str(
+                            self.strings
+                                .get(index as usize)
+                                .ok_or_else(|| invalid("string reference is out of range"))?
+                                .clone(),
+                        ),
+                    }
+                } else if raw == 0 {
+                    Value::Null
+                } else if width == 2 {
+                    // Integers are stored with their top bit flipped.
+                    Value::Int(i32::from((raw ^ 0x8000) as u16 as i16))
+                } else {
+                    Value::Int((raw ^ 0x8000_0000) as i32)
+                };
+                row.insert(column.name.clone(), value);
+            }
+        }
+        Ok(rows)
+    }
+
+    fn width(&self, kind: u16) -> usize {
+        if is_binary(kind) {
+            2
+        } else if is_string(kind) {
+            if self.long_refs {
+                3
+            } else {
+                2
+            }
+        } else if kind & TYPE_WIDTH == 4 {
+            4
+        } else {
+            2
+        }
+    }
+}
+
+fn is_string(kind: u16) -> bool {
+    kind & TYPE_STRING != 0
+}
+
+/// Binary columns refer to a stream, rather than holding a string.
+fn is_binary(kind: u16) -> bool {
+    kind & !TYPE_NULLABLE == TYPE_STRING | TYPE_VALID
+}
+
+/// File names can be given as `short|long`.
+fn long_name(name: &str) -> &str {
+    name.rsplit('|').next().unwrap_or(name)
+}
+
+/// Directory names can be given as `target:source`, and each of those as
+/// `short|long`.
+fn target_dir(name: &str) -> &str {
+    long_name(name.split(':').next().unwrap_or(name))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    /// Packs a stream name the way Windows Installer does, so that it fits
+    /// in the 31 characters a compound file allows.
+    pub(crate) fn encode_name(name: &str, table: bool) -> String {
+        let index = |c: u8| PACKED_CHARS.iter().position(|packed| *packed == c);
+        let mut encoded = String::new();
+        if table {
+            encoded.extend(std::char::from_u32(TABLE_PREFIX));
+        }
+        let bytes = name.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match (index(bytes[i]), bytes.get(i + 1).and_then(|c| index(*c))) {
+                (Some(first), Some(second)) => {
+                    let packed = PACKED_TWO + first as u32 + ((second as u32) << 6);
+                    encoded.extend(std::char::from_u32(packed));
+                    i += 2;
+                }
+                (Some(first), None) => {
+                    encoded.extend(std::char::from_u32(PACKED_ONE + first as u32));
+                    i += 1;
+                }
+                (None, _) => {
+                    encoded.push(char::from(bytes[i]));
+                    i += 1;
+                }
+            }
+        }
+        encoded
+    }
+
+    /// A string, a two byte integer or null in a fixture table.
+    #[derive(Clone, Copy)]
+    enum Cell<'a> {
+        S(&'a str),
+        I(i16),
+        Null,
+    }
+
+    /// A table, with whether each column holds strings.
+    #[derive(Clone)]
+    struct FixtureTable<'a> {
+        name: &'a str,
+        columns: Vec<(&'a str, bool)>,
+        rows: Vec<Vec<Cell<'a>>>,
+    }
+
+    /// Builds tables and their string pool.
+    struct Tables<'a> {
+        strings: Vec<String>,
+        tables: Vec<FixtureTable<'a>>,
+    }
+
+    impl<'a> Tables<'a> {
+        fn string(&mut self, value: &str) -> u16 {
+            match self.strings.iter().position(|string| string == value) {
+                Some(index) => index as u16 + 1,
+                None => {
+                    self.strings.push(value.to_string());
+                    self.strings.len() as u16
+                }
+            }
+        }
+
+        fn stream(&mut self, columns: &[(&str, bool)], rows: &[Vec<Cell>]) -> Vec<u8> {
+            let mut data = vec![];
+            for (i, _) in columns.iter().enumerate() {
+                for row in rows {
+                    let raw = match row[i] {
+                        Cell::S(value) => self.string(value),
+                        Cell::I(value) => (value as u16) ^ 0x8000,
+                        Cell::Null => 0,
+                    };
+                    data.extend_from_slice(&raw.to_le_bytes());
+                }
+            }
+            data
+        }
+    }
+
+    /// Builds an MSI package with the tables needed to place the files in
+    /// `files`, given as a key, directory and file name, and `cabinet` as an
+    /// embedded stream. Directories are given as key, parent and name.
+    pub(crate) fn package(
+        directories: &[(&str, Option<&str>, &str)],
+        files: &[(&str, &str, &str)],
+        cabinet: &[u8],
+    ) -> Vec<u8> {
+        let mut tables = Tables {
+            strings: vec![],
+            tables: vec![],
+        };
+        tables.tables.push(FixtureTable {
+            name: "Directory",
+            columns: vec![
+                ("Directory", true),
+                ("Directory_Parent", true),
+                ("DefaultDir", true),
+            ],
+            rows: directories
+                .iter()
+                .map(|(key, parent, name)| {
+                    vec![
+                        Cell::S(key),
+                        parent.map_or(Cell::Null, Cell::S),
+                        Cell::S(name),
+                    ]
+                })
+                .collect(),
+        });
+        tables.tables.push(FixtureTable {
+            name: "Component",
+            columns: vec![
+                ("Component", true),
+                ("Directory_", true),
+                ("Attributes", false),
+            ],
+            rows: files
+                .iter()
+                .map(|(key, directory, _)| vec![Cell::S(key), Cell::S(directory), Cell::I(0)])
+                .collect(),
+        });
+        tables.tables.push(FixtureTable {
+            name: "File",
+            columns: vec![
+                ("File", true),
+                ("Component_", true),
+                ("FileName", true),
+                ("Sequence", false),
+            ],
+            rows: files
+                .iter()
+                .enumerate()
+                .map(|(i, (key, _, name))| {
+                    vec![
+                        Cell::S(key),
+                        Cell::S(key),
+                        Cell::S(name),
+                        Cell::I(i as i16 + 1),
+                    ]
+                })
+                .collect(),
+        });
+
+        let mut columns = vec![];
+        for table in &tables.tables {
+            for (number, (name, string)) in table.columns.iter().enumerate() {
+                let kind = if *string {
+                    TYPE_VALID | TYPE_STRING | TYPE_NULLABLE | 72
+                } else {
+                    TYPE_VALID | 2
+                };
+                columns.push(vec![
+                    Cell::S(table.name),
+                    Cell::I(number as i16 + 1),
+                    Cell::S(name),
+                    Cell::I(kind as i16),
+                ]);
+            }
+        }
+        let mut streams = vec![];
+        let columns_stream = tables.stream(
+            &[
+                ("Table", true),
+                ("Number", false),
+                ("Name", true),
+                ("Type", false),
+            ],
+            &columns,
+        );
+        streams.push(("_Columns".to_string(), true, columns_stream));
+        for table in tables.tables.clone() {
+            let stream = tables.stream(&table.columns, &table.rows);
+            streams.push((table.name.to_string(), true, stream));
+        }
+
+        let mut pool = 65001u32.to_le_bytes().to_vec();
+        let mut data = vec![];
+        for string in &tables.strings {
+            pool.extend_from_slice(&(string.len() as u16).to_le_bytes());
+            pool.extend_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(string.as_bytes());
+        }
+        streams.push(("_StringPool".to_string(), true, pool));
+        streams.push(("_StringData".to_string(), true, data));
+        streams.push(("Data1.cab".to_string(), false, cabinet.to_vec()));
+
+        let mut file = CompoundFile::create(Cursor::new(vec![])).unwrap();
+        for (name, table, data) in streams {
+            let path = format!("/{}", encode_name(&name, table));
+            file.create_stream(&path).unwrap().write_all(&data).unwrap();
+        }
+        file.flush().unwrap();
+        file.into_inner().into_inner()
+    }
+
+    #[test]
+    fn stream_names_are_unpacked() {
+        assert_eq!(
+            decode_name(&encode_name("_StringPool", true)),
+            "!_StringPool"
+        );
+        assert_eq!(decode_name(&encode_name("Data1.cab", false)), "Data1.cab");
+        assert_eq!(
+            decode_name("\u{5}SummaryInformation"),
+            "\u{5}SummaryInformation"
+        );
+    }
+
+    #[test]
+    fn files_are_placed_in_their_directories() {
+        let msi = package(
+            &[
+                ("TARGETDIR", None, "SourceDir"),
+                ("ProgramFilesFolder", Some("TARGETDIR"), "."),
+                ("Vendor", Some("ProgramFilesFolder"), "Google"),
+                ("INSTALLDIR", Some("Vendor"), "CHROME~1|Chrome:Chrome"),
+                ("Here", Some("INSTALLDIR"), "."),
+            ],
+            &[
+                ("chrome.exe.1", "INSTALLDIR", "chrome.exe"),
+                ("VERSION", "Here", "VERSION~1|VERSION.txt"),
+            ],
+            b"MSCF",
+        );
+        let mut package = Package::open(Cursor::new(msi)).unwrap();
+        let paths = package.file_paths().unwrap();
+        assert_eq!(paths["chrome.exe.1"], "Google/Chrome/chrome.exe");
+        assert_eq!(paths["VERSION"], "Google/Chrome/VERSION.txt");
+        assert_eq!(package.cabinets().unwrap(), vec!["Data1.cab".to_string()]);
+    }
+
+    #[test]
+    fn broken_packages_are_refused() {
+        assert!(Package::open(Cursor::new(vec![0xd0, 0xcf, 0x11, 0xe0])).is_err());
+
+        let mut file = CompoundFile::create(Cursor::new(vec![])).unwrap();
+        file.create_stream("/Data1.cab").unwrap();
+        let error = Package::open(Cursor::new(file.into_inner().into_inner()))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::browser::{
+    install_target, latest_driver_url, latest_key, parse_version_text, refuse_offline,
+    version_from_release, Browser, DownloadLinks,
+};
+use crate::http::{
+    check_length, check_status, client_settings, github_auth, interrupted, request_failure,
+    resume_offset, status_failure, Failure, HttpError, PartialDownload, RetryPolicy, USER_AGENT,
+};
+use crate::lock::InstallLock;
+use crate::metadata::MetadataCache;
+use crate::options::Options;
+use crate::progress::{Phase, Progress};
+
+use reqwest::header::{AUTHORIZATION, IF_RANGE, RANGE};
+use reqwest::{Client, Response};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{sleep, timeout};
+
+/// The async twin of `Http`. It retries and
+/// resumes the same way, but waits on the runtime instead of blocking it.
+pub(crate) struct AsyncHttp {
+    client: Client,
+    retry: RetryPolicy,
+    read_timeout: Duration,
+    github_token: Option<String>,
+}
+
+impl AsyncHttp {
+    pub(crate) fn new(options: &Options) -> Result<AsyncHttp, HttpError> {
+        let settings = client_settings(options)?;
+        let mut builder = Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(options.connect_timeout);
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        for cert in settings.certificates {
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| HttpError::Config(format!("Could not create the HTTP client: {}", e)))?;
+        Ok(AsyncHttp {
+            client,
+            retry: RetryPolicy::new(options),
+            read_timeout: options.read_timeout,
+            github_token: options.github_token.to_owned(),
+        })
+    }
+
+    /// Downloads the whole body of `url`, retrying if it is cut short.
+    pub(crate) async fn get_bytes(&self, url: &str) -> Result<Vec<u8>, HttpError> {
+        Ok(self.get_body(url).await?.1)
+    }
+
+    /// Like [`get_bytes`](#method.get_bytes), along with the URL the body
+    /// came from once any redirects were followed.
+    pub(crate) async fn get_body(&self, url: &str) -> Result<(String, Vec<u8>), HttpError> {
+        let mut attempt = 0;
+        loop {
+            let failure = match self.send_from(url, &None).await {
+                Ok(mut response) if response.status().is_success() => {
+                    let mut data = vec![];
+                    loop {
+                        match self.within(url, response.chunk()).await {
+                            Ok(Some(chunk)) => data.extend_from_slice(&chunk),
+                            Ok(None) => return Ok((response.url().to_string(), data)),
+                            Err(failure) => break failure,
+                        }
+                    }
+                }
+                Ok(response) => status_failure(url, response.status(), response.headers()),
+                Err(failure) => failure,
+            };
+            sleep(self.retry.delay(failure, attempt)?).await;
+            attempt += 1;
+        }
+    }
+
+    /// Streams `url` into `path` through a `.part` file, resuming it after
+    /// failures, like `Http::download`.
+    pub(crate) async fn download(
+        &self,
+        url: &str,
+        path: &Path,
+        on_bytes: &(dyn Fn(u64, Option<u64>) + Sync),
+    ) -> Result<(), HttpError> {
+        let partial = PartialDownload::new(path);
+        let mut attempt = 0;
+        loop {
+            let before = partial.size();
+            let failure = match self.download_part(url, &partial, on_bytes).await {
+                Ok(()) => break,
+                Err(failure) => failure,
+            };
+            if partial.size() > before {
+                attempt = 0;
+            }
+            sleep(self.retry.delay(failure, attempt)?).await;
+            attempt += 1;
+        }
+        partial.finish(path)
+    }
+
+    async fn download_part(
+        &self,
+        url: &str,
+        partial: &PartialDownload,
+        on_bytes: &(dyn Fn(u64, Option<u64>) + Sync),
+    ) -> Result<(), Failure> {
+        let resume = partial.resume_point();
+        let mut response = self.send_from(url, &resume).await?;
+        let offset = match resume_offset(&resume, response.status(), response.headers()) {
+            Some(offset) => offset,
+            None => {
+                partial.restart();
+                response = self.send_from(url, &None).await?;
+                0
+            }
+        };
+        check_status(url, response.status(), response.headers(), offset)?;
+
+        let file = partial.open(response.headers(), offset)?;
+        let mut file = tokio::fs::File::from_std(file);
+        let expected = response.content_length();
+        let total = expected.map(|expected| expected + offset);
+        let mut received = 0;
+        on_bytes(offset, total);
+        while let Some(chunk) = self.within(url, response.chunk()).await? {
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| partial.io_failure(e))?;
+            received += chunk.len() as u64;
+            on_bytes(offset + received, total);
+        }
+        check_length(url, expected, received)?;
+        file.sync_all().await.map_err(|e| partial.io_failure(e))
+    }
+
+    async fn send_from(
+        &self,
+        url: &str,
+        resume: &Option<(u64, String)>,
+    ) -> Result<Response, Failure> {
+        let mut request = self.client.get(url);
+        if let Some(auth) = github_auth(&self.github_token, url) {
+            request = request.header(AUTHORIZATION, auth);
+        }
+        if let Some((length, tag)) = resume {
+            request = request
+                .header(RANGE, format!("bytes={}-", length))
+                .header(IF_RANGE, tag.as_str());
+        }
+        self.within(url, request.send()).await
+    }
+
+    /// Waits for `future` for up to the read timeout. The async client only
+    /// has an overall timeout, which large downloads would run into.
+    async fn within<T>(
+        &self,
+        url: &str,
+        future: impl Future<Output = reqwest::Result<T>>,
+    ) -> Result<T, Failure> {
+        match timeout(self.read_timeout, future).await {
+            Ok(result) => result.map_err(|source| request_failure(url, source)),
+            Err(_) => Err(interrupted(
+                url,
+                io::Error::new(io::ErrorKind::TimedOut, "the server stopped responding"),
+            )),
+        }
+    }
+}
+
+impl Browser {
+    /// Async version of [`download`](#method.download), for use inside a
+    /// tokio runtime where the blocking client would panic.
+    pub async fn download_async(&self) -> Result<Browser, Box<dyn Error + Send + Sync>> {
+        self.download_with_async(&Options::load()?).await
+    }
+
+    /// Async version of [`download_with`](#method.download_with).
+    pub async fn download_with_async(
+        &self,
+        options: &Options,
+    ) -> Result<Browser, Box<dyn Error + Send + Sync>> {
+        self.download_with_progress_async(options, &|_| {}).await
+    }
+
+    /// Async version of [`download_with_progress`](#method.download_with_progress).
+    pub async fn download_with_progress_async(
+        &self,
+        options: &Options,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<Browser, Box<dyn Error + Send + Sync>> {
+        on_progress(&Progress::new(
+            &self.name,
+            &self.name,
+            Phase::Resolving,
+            0,
+            None,
+        ));
+        let http = AsyncHttp::new(options)?;
+        let links = self.get_download_urls_async(&http, options).await?;
+        if let Some(browser_download_path) = self.browser_download_path() {
+            let _lock = lock(&browser_download_path).await?;
+            let downloaded = match self.downloaded_browser(&browser_download_path) {
+                Some(downloaded) => downloaded,
+                None => {
+                    self.fetch(
+                        &http,
+                        options,
+                        &links.browser_url,
+                        &browser_download_path,
+                        on_progress,
+                    )
+                    .await?;
+                    self.name_browser_download(&browser_download_path)?
+                }
+            };
+            let (browser, options) = (self.clone(), options.clone());
+            unpack(on_progress, move |progress| {
+                browser.set_up_browser(&downloaded, &options, progress)
+            })
+            .await?;
+        }
+
+        let driver_dir = self.driver_dir(options)?;
+        let archive = self.driver_archive(&driver_dir, &links);
+        let _lock = lock(&install_target(&driver_dir, &self.name, true)).await?;
+        if self.needs_download(&archive) {
+            self.fetch(&http, options, &links.driver_url, &archive, on_progress)
+                .await?;
+        }
+        let (browser, options) = (self.clone(), options.clone());
+        unpack(on_progress, move |progress| {
+            browser.set_up_driver(&archive, &driver_dir, links, &options, progress)
+        })
+        .await
+    }
+
+    /// Async version of [`download_urls`](#method.download_urls).
+    pub async fn download_urls_async(
+        &self,
+        options: &Options,
+    ) -> Result<DownloadLinks, Box<dyn Error + Send + Sync>> {
+        self.get_download_urls_async(&AsyncHttp::new(options)?, options)
+            .await
+    }
+
+    async fn get_download_urls_async(
+        &self,
+        http: &AsyncHttp,
+        options: &Options,
+    ) -> Result<DownloadLinks, Box<dyn Error + Send + Sync>> {
+        let mirrors = options.mirrors_for(&self.name);
+        let url = latest_driver_url(&self.name, &mirrors);
+        let cache = MetadataCache::new(options)?;
+        let key = latest_key(url);
+        let driver_version = match cache.cached(&key, options.latest_ttl)? {
+            Some(version) => version,
+            None => {
+                let version = if self.name == "firefox" {
+                    let (release_url, body) = http.get_body(url).await?;
+                    version_from_release(&release_url, &body)
+                } else {
+                    parse_version_text(&http.get_bytes(url).await?)
+                };
+                // Storing it waits on the lock for the cache file
+                let stored = version.clone();
+                task::spawn_blocking(move || cache.store(&key, &stored)).await??;
+                version
+            }
+        };
+        Ok(self.links_for(&driver_version, &mirrors)?)
+    }
+
+    async fn fetch(
+        &self,
+        http: &AsyncHttp,
+        options: &Options,
+        url: &str,
+        path: &Path,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        refuse_offline(options, url)?;
+        let item = path.file_name().unwrap_or_default().to_string_lossy();
+        let report =
+            |phase, done, total| on_progress(&Progress::new(&self.name, &item, phase, done, total));
+        http.download(url, path, &|done, total| {
+            report(Phase::Downloading, done, total)
+        })
+        .await?;
+        let size = path.metadata().map(|meta| meta.len()).unwrap_or(0);
+        report(Phase::Verifying, size, Some(size));
+        Ok(())
+    }
+}
+
+/// Takes the `InstallLock` for `target` without blocking the runtime while
+/// another process holds it.
+async fn lock(target: &Path) -> io::Result<InstallLock> {
+    let target = target.to_path_buf();
+    task::spawn_blocking(move || InstallLock::acquire(&target)).await?
+}
+
+/// Unpacking is blocking work, so `work` runs on its own thread and sends
+/// its progress back to `on_progress`.
+async fn unpack<F, T>(
+    on_progress: &(dyn Fn(&Progress) + Sync),
+    work: F,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    F: FnOnce(&(dyn Fn(&Progress) + Sync)) -> Result<T, Box<dyn Error + Send + Sync>>
+        + Send
+        + 'static,
+    T: Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let unpacking = task::spawn_blocking(move || {
+        work(&move |progress: &Progress| {
+            let _ = sender.send(progress.clone());
+        })
+    });
+    while let Some(progress) = receiver.recv().await {
+        on_progress(&progress);
+    }
+    unpacking.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser::Mirrors;
+    use crate::test_server::{Reply, TestServer};
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    fn http(retries: u32) -> AsyncHttp {
+        AsyncHttp::new(&Options {
+            retries,
+            retry_backoff: Duration::from_millis(1),
+            ..Options::default()
+        })
+        .unwrap()
+    }
+
+    fn driver_zip() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("geckodriver.exe", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"driver").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let server = TestServer::sequence(vec![Reply::status(503), Reply::ok(b"done")]);
+        assert_eq!(http(1).get_bytes(&server.url).await.unwrap(), b"done");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn interrupted_downloads_are_resumed() {
+        let body = b"0123456789abcdefghij";
+        let tag = vec![("ETag".to_string(), "\"v1\"".to_string())];
+        let mut resumed = tag.clone();
+        resumed.push(("Content-Range".to_string(), "bytes 8-19/20".to_string()));
+        let server = TestServer::sequence(vec![
+            Reply::Truncated(200, tag, body[..8].to_vec(), body.len()),
+            Reply::Response(206, resumed, body[8..].to_vec()),
+        ]);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.zip");
+
+        http(1)
+            .download(&server.url, &path, &|_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), body);
+        assert_eq!(server.requests()[1].header("range"), Some("bytes=8-"));
+    }
+
+    #[tokio::test]
+    async fn browsers_can_be_downloaded_inside_a_runtime() {
+        let archive = driver_zip();
+        let server = TestServer::new(move |request| match request.path.as_str() {
+            "/releases/latest" => Reply::redirect("/releases/tag/v0.29.1"),
+            _ => Reply::ok(&archive),
+        });
+        let dir = TempDir::new().unwrap();
+        let options = Options {
+            retries: 0,
+            mirrors: Mirrors {
+                geckodriver: format!("{}/download/", server.url),
+                geckodriver_latest: format!("{}/releases/latest", server.url),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(dir.path().join("cache"))
+        };
+        let firefox = Browser::new(
+            "firefox".to_string(),
+            dir.path().display().to_string(),
+            dir.path().join("firefox").display().to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("windows", "x86_64");
+
+        let phases = Mutex::new(vec![]);
+        let installed = firefox
+            .download_with_progress_async(&options, &|progress| {
+                phases.lock().unwrap().push(progress.phase)
+            })
+            .await
+            .unwrap();
+        let mut phases = phases.into_inner().unwrap();
+        phases.dedup();
+        assert_eq!(
+            phases,
+            vec![
+                Phase::Resolving,
+                Phase::Downloading,
+                Phase::Verifying,
+                Phase::Extracting
+            ]
+        );
+        assert_eq!(installed.driver_path, dir.path().display().to_string());
+        assert!(dir.path().join("geckodriver.exe").exists());
+    }
+
+    #[tokio::test]
+    async fn download_urls_are_resolved() {
+        let server = TestServer::sequence(vec![Reply::ok(b"91.0.4472.101\n")]);
+        let cache = TempDir::new().unwrap();
+        let options = Options {
+            mirrors: Mirrors {
+                chromedriver_latest: server.url.to_owned(),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(cache.path())
+        };
+        let chrome = Browser::new(
+            "chrome".to_string(),
+            "".to_string(),
+            "".to_string(),
+            "latest".to_string(),
+        )
+        .with_platform("linux", "x86_64");
+
+        let links = chrome.download_urls_async(&options).await.unwrap();
+        assert_eq!(
+            links.driver_url,
+            chrome
+                .links_for("91.0.4472.101", &options.mirrors)
+                .unwrap()
+                .driver_url
+        );
+        // The second lookup comes from the metadata cache
+        chrome.download_urls_async(&options).await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn browsers_can_be_found() {
+        // Whatever is installed here, looking must not panic inside a runtime
+        let _ = crate::find_browser_for_async("firefox".to_string()).await;
+    }
+}
@@ -0,0 +1,290 @@
+use crate::browser::Mirrors;
+use crate::config::Config;
+
+use directories::ProjectDirs;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Environmental variable that overrides where browsers and drivers are installed.
+pub const HOME_ENV: &str = "BROWSER_MANAGER_HOME";
+
+/// Name of the config file that is looked up in the
+/// [`get_config_dir`](fn.get_config_dir.html) and the current directory.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// The settings that control where and how browsers and drivers are set up.
+/// These are normally created from a [`Config`](struct.Config.html).
+///
+/// # Example
+/// ```
+/// use browser_manager::Options;
+/// let options = Options::load()
+///     .unwrap_or_default()
+///     .with_cache_path("/var/cache/browsers");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The browser to use when one isn't asked for. Several can be listed,
+    /// separated by commas.
+    pub browser: Option<String>,
+    /// The version to use when the browser doesn't include one.
+    pub version: String,
+    /// The root directory downloads are placed in. When this is `None` the
+    /// platform cache directory is used.
+    pub cache_path: Option<PathBuf>,
+    /// The proxy to send requests through. When this is `None` the
+    /// `HTTP_PROXY` and `HTTPS_PROXY` environmental variables are used.
+    pub proxy: Option<String>,
+    /// User name for the proxy, if it isn't in the proxy URL.
+    pub proxy_user: Option<String>,
+    /// Password for the proxy user.
+    pub proxy_password: Option<String>,
+    /// Comma separated hosts that shouldn't go through `proxy`. Defaults to
+    /// the `NO_PROXY` environmental variable.
+    pub no_proxy: Option<String>,
+    /// PEM file of extra root certificates to trust, like the one for a TLS
+    /// inspecting proxy.
+    pub ca_bundle: Option<PathBuf>,
+    /// Client certificate for mutual TLS. This is a PEM certificate when
+    /// `client_key` is set, otherwise a PKCS #12 archive.
+    pub client_cert: Option<PathBuf>,
+    /// PKCS #8 PEM private key for `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Password for a PKCS #12 `client_cert`.
+    pub client_cert_password: Option<String>,
+    /// Token sent with requests to GitHub, which allows many more of them
+    /// than anonymous requests. Defaults to the `GITHUB_TOKEN` environmental
+    /// variable.
+    pub github_token: Option<String>,
+    /// How long to wait for a connection to a download server.
+    pub connect_timeout: Duration,
+    /// How long to wait for a download server to respond.
+    pub read_timeout: Duration,
+    /// How many times a failed request is retried.
+    pub retries: u32,
+    /// How long to wait before the first retry. Each later retry waits about
+    /// twice as long as the one before.
+    pub retry_backoff: Duration,
+    /// How long looked up metadata is trusted for, unless it has a TTL of its
+    /// own below.
+    pub metadata_ttl: Duration,
+    /// How long the latest version of each driver is trusted for.
+    pub latest_ttl: Duration,
+    /// How long the lists of versions that can be installed are trusted for.
+    pub versions_ttl: Duration,
+    /// Look metadata up again even when it hasn't expired.
+    pub refresh: bool,
+    /// Never touch the network, only use what is already installed.
+    pub offline: bool,
+    /// Keep downloaded archives once they have been unpacked and the driver
+    /// checked, so they can be unpacked again without downloading them.
+    /// Browsers that are used as they were downloaded are always kept.
+    pub keep_archives: bool,
+    /// How many browsers [`install_all`](fn.install_all.html) sets up at once.
+    pub parallelism: usize,
+    /// The operating system to set browsers up for, like `windows`. When this
+    /// is `None` it is the one this is running on.
+    pub os: Option<String>,
+    /// The architecture to set browsers up for, like `aarch64`. When this is
+    /// `None` it is the one this is running on.
+    pub arch: Option<String>,
+    /// Base URL for the selected browser's download, overriding its mirror.
+    pub browser_mirror: Option<String>,
+    /// Base URL for the selected browser's driver, overriding its mirror.
+    pub driver_mirror: Option<String>,
+    /// The base URLs that browsers and drivers are downloaded from.
+    pub mirrors: Mirrors,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Config::default()
+            .options()
+            .expect("The default settings should always be valid")
+    }
+}
+
+impl Options {
+    /// Loads the options from the config files and the environment. See
+    /// [`Config`](struct.Config.html) for the order they are applied in.
+    pub fn load() -> Result<Self, Error> {
+        Config::load()?.options()
+    }
+
+    /// Sets the root directory that downloads are placed in. This is what the
+    /// `--cache-path` command line flag maps to.
+    pub fn with_cache_path<P: Into<PathBuf>>(mut self, cache_path: P) -> Self {
+        self.cache_path = Some(cache_path.into());
+        self
+    }
+
+    /// Splits `browser` into the names of each browser asked for.
+    pub fn browsers(&self) -> Vec<String> {
+        self.browser
+            .iter()
+            .flat_map(|browsers| browsers.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Gets the mirrors to use for `browser`, with the `browser_mirror` and
+    /// `driver_mirror` overrides applied to that browser and its driver.
+    pub fn mirrors_for(&self, browser: &str) -> Mirrors {
+        let mut mirrors = self.mirrors.clone();
+        let (browser_url, driver_url) = match browser {
+            "firefox" => (&mut mirrors.firefox, &mut mirrors.geckodriver),
+            "chrome" => (&mut mirrors.chrome, &mut mirrors.chromedriver),
+            _ => (&mut mirrors.edge, &mut mirrors.edgedriver),
+        };
+        if let Some(mirror) = &self.browser_mirror {
+            *browser_url = mirror.to_owned();
+        }
+        if let Some(mirror) = &self.driver_mirror {
+            *driver_url = mirror.to_owned();
+            // New chromedrivers come from Chrome for Testing, so that is
+            // mirrored as well
+            if browser == "chrome" {
+                mirrors.chrome_for_testing = mirror.to_owned();
+            }
+        }
+        mirrors
+    }
+
+    /// The operating system and architecture browsers are set up for, with
+    /// the `os` and `arch` overrides applied.
+    pub fn platform(&self) -> (&str, &str) {
+        (
+            self.os.as_deref().unwrap_or(env::consts::OS),
+            self.arch.as_deref().unwrap_or(env::consts::ARCH),
+        )
+    }
+
+    /// Whether browsers are being set up for a platform other than the one
+    /// this is running on.
+    pub fn is_cross_platform(&self) -> bool {
+        self.platform() != (env::consts::OS, env::consts::ARCH)
+    }
+
+    /// The directory browsers for the [`platform`](#method.platform) are
+    /// installed in. This is the [`project_dir`](#method.project_dir), except
+    /// for other platforms which each get a `platforms/{os}-{arch}` directory
+    /// in it so that their downloads aren't mixed up with this machine's.
+    pub fn platform_dir(&self) -> Result<PathBuf, Error> {
+        let dir = self.project_dir()?;
+        if !self.is_cross_platform() {
+            return Ok(dir);
+        }
+        let (os, arch) = self.platform();
+        let dir = dir.join("platforms").join(format!("{}-{}", os, arch));
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Finds the directory that browsers and drivers are installed in and
+    /// creates it if it doesn't exist yet. Unless it has been configured, this
+    /// is the platform cache directory.
+    ///
+    /// |Platform | Value                                | Example                                              |
+    /// | ------- | ------------------------------------ | ---------------------------------------------------- |
+    /// | Linux   | `$XDG_CACHE_HOME` or `$HOME`/.cache  | /home/alice/.cache/browser-manager                   |
+    /// | macOS   | `$HOME`/Library/Caches               | /Users/Alice/Library/Caches/org.webdriver.browser-manager |
+    /// | Windows | `{FOLDERID_LocalAppData}`            | C:\Users\Alice\AppData\Local\webdriver\browser-manager\cache |
+    pub fn project_dir(&self) -> Result<PathBuf, Error> {
+        let dir = match &self.cache_path {
+            Some(path) => path.to_owned(),
+            None => match ProjectDirs::from("org", "webdriver", "browser-manager") {
+                Some(proj_dirs) => proj_dirs.cache_dir().to_path_buf(),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        "Could not look up project directory",
+                    ))
+                }
+            },
+        };
+
+        if !dir.is_dir() {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn defaults_match_the_config_defaults() {
+        let options = Options::default();
+        assert_eq!(options.browser, None);
+        assert_eq!(options.version, "latest".to_string());
+        assert_eq!(options.cache_path, None);
+        assert_eq!(options.connect_timeout, Duration::from_secs(30));
+        assert_eq!(options.mirrors, Mirrors::default());
+    }
+
+    #[test]
+    fn mirror_overrides_only_apply_to_the_selected_browser() {
+        let options = Options {
+            driver_mirror: Some("https://artifactory.example/drivers/".to_string()),
+            ..Options::default()
+        };
+
+        let mirrors = options.mirrors_for("firefox");
+        assert_eq!(mirrors.geckodriver, "https://artifactory.example/drivers/");
+        assert_eq!(mirrors.chromedriver, Mirrors::default().chromedriver);
+        assert_eq!(mirrors.firefox, Mirrors::default().firefox);
+    }
+
+    #[test]
+    fn several_browsers_can_be_listed() {
+        let options = Options {
+            browser: Some("firefox, chrome@91,,edge".to_string()),
+            ..Options::default()
+        };
+        assert_eq!(options.browsers(), vec!["firefox", "chrome@91", "edge"]);
+        assert!(Options::default().browsers().is_empty());
+    }
+
+    #[test]
+    fn project_dir_is_created_for_cache_path() {
+        let dir = TempDir::new().unwrap();
+        let cache = dir.path().join("nested").join("cache");
+
+        let options = Options::default().with_cache_path(&cache);
+        assert_eq!(options.project_dir().unwrap(), cache);
+        assert!(cache.is_dir());
+    }
+
+    #[test]
+    fn other_platforms_get_their_own_directory() {
+        let dir = TempDir::new().unwrap();
+        let options = Options::default().with_cache_path(dir.path());
+        assert!(!options.is_cross_platform());
+        assert_eq!(options.platform_dir().unwrap(), dir.path());
+
+        let os = if env::consts::OS == "windows" {
+            "linux"
+        } else {
+            "windows"
+        };
+        let options = Options {
+            os: Some(os.to_string()),
+            ..options
+        };
+        assert!(options.is_cross_platform());
+        let expected = dir
+            .path()
+            .join("platforms")
+            .join(format!("{}-{}", os, env::consts::ARCH));
+        assert_eq!(options.platform_dir().unwrap(), expected);
+        assert!(expected.is_dir());
+    }
+}
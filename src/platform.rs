@@ -0,0 +1,470 @@
+use std::error::Error;
+use std::fmt;
+
+/// The operating systems browsers can be set up for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Os {
+    Linux,
+    MacOs,
+    Windows,
+}
+
+impl Os {
+    pub const ALL: [Os; 3] = [Os::Linux, Os::MacOs, Os::Windows];
+
+    /// Reads the name `std::env::consts::OS` uses, or a common alias like
+    /// `mac` or `win`.
+    pub fn from_name(name: &str) -> Option<Os> {
+        match name.to_lowercase().as_str() {
+            "linux" => Some(Os::Linux),
+            "macos" | "mac" | "darwin" | "osx" => Some(Os::MacOs),
+            "windows" | "win" => Some(Os::Windows),
+            _ => None,
+        }
+    }
+
+    /// The name `std::env::consts::OS` uses for this OS.
+    pub fn name(self) -> &'static str {
+        match self {
+            Os::Linux => "linux",
+            Os::MacOs => "macos",
+            Os::Windows => "windows",
+        }
+    }
+}
+
+impl fmt::Display for Os {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The processor architectures browsers can be set up for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arch {
+    X86,
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    pub const ALL: [Arch; 3] = [Arch::X86, Arch::X86_64, Arch::Aarch64];
+
+    /// Reads the name `std::env::consts::ARCH` uses, or a common alias like
+    /// `amd64` or `arm64`.
+    pub fn from_name(name: &str) -> Option<Arch> {
+        match name.to_lowercase().as_str() {
+            "x86" | "i386" | "i686" => Some(Arch::X86),
+            "x86_64" | "amd64" | "x64" => Some(Arch::X86_64),
+            "aarch64" | "arm64" => Some(Arch::Aarch64),
+            _ => None,
+        }
+    }
+
+    /// The name `std::env::consts::ARCH` uses for this architecture.
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86 => "x86",
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Where browsers and drivers are downloaded from. Each names the download
+/// for a platform in its own way, see
+/// [`Platform::asset_name`](struct.Platform.html#method.asset_name).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Provider {
+    /// Firefox from download.mozilla.org, named by its `os` parameter.
+    Firefox,
+    /// The geckodriver releases on GitHub.
+    Geckodriver,
+    /// The Chrome enterprise bundles, named by their `platform` parameter.
+    Chrome,
+    /// chromedriver, from chromedriver.storage.googleapis.com up to 114 and
+    /// from Chrome for Testing since.
+    Chromedriver,
+    /// msedgedriver from msedgedriver.azureedge.net.
+    Edgedriver,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 5] = [
+        Provider::Firefox,
+        Provider::Geckodriver,
+        Provider::Chrome,
+        Provider::Chromedriver,
+        Provider::Edgedriver,
+    ];
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Provider::Firefox => "Firefox",
+            Provider::Geckodriver => "geckodriver",
+            Provider::Chrome => "Chrome",
+            Provider::Chromedriver => "chromedriver",
+            Provider::Edgedriver => "msedgedriver",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The first chromedriver that is downloaded from Chrome for Testing.
+pub(crate) const CHROME_FOR_TESTING_SINCE: &str = "115";
+
+/// What each provider calls its download for a platform. An entry applies
+/// from the driver version in the fourth column until the next entry for the
+/// same provider and platform. Platforms that aren't listed, or that are
+/// asked for at a version before their first entry, have no download.
+#[rustfmt::skip]
+const ASSETS: &[(Provider, Os, Arch, &str, &str)] = &[
+    (Provider::Firefox, Os::Linux, Arch::X86, "0", "linux"),
+    (Provider::Firefox, Os::Linux, Arch::X86_64, "0", "linux64"),
+    (Provider::Firefox, Os::Linux, Arch::Aarch64, "0", "linux64-aarch64"),
+    // Firefox for macOS is a universal binary
+    (Provider::Firefox, Os::MacOs, Arch::X86_64, "0", "osx"),
+    (Provider::Firefox, Os::MacOs, Arch::Aarch64, "0", "osx"),
+    (Provider::Firefox, Os::Windows, Arch::X86, "0", "win"),
+    (Provider::Firefox, Os::Windows, Arch::X86_64, "0", "win64"),
+    (Provider::Firefox, Os::Windows, Arch::Aarch64, "0", "win64-aarch64"),
+
+    (Provider::Geckodriver, Os::Linux, Arch::X86, "0", "linux32"),
+    (Provider::Geckodriver, Os::Linux, Arch::X86_64, "0", "linux64"),
+    (Provider::Geckodriver, Os::Linux, Arch::Aarch64, "0.32.0", "linux-aarch64"),
+    (Provider::Geckodriver, Os::MacOs, Arch::X86_64, "0", "macos"),
+    (Provider::Geckodriver, Os::MacOs, Arch::Aarch64, "0.29.0", "macos-aarch64"),
+    (Provider::Geckodriver, Os::Windows, Arch::X86, "0", "win32"),
+    (Provider::Geckodriver, Os::Windows, Arch::X86_64, "0", "win64"),
+    (Provider::Geckodriver, Os::Windows, Arch::Aarch64, "0.32.0", "win-aarch64"),
+
+    (Provider::Chrome, Os::Linux, Arch::X86_64, "0", "linux64_BUNDLE"),
+    // Chrome for macOS is a universal binary
+    (Provider::Chrome, Os::MacOs, Arch::X86_64, "0", "UNIVERSAL_MAC_DMG"),
+    (Provider::Chrome, Os::MacOs, Arch::Aarch64, "0", "UNIVERSAL_MAC_DMG"),
+    (Provider::Chrome, Os::Windows, Arch::X86, "0", "win_BUNDLE"),
+    (Provider::Chrome, Os::Windows, Arch::X86_64, "0", "win64_BUNDLE"),
+
+    (Provider::Chromedriver, Os::Linux, Arch::X86_64, "0", "linux64"),
+    (Provider::Chromedriver, Os::MacOs, Arch::X86_64, "0", "mac64"),
+    (Provider::Chromedriver, Os::MacOs, Arch::X86_64, CHROME_FOR_TESTING_SINCE, "mac-x64"),
+    (Provider::Chromedriver, Os::MacOs, Arch::Aarch64, "87", "mac64_m1"),
+    (Provider::Chromedriver, Os::MacOs, Arch::Aarch64, "106", "mac_arm64"),
+    (Provider::Chromedriver, Os::MacOs, Arch::Aarch64, CHROME_FOR_TESTING_SINCE, "mac-arm64"),
+    (Provider::Chromedriver, Os::Windows, Arch::X86, "0", "win32"),
+    // There was only a 32 bit chromedriver for Windows until Chrome for Testing
+    (Provider::Chromedriver, Os::Windows, Arch::X86_64, "0", "win32"),
+    (Provider::Chromedriver, Os::Windows, Arch::X86_64, CHROME_FOR_TESTING_SINCE, "win64"),
+
+    (Provider::Edgedriver, Os::Linux, Arch::X86_64, "0", "linux64"),
+    (Provider::Edgedriver, Os::MacOs, Arch::X86_64, "0", "mac64"),
+    (Provider::Edgedriver, Os::MacOs, Arch::Aarch64, "0", "mac64_m1"),
+    (Provider::Edgedriver, Os::Windows, Arch::X86, "0", "win32"),
+    (Provider::Edgedriver, Os::Windows, Arch::X86_64, "0", "win64"),
+    (Provider::Edgedriver, Os::Windows, Arch::Aarch64, "0", "arm64"),
+];
+
+/// An operating system and architecture that browsers are set up for.
+///
+/// # Example
+/// ```
+/// use browser_manager::{Platform, Provider};
+/// let platform = Platform::from_names("macos", "aarch64").unwrap();
+/// let name = platform.asset_name(Provider::Geckodriver, "v0.34.0").unwrap();
+/// assert_eq!(name, "macos-aarch64");
+/// assert_eq!(platform.to_string(), "macos-aarch64");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Platform {
+    pub os: Os,
+    pub arch: Arch,
+}
+
+impl Platform {
+    pub fn new(os: Os, arch: Arch) -> Self {
+        Platform { os, arch }
+    }
+
+    /// Reads the names used by `std::env::consts`, or their aliases. This is
+    /// `None` when either isn't one browsers can be set up for.
+    pub fn from_names(os: &str, arch: &str) -> Option<Platform> {
+        Some(Platform::new(Os::from_name(os)?, Arch::from_name(arch)?))
+    }
+
+    /// What `provider` calls its download for this platform at the driver
+    /// `version`, like `linux-aarch64` for geckodriver or `mac64_m1` for
+    /// chromedriver 105 on an Apple Silicon Mac. A `version` that can't be
+    /// read, like `latest`, is taken to be the newest.
+    pub fn asset_name(
+        self,
+        provider: Provider,
+        version: &str,
+    ) -> Result<&'static str, UnsupportedPlatform> {
+        let parts = version_parts(version);
+        // Later entries for a platform are for newer versions
+        ASSETS
+            .iter()
+            .rev()
+            .filter(|(p, os, arch, _, _)| *p == provider && *os == self.os && *arch == self.arch)
+            .find(|(_, _, _, since, _)| match &parts {
+                Some(parts) => version_parts(since).is_some_and(|since| since <= *parts),
+                None => true,
+            })
+            .map(|(_, _, _, _, name)| *name)
+            .ok_or_else(|| UnsupportedPlatform {
+                provider,
+                platform: self.to_string(),
+                version: version.to_string(),
+            })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.os, self.arch)
+    }
+}
+
+/// Reads the numbers out of a version like `v0.34.0` or `114.0.5735.90`, so
+/// that versions can be compared. This is `None` for names like `latest`.
+pub(crate) fn version_parts(version: &str) -> Option<Vec<u32>> {
+    let parts = version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts)
+    }
+}
+
+/// A provider has no download for the platform, or not at the version that
+/// was asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedPlatform {
+    pub provider: Provider,
+    /// The platform, like `linux-aarch64`.
+    pub platform: String,
+    /// The driver version, if the download depends on it.
+    pub version: String,
+}
+
+impl fmt::Display for UnsupportedPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "There is no {} for {}", self.provider, self.platform)?;
+        if !self.version.is_empty() {
+            write!(f, " at version {}", self.version)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for UnsupportedPlatform {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for os in &Os::ALL {
+            assert_eq!(Os::from_name(os.name()), Some(*os));
+        }
+        for arch in &Arch::ALL {
+            assert_eq!(Arch::from_name(arch.name()), Some(*arch));
+        }
+        assert_eq!(
+            Platform::from_names("Darwin", "arm64"),
+            Some(Platform::new(Os::MacOs, Arch::Aarch64))
+        );
+        assert_eq!(Platform::from_names("freebsd", "x86_64"), None);
+        assert_eq!(Platform::from_names("linux", "riscv64"), None);
+    }
+
+    #[test]
+    fn every_platform_has_the_right_asset_name() {
+        use self::Arch::*;
+        use self::Os::*;
+        // Firefox, geckodriver, Chrome, chromedriver, msedgedriver
+        let table: &[(Os, Arch, [Option<&str>; 5])] = &[
+            (
+                Linux,
+                X86,
+                [Some("linux"), Some("linux32"), None, None, None],
+            ),
+            (
+                Linux,
+                X86_64,
+                [
+                    Some("linux64"),
+                    Some("linux64"),
+                    Some("linux64_BUNDLE"),
+                    Some("linux64"),
+                    Some("linux64"),
+                ],
+            ),
+            (
+                Linux,
+                Aarch64,
+                [
+                    Some("linux64-aarch64"),
+                    Some("linux-aarch64"),
+                    None,
+                    None,
+                    None,
+                ],
+            ),
+            (MacOs, X86, [None, None, None, None, None]),
+            (
+                MacOs,
+                X86_64,
+                [
+                    Some("osx"),
+                    Some("macos"),
+                    Some("UNIVERSAL_MAC_DMG"),
+                    Some("mac-x64"),
+                    Some("mac64"),
+                ],
+            ),
+            (
+                MacOs,
+                Aarch64,
+                [
+                    Some("osx"),
+                    Some("macos-aarch64"),
+                    Some("UNIVERSAL_MAC_DMG"),
+                    Some("mac-arm64"),
+                    Some("mac64_m1"),
+                ],
+            ),
+            (
+                Windows,
+                X86,
+                [
+                    Some("win"),
+                    Some("win32"),
+                    Some("win_BUNDLE"),
+                    Some("win32"),
+                    Some("win32"),
+                ],
+            ),
+            (
+                Windows,
+                X86_64,
+                [
+                    Some("win64"),
+                    Some("win64"),
+                    Some("win64_BUNDLE"),
+                    Some("win64"),
+                    Some("win64"),
+                ],
+            ),
+            (
+                Windows,
+                Aarch64,
+                [
+                    Some("win64-aarch64"),
+                    Some("win-aarch64"),
+                    None,
+                    None,
+                    Some("arm64"),
+                ],
+            ),
+        ];
+
+        assert_eq!(table.len(), Os::ALL.len() * Arch::ALL.len());
+        for (os, arch, names) in table {
+            let platform = Platform::new(*os, *arch);
+            for (provider, name) in Provider::ALL.iter().zip(names.iter()) {
+                assert_eq!(
+                    platform.asset_name(*provider, "latest").ok(),
+                    *name,
+                    "{:?} on {}",
+                    provider,
+                    platform
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn asset_names_depend_on_the_driver_version() {
+        let windows = Platform::new(Os::Windows, Arch::X86_64);
+        let mac = Platform::new(Os::MacOs, Arch::Aarch64);
+        let linux = Platform::new(Os::Linux, Arch::Aarch64);
+        let cases = &[
+            (
+                windows,
+                Provider::Chromedriver,
+                "114.0.5735.90",
+                Some("win32"),
+            ),
+            (
+                windows,
+                Provider::Chromedriver,
+                "115.0.5790.170",
+                Some("win64"),
+            ),
+            (mac, Provider::Chromedriver, "86.0.4240.22", None),
+            (
+                mac,
+                Provider::Chromedriver,
+                "105.0.5195.52",
+                Some("mac64_m1"),
+            ),
+            (
+                mac,
+                Provider::Chromedriver,
+                "106.0.5249.21",
+                Some("mac_arm64"),
+            ),
+            (
+                mac,
+                Provider::Chromedriver,
+                "120.0.6099.109",
+                Some("mac-arm64"),
+            ),
+            (mac, Provider::Geckodriver, "v0.28.0", None),
+            (mac, Provider::Geckodriver, "v0.29.0", Some("macos-aarch64")),
+            (linux, Provider::Geckodriver, "v0.31.0", None),
+            (
+                linux,
+                Provider::Geckodriver,
+                "v0.32.0",
+                Some("linux-aarch64"),
+            ),
+        ];
+        for (platform, provider, version, name) in cases {
+            assert_eq!(
+                platform.asset_name(*provider, version).ok(),
+                *name,
+                "{} {} on {}",
+                provider,
+                version,
+                platform
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_platforms_say_what_is_missing() {
+        let error = Platform::new(Os::Linux, Arch::Aarch64)
+            .asset_name(Provider::Chrome, "")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "There is no Chrome for linux-aarch64");
+        let error = Platform::new(Os::Linux, Arch::Aarch64)
+            .asset_name(Provider::Geckodriver, "v0.30.0")
+            .unwrap_err();
+        assert_eq!(error.version, "v0.30.0");
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// The steps a download goes through, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Looking up the versions and download URLs.
+    Resolving,
+    /// Fetching a file. `done` and `total` count bytes.
+    Downloading,
+    /// Checking that a downloaded file is complete.
+    Verifying,
+    /// Unpacking an archive. `done` and `total` count files.
+    Extracting,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Resolving => "resolving",
+            Phase::Downloading => "downloading",
+            Phase::Verifying => "verifying",
+            Phase::Extracting => "extracting",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An update passed to the callback given to
+/// [`Browser::download_with_progress`](struct.Browser.html#method.download_with_progress).
+///
+/// # Example
+/// ```no_run
+/// use browser_manager::{Browser, Options, Phase};
+/// let browser = Browser::new("firefox".into(), "".into(), "".into(), "latest".into());
+/// browser.download_with_progress(&Options::default(), &|progress| {
+///     if progress.phase == Phase::Downloading {
+///         println!("{}: {} bytes", progress.item, progress.done);
+///     }
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// The browser being set up.
+    pub browser: String,
+    /// What is being worked on, like the name of the driver archive.
+    pub item: String,
+    pub phase: Phase,
+    pub done: u64,
+    /// The amount there is to do, when it is known.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub(crate) fn new(
+        browser: &str,
+        item: &str,
+        phase: Phase,
+        done: u64,
+        total: Option<u64>,
+    ) -> Self {
+        Progress {
+            browser: browser.to_string(),
+            item: item.to_string(),
+            phase,
+            done,
+            total,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::browser::install_target;
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What is known about a downloaded archive, kept beside it in a file with
+/// `.installed` added to its name. It is written when the archive is
+/// unpacked and each time the install is used, so that
+/// [`clean`](fn.clean.html) can tell which installs are old or unused.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct InstallRecord {
+    /// The browser it was downloaded for, like `firefox`.
+    pub browser: String,
+    /// The version that was asked for, like `latest` or `esr`.
+    pub channel: String,
+    /// The version that was installed. Browsers don't say what version they
+    /// are until they are run, so for them this is the one asked for.
+    pub version: String,
+    /// Whether the archive is a driver rather than a browser.
+    pub driver: bool,
+    /// The top level files and directories that were unpacked from the
+    /// archive, relative to the directory it is in.
+    pub entries: Vec<String>,
+    /// When the archive was last unpacked, in seconds since the Unix epoch.
+    pub installed: u64,
+    /// When the install was last used, in seconds since the Unix epoch.
+    pub last_used: u64,
+    /// Whether the archive is kept once it has been unpacked, so that it can
+    /// be unpacked again without downloading it. When it isn't, only this
+    /// record and the `entries` are left.
+    pub archive_kept: bool,
+}
+
+impl InstallRecord {
+    /// Where the record for `archive` is kept.
+    pub fn path(archive: &Path) -> PathBuf {
+        let mut name = archive.as_os_str().to_owned();
+        name.push(".installed");
+        PathBuf::from(name)
+    }
+
+    /// The archive a record at `path` is for, if it is one.
+    pub(crate) fn archive(path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".installed")?;
+        Some(path.with_file_name(name))
+    }
+
+    /// Reads the record for `archive`, or `None` if there isn't one or it
+    /// can't be read.
+    pub fn read(archive: &Path) -> Option<InstallRecord> {
+        let contents = fs::read(InstallRecord::path(archive)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    pub(crate) fn write(&self, archive: &Path) -> io::Result<()> {
+        fs::write(
+            InstallRecord::path(archive),
+            serde_json::to_vec_pretty(self)?,
+        )
+    }
+
+    /// Takes this record's `entries` out of the records of the other
+    /// versions beside `archive`, now that it has been unpacked over them.
+    /// Every version of a driver unpacks to the same name, so without this
+    /// an older version's record would still say it is installed after a
+    /// newer one replaced it. Only records with the same `install_target`
+    /// are changed, as the `InstallLock` for that is held while unpacking.
+    pub(crate) fn replace_entries(&self, archive: &Path) -> io::Result<()> {
+        let dir = archive.parent().unwrap_or_else(|| Path::new("."));
+        let target = install_target(dir, &self.browser, self.driver);
+        for entry in fs::read_dir(dir)? {
+            let other = match InstallRecord::archive(&entry?.path()) {
+                Some(other) if other != archive => other,
+                _ => continue,
+            };
+            if let Some(mut record) = InstallRecord::read(&other) {
+                if install_target(dir, &record.browser, record.driver) != target {
+                    continue;
+                }
+                let before = record.entries.len();
+                record.entries.retain(|name| !self.entries.contains(name));
+                if record.entries.len() != before {
+                    record.write(&other)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn records_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("geckodriver-v0.34.0-linux64.tar.gz");
+        assert_eq!(InstallRecord::read(&archive), None);
+
+        fs::write(InstallRecord::path(&archive), "geckodriver\n").unwrap();
+        assert_eq!(InstallRecord::read(&archive), None);
+
+        let record = InstallRecord {
+            browser: "firefox".to_string(),
+            channel: "latest".to_string(),
+            version: "v0.34.0".to_string(),
+            driver: true,
+            entries: vec!["geckodriver".to_string()],
+            installed: 1,
+            last_used: 2,
+            archive_kept: true,
+        };
+        record.write(&archive).unwrap();
+        assert_eq!(InstallRecord::read(&archive), Some(record));
+        assert_eq!(
+            InstallRecord::archive(&InstallRecord::path(&archive)),
+            Some(archive)
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! A tiny HTTP/1.1 server for tests, so that download code can be exercised
+//! against flaky or misbehaving servers without touching the network.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// How the server answers a request.
+pub(crate) enum Reply {
+    /// A normal response with a status, headers and body.
+    Response(u16, Vec<(String, String)>, Vec<u8>),
+    /// A response that claims a longer body than it sends, then hangs up.
+    Truncated(u16, Vec<(String, String)>, Vec<u8>, usize),
+    /// Hang up without answering, like a connection reset.
+    Reset,
+}
+
+impl Reply {
+    pub fn ok(body: &[u8]) -> Reply {
+        Reply::Response(200, vec![], body.to_vec())
+    }
+
+    pub fn status(status: u16) -> Reply {
+        Reply::Response(status, vec![], vec![])
+    }
+
+    pub fn redirect(location: &str) -> Reply {
+        Reply::Response(
+            302,
+            vec![("Location".to_string(), location.to_string())],
+            vec![],
+        )
+    }
+}
+
+pub(crate) struct TestServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    /// Starts a server on a random local port that answers every request with
+    /// the reply from `handler`. Each connection is handled on its own thread
+    /// so that concurrent requests can be tested.
+    pub fn new<F>(handler: F) -> TestServer
+    where
+        F: Fn(&Request) -> Reply + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let handler = handler.clone();
+                let seen = seen.clone();
+                thread::spawn(move || {
+                    if let Some(request) = read_request(&stream) {
+                        seen.lock().unwrap().push(request.clone());
+                        let reply = handler(&request);
+                        write_reply(stream, reply);
+                    }
+                });
+            }
+        });
+
+        TestServer { url, requests }
+    }
+
+    /// Starts a server that gives each reply in turn, repeating the last one.
+    pub fn sequence(replies: Vec<Reply>) -> TestServer {
+        let replies = Mutex::new(replies);
+        TestServer::new(move |_| {
+            let mut replies = replies.lock().unwrap();
+            if replies.len() > 1 {
+                replies.remove(0)
+            } else {
+                match &replies[0] {
+                    Reply::Response(status, headers, body) => {
+                        Reply::Response(*status, headers.clone(), body.clone())
+                    }
+                    Reply::Truncated(status, headers, body, length) => {
+                        Reply::Truncated(*status, headers.clone(), body.clone(), *length)
+                    }
+                    Reply::Reset => Reply::Reset,
+                }
+            }
+        })
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let path = line.split_whitespace().nth(1)?.to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(index) = line.find(':') {
+            headers.push((
+                line[..index].trim().to_string(),
+                line[index + 1..].trim().to_string(),
+            ));
+        }
+    }
+    Some(Request { path, headers })
+}
+
+fn write_reply(mut stream: TcpStream, reply: Reply) {
+    let (status, headers, body, length) = match reply {
+        Reply::Response(status, headers, body) => {
+            let length = body.len();
+            (status, headers, body, length)
+        }
+        Reply::Truncated(status, headers, body, length) => (status, headers, body, length),
+        Reply::Reset => {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status, length
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&body);
+    let _ = stream.flush();
+    let _ = stream.shutdown(Shutdown::Both);
+}
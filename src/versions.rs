@@ -0,0 +1,471 @@
+use crate::browser::{parse_version_text, Mirrors};
+use crate::http::Http;
+use crate::metadata::MetadataCache;
+use crate::options::Options;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/// What [`available_versions`](fn.available_versions.html) lists the
+/// versions of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Firefox,
+    Geckodriver,
+    /// Chrome and chromedriver, which share their versions.
+    Chrome,
+    /// Edge and msedgedriver, which share their versions.
+    Edge,
+}
+
+impl Kind {
+    /// Reads the name of a browser or driver, like `chrome` or
+    /// `msedgedriver`.
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name.to_lowercase().as_str() {
+            "firefox" => Some(Kind::Firefox),
+            "geckodriver" => Some(Kind::Geckodriver),
+            "chrome" | "chromedriver" => Some(Kind::Chrome),
+            "edge" | "msedgedriver" | "edgedriver" => Some(Kind::Edge),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Firefox => "firefox",
+            Kind::Geckodriver => "geckodriver",
+            Kind::Chrome => "chrome",
+            Kind::Edge => "edge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A release channel to list the versions of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Stable,
+    Beta,
+    Dev,
+    /// Firefox's extended support releases.
+    Esr,
+}
+
+impl Channel {
+    pub fn from_name(name: &str) -> Option<Channel> {
+        match name.to_lowercase().as_str() {
+            "stable" | "release" => Some(Channel::Stable),
+            "beta" => Some(Channel::Beta),
+            "dev" => Some(Channel::Dev),
+            "esr" => Some(Channel::Esr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Dev => "dev",
+            Channel::Esr => "esr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Lists the versions of `kind` on `channel` that can be installed, newest
+/// first, using the options from the environment and config file.
+///
+/// # Example
+/// ```no_run
+/// use browser_manager::{available_versions, Channel, Kind};
+/// for version in available_versions(Kind::Geckodriver, Channel::Stable).unwrap() {
+///     println!("{}", version);
+/// }
+/// ```
+pub fn available_versions(
+    kind: Kind,
+    channel: Channel,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    available_versions_with(kind, channel, &Options::load()?)
+}
+
+/// Like [`available_versions`](fn.available_versions.html) with the options
+/// passed in. The lists are looked up from:
+///
+/// | Kind        | Source                                      |
+/// | ----------- | ------------------------------------------- |
+/// | Firefox     | Mozilla's product details                   |
+/// | geckodriver | The GitHub releases API                     |
+/// | Chrome      | Chrome for Testing's known good versions    |
+/// | Edge        | The msedgedriver storage listing            |
+///
+/// Each list is kept in the project directory for the `versions_ttl`. Chrome
+/// and Edge don't say which channel a version was released on, so their
+/// channels list every version up to the newest on that channel.
+pub fn available_versions_with(
+    kind: Kind,
+    channel: Channel,
+    options: &Options,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let supported = match kind {
+        Kind::Firefox => channel != Channel::Dev,
+        Kind::Geckodriver => channel == Channel::Stable,
+        Kind::Chrome | Kind::Edge => channel != Channel::Esr,
+    };
+    if !supported {
+        return Err(format!("{} has no {} channel", kind, channel).into());
+    }
+
+    let cache = MetadataCache::new(options)?;
+    let key = format!("versions.{}.{}", kind, channel);
+    cache.get_or_fetch(&key, options.versions_ttl, || {
+        let http = Http::new(options)?;
+        let mirrors = &options.mirrors;
+        let versions = match kind {
+            Kind::Firefox => {
+                firefox_versions(&http.get_bytes(&mirrors.firefox_versions)?, channel)?
+            }
+            Kind::Geckodriver => {
+                geckodriver_versions(&http.get_bytes(&mirrors.geckodriver_releases)?)?
+            }
+            Kind::Chrome => {
+                let newest = chrome_channel(&http.get_bytes(&mirrors.chrome_channels)?, channel)?;
+                up_to(
+                    chrome_versions(&http.get_bytes(&mirrors.chrome_versions)?)?,
+                    &newest,
+                )
+            }
+            Kind::Edge => {
+                let latest = edge_latest_url(mirrors, channel);
+                let newest = parse_version_text(&http.get_bytes(&latest)?);
+                up_to(edge_versions(&http, &mirrors.edgedriver_versions)?, &newest)
+            }
+        };
+        Ok(newest_first(versions))
+    })
+}
+
+/// Async version of [`available_versions_with`](fn.available_versions_with.html).
+/// The lookups are done on a blocking thread so the runtime isn't held up.
+#[cfg(feature = "async")]
+pub async fn available_versions_async(
+    kind: Kind,
+    channel: Channel,
+    options: &Options,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let options = options.clone();
+    tokio::task::spawn_blocking(move || available_versions_with(kind, channel, &options)).await?
+}
+
+#[derive(Deserialize)]
+struct ProductDetails {
+    releases: BTreeMap<String, FirefoxRelease>,
+}
+
+#[derive(Deserialize)]
+struct FirefoxRelease {
+    category: String,
+    version: String,
+}
+
+fn firefox_versions(data: &[u8], channel: Channel) -> serde_json::Result<Vec<String>> {
+    let details: ProductDetails = serde_json::from_slice(data)?;
+    let categories: &[&str] = match channel {
+        Channel::Stable => &["major", "stability"],
+        Channel::Beta => &["dev"],
+        _ => &["esr"],
+    };
+    Ok(details
+        .releases
+        .into_values()
+        .filter(|release| categories.contains(&release.category.as_str()))
+        .map(|release| release.version)
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+}
+
+fn geckodriver_versions(data: &[u8]) -> serde_json::Result<Vec<String>> {
+    let releases: Vec<GithubRelease> = serde_json::from_slice(data)?;
+    Ok(releases
+        .into_iter()
+        .filter(|release| !release.prerelease && !release.draft)
+        .map(|release| release.tag_name)
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct KnownGoodVersions {
+    versions: Vec<KnownGoodVersion>,
+}
+
+#[derive(Deserialize)]
+struct KnownGoodVersion {
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct LastKnownGoodVersions {
+    channels: BTreeMap<String, KnownGoodVersion>,
+}
+
+fn chrome_versions(data: &[u8]) -> serde_json::Result<Vec<String>> {
+    let known: KnownGoodVersions = serde_json::from_slice(data)?;
+    Ok(known
+        .versions
+        .into_iter()
+        .map(|known| known.version)
+        .collect())
+}
+
+fn chrome_channel(data: &[u8], channel: Channel) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let name = match channel {
+        Channel::Beta => "Beta",
+        Channel::Dev => "Dev",
+        _ => "Stable",
+    };
+    let mut last: LastKnownGoodVersions = serde_json::from_slice(data)?;
+    match last.channels.remove(name) {
+        Some(known) => Ok(known.version),
+        None => Err(format!("Chrome for Testing does not list a {} version", name).into()),
+    }
+}
+
+fn edge_latest_url(mirrors: &Mirrors, channel: Channel) -> String {
+    match channel {
+        Channel::Beta => format!("{}LATEST_BETA", mirrors.edgedriver),
+        Channel::Dev => format!("{}LATEST_DEV", mirrors.edgedriver),
+        _ => mirrors.edgedriver_latest.to_owned(),
+    }
+}
+
+/// How many pages of the storage listing are read before giving up.
+const MAX_LISTING_PAGES: usize = 100;
+
+/// Reads the version directories out of the msedgedriver storage listing,
+/// which is XML split into pages that each give the marker for the next.
+fn edge_versions(http: &Http, url: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let mut versions = vec![];
+    let mut page_url = url.to_string();
+    for _ in 0..MAX_LISTING_PAGES {
+        let page = String::from_utf8_lossy(&http.get_bytes(&page_url)?).into_owned();
+        versions.extend(
+            tag_values(&page, "Name")
+                .into_iter()
+                .map(|name| name.trim_end_matches('/').to_string())
+                .filter(|name| name.starts_with(|c: char| c.is_ascii_digit())),
+        );
+        match tag_values(&page, "NextMarker").into_iter().next() {
+            Some(marker) if !marker.is_empty() => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                page_url = format!("{}{}marker={}", url, separator, marker);
+            }
+            _ => return Ok(versions),
+        }
+    }
+    Err(format!("{} has more than {} pages", url, MAX_LISTING_PAGES).into())
+}
+
+/// The text inside every `<tag>` in `xml`.
+fn tag_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+/// The numbers in a version, so that `v0.34.0`, `100.0b9` and `91.8.0esr`
+/// can all be compared.
+fn sort_key(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+fn up_to(versions: Vec<String>, newest: &str) -> Vec<String> {
+    let newest = sort_key(newest);
+    versions
+        .into_iter()
+        .filter(|version| sort_key(version) <= newest)
+        .collect()
+}
+
+fn newest_first(mut versions: Vec<String>) -> Vec<String> {
+    versions.sort_by_key(|version| std::cmp::Reverse(sort_key(version)));
+    versions.dedup();
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn options(dir: &TempDir, server: &TestServer) -> Options {
+        let url = |path: &str| format!("{}/{}", server.url, path);
+        Options {
+            retries: 0,
+            mirrors: Mirrors {
+                firefox_versions: url("firefox.json"),
+                geckodriver_releases: url("releases"),
+                chrome_versions: url("known-good-versions.json"),
+                chrome_channels: url("last-known-good-versions.json"),
+                edgedriver: url(""),
+                edgedriver_latest: url("LATEST_STABLE"),
+                edgedriver_versions: url("edgewebdriver?comp=list"),
+                ..Mirrors::default()
+            },
+            ..Options::default().with_cache_path(dir.path())
+        }
+    }
+
+    fn server() -> TestServer {
+        TestServer::new(|request| {
+            let body: &[u8] = match request.path.as_str() {
+                "/firefox.json" => {
+                    br#"{"releases": {
+                    "firefox-99.0": {"category": "major", "version": "99.0"},
+                    "firefox-100.0": {"category": "major", "version": "100.0"},
+                    "firefox-100.0.2": {"category": "stability", "version": "100.0.2"},
+                    "firefox-101.0b9": {"category": "dev", "version": "101.0b9"},
+                    "firefox-91.9.0esr": {"category": "esr", "version": "91.9.0esr"}
+                }}"#
+                }
+                "/releases" => {
+                    br#"[
+                    {"tag_name": "v0.33.0", "prerelease": false},
+                    {"tag_name": "v0.34.0", "prerelease": false},
+                    {"tag_name": "v0.35.0-rc1", "prerelease": true}
+                ]"#
+                }
+                "/known-good-versions.json" => {
+                    br#"{"versions": [
+                    {"version": "113.0.5672.0", "revision": "1121455"},
+                    {"version": "120.0.6099.109", "revision": "1217362"},
+                    {"version": "121.0.6167.16", "revision": "1233107"}
+                ]}"#
+                }
+                "/last-known-good-versions.json" => {
+                    br#"{"channels": {
+                    "Stable": {"channel": "Stable", "version": "120.0.6099.109"},
+                    "Beta": {"channel": "Beta", "version": "121.0.6167.16"}
+                }}"#
+                }
+                "/LATEST_STABLE" => b"120.0.2210.91\n",
+                "/edgewebdriver?comp=list" => {
+                    b"<EnumerationResults><Blobs>\
+                    <BlobPrefix><Name>119.0.2151.97/</Name></BlobPrefix>\
+                    <Blob><Name>LATEST_STABLE</Name></Blob>\
+                    </Blobs><NextMarker>page2</NextMarker></EnumerationResults>"
+                }
+                "/edgewebdriver?comp=list&marker=page2" => {
+                    b"<EnumerationResults><Blobs>\
+                    <BlobPrefix><Name>120.0.2210.91/</Name></BlobPrefix>\
+                    <BlobPrefix><Name>121.0.2277.4/</Name></BlobPrefix>\
+                    </Blobs><NextMarker /></EnumerationResults>"
+                }
+                _ => return Reply::status(404),
+            };
+            Reply::ok(body)
+        })
+    }
+
+    #[test]
+    fn versions_are_listed_newest_first_for_each_channel() {
+        let dir = TempDir::new().unwrap();
+        let server = server();
+        let options = options(&dir, &server);
+        let list = |kind, channel| available_versions_with(kind, channel, &options).unwrap();
+
+        assert_eq!(
+            list(Kind::Firefox, Channel::Stable),
+            vec!["100.0.2", "100.0", "99.0"]
+        );
+        assert_eq!(list(Kind::Firefox, Channel::Beta), vec!["101.0b9"]);
+        assert_eq!(list(Kind::Firefox, Channel::Esr), vec!["91.9.0esr"]);
+        assert_eq!(
+            list(Kind::Geckodriver, Channel::Stable),
+            vec!["v0.34.0", "v0.33.0"]
+        );
+        assert_eq!(
+            list(Kind::Chrome, Channel::Stable),
+            vec!["120.0.6099.109", "113.0.5672.0"]
+        );
+        assert_eq!(
+            list(Kind::Chrome, Channel::Beta),
+            vec!["121.0.6167.16", "120.0.6099.109", "113.0.5672.0"]
+        );
+        assert_eq!(
+            list(Kind::Edge, Channel::Stable),
+            vec!["120.0.2210.91", "119.0.2151.97"]
+        );
+    }
+
+    #[test]
+    fn lists_are_cached_for_the_versions_ttl() {
+        let dir = TempDir::new().unwrap();
+        let server = server();
+        let options = options(&dir, &server);
+        available_versions_with(Kind::Geckodriver, Channel::Stable, &options).unwrap();
+        available_versions_with(Kind::Geckodriver, Channel::Stable, &options).unwrap();
+        assert_eq!(server.requests().len(), 1);
+
+        let expired = Options {
+            versions_ttl: Duration::from_secs(0),
+            ..options
+        };
+        available_versions_with(Kind::Geckodriver, Channel::Stable, &expired).unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn channels_that_dont_exist_are_errors() {
+        let dir = TempDir::new().unwrap();
+        let server = server();
+        let options = options(&dir, &server);
+        let error = available_versions_with(Kind::Geckodriver, Channel::Beta, &options);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "geckodriver has no beta channel"
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn names_can_be_read() {
+        assert_eq!(Kind::from_name("msedgedriver"), Some(Kind::Edge));
+        assert_eq!(Kind::from_name("Chrome"), Some(Kind::Chrome));
+        assert_eq!(Kind::from_name("safari"), None);
+        assert_eq!(Channel::from_name("ESR"), Some(Channel::Esr));
+    }
+}